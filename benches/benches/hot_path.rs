@@ -0,0 +1,133 @@
+//! Benchmarks for the work done on every update from the browser or editor
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{stream, StreamExt};
+use gtany::server::{
+    benches::{calculate_hash, utf16_offset_to_utf8_line_col, LocalFile, MyStreamExt},
+    msg::{GetTextFromComponent, RangeInText, SetTextInComponent},
+};
+use gtany::settings::Newline;
+
+/// File sizes of the round trip benchmarks, in bytes
+const SIZES: &[usize] = &[10_000, 1_000_000, 10_000_000];
+
+/// Lines of mixed-width characters, like a multilingual page
+fn text(len: usize) -> String {
+    const LINE: &str = "Some ascii, some àccénts, some 漢字, and 🇺🇸 flags\n";
+    let mut text = LINE.repeat(len / LINE.len() + 1);
+    let mut end = len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    text
+}
+
+fn message(text: String) -> GetTextFromComponent {
+    GetTextFromComponent {
+        selections: vec![RangeInText { start: 0, end: 0 }],
+        syntax: String::new(),
+        text,
+        title: String::from("Benchmark"),
+        url: String::from("example.com"),
+        resume_token: None,
+    }
+}
+
+fn hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("calculate_hash");
+    for &size in SIZES {
+        let text = text(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &text, |b, text| {
+            b.iter(|| calculate_hash(text))
+        });
+    }
+    group.finish();
+}
+
+fn offsets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("utf16_offset_to_utf8_line_col");
+    for &size in SIZES {
+        let text = text(size);
+        // the cursor is usually near the end of what was typed
+        let offset = text.encode_utf16().count() - 1;
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &text, |b, text| {
+            b.iter(|| utf16_offset_to_utf8_line_col(offset, text))
+        });
+    }
+    group.finish();
+}
+
+fn debounce(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("debounce");
+    for burst in [10, 1_000, 100_000] {
+        group.throughput(Throughput::Elements(burst));
+        group.bench_with_input(BenchmarkId::from_parameter(burst), &burst, |b, &burst| {
+            b.iter(|| {
+                // a burst of keystrokes collapses to the last one once it ends
+                let last: Vec<_> = runtime.block_on(async {
+                    stream::iter(0..burst)
+                        .debounce(Duration::from_millis(20))
+                        .collect()
+                        .await
+                });
+                assert_eq!(vec![burst - 1], last);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A browser update written to the file, then an editor save read back and sent
+fn round_trip(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("round_trip");
+    group.sample_size(20);
+    for &size in SIZES {
+        let from_browser = serde_json::to_string(&message(text(size))).unwrap();
+        let from_editor = text(size) + "!";
+        let mut file = runtime
+            .block_on(LocalFile::create(
+                &message(String::new()),
+                2 * size,
+                Newline::Append,
+            ))
+            .unwrap();
+
+        group.throughput(Throughput::Bytes(2 * size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let update: GetTextFromComponent = serde_json::from_str(&from_browser).unwrap();
+                    assert!(file.maybe_update(&update.text).await.unwrap());
+
+                    tokio::fs::write(file.as_ref(), &from_editor).await.unwrap();
+                    let text = file.get_current_contents().await.unwrap();
+                    serde_json::to_string(&SetTextInComponent {
+                        text,
+                        selections: &update.selections,
+                        resume_token: None,
+                    })
+                    .unwrap()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hashing, offsets, debounce, round_trip);
+criterion_main!(benches);
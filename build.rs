@@ -1,4 +1,5 @@
 use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Pass git-describe through CARGO_GIT_VERSION env variable
 ///
@@ -26,6 +27,43 @@ fn set_version_from_git() {
     }
 }
 
+/// Pass the UTC build date through CARGO_BUILD_DATE env variable
+///
+/// Respects SOURCE_DATE_EPOCH for reproducible builds.
+fn set_build_date() {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let secs = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse()
+            .expect("SOURCE_DATE_EPOCH should be an integer"),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time is after the epoch")
+            .as_secs(),
+    };
+
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    println!("cargo:rustc-env=CARGO_BUILD_DATE={year:04}-{month:02}-{day:02}");
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
 fn main() {
     set_version_from_git();
+    set_build_date();
 }
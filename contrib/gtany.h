@@ -0,0 +1,38 @@
+/*
+ * C API of GhostText-Any, built with
+ * `cargo rustc --release --lib --features capi --crate-type cdylib`
+ *
+ * See src/capi.rs for details.
+ */
+#ifndef GTANY_H
+#define GTANY_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct GtanyServer GtanyServer;
+
+/* Called from the server's threads with each session event as JSON,
+ * e.g. {"event": "start", "url": "...", "title": "..."} */
+typedef void (*gtany_event_callback)(const char *event, void *user_data);
+
+/* Log to stderr, filtered with RUST_LOG. Returns -1 if already set up. */
+int gtany_init_logging(void);
+
+/* Start a server with command line arguments, without the program name,
+ * e.g. {"--port", "4001", "--editor", "code --wait"}.
+ * callback may be NULL. Returns NULL on errors. */
+GtanyServer *gtany_server_start(const char *const *argv, size_t argc,
+                                gtany_event_callback callback, void *user_data);
+
+/* Stop and free a server. Returns 0 if it ran without errors, -1 otherwise. */
+int gtany_server_stop(GtanyServer *server);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* GTANY_H */
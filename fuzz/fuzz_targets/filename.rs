@@ -0,0 +1,29 @@
+#![no_main]
+
+use std::path::Path;
+
+use gtany::server::{fuzzing::get_filename, msg::GetTextFromComponent};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (String, String, String)| {
+    let (title, url, syntax) = input;
+    let msg = GetTextFromComponent {
+        selections: vec![],
+        syntax,
+        text: String::new(),
+        title,
+        url,
+        resume_token: None,
+    };
+
+    let name = get_filename(&msg, None);
+    assert_eq!(
+        Some(name.as_ref()),
+        Path::new(&name).file_name(),
+        "{name:?} is not a plain file name"
+    );
+    assert!(
+        !name.contains(|c: char| c == '\\' || c.is_control()),
+        "{name:?} contains unexpected characters"
+    );
+});
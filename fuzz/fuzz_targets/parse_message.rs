@@ -0,0 +1,11 @@
+#![no_main]
+
+use gtany::server::msg::{GetTextFromComponent, UpdateTextFromComponent};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = serde_json::from_slice::<GetTextFromComponent>(data) {
+        let _ = msg.domain();
+    }
+    let _ = serde_json::from_slice::<UpdateTextFromComponent>(data);
+});
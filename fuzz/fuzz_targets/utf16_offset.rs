@@ -0,0 +1,13 @@
+#![no_main]
+
+use gtany::server::fuzzing::utf16_offset_to_utf8_line_col;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (String, usize)| {
+    let (text, offset) = input;
+    let (line, col) = utf16_offset_to_utf8_line_col(offset, &text);
+
+    assert!(line >= 1 && col >= 1);
+    assert!(line <= text.lines().count() + 1);
+    assert!(col <= text.len() + 1);
+});
@@ -0,0 +1,213 @@
+//! Load testing against a running server
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    pin::pin,
+};
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use hyper::{body, header::ORIGIN, http::HeaderValue, Client};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, Message},
+};
+
+use crate::server::msg;
+use crate::settings::{BenchOptions, Settings};
+
+const ORIGIN_VALUE: &str = "moz-extension://gtany-bench";
+
+/// Time taken by each stage of a session
+struct SessionTimings {
+    /// Websocket handshake
+    connect: Duration,
+    /// Initial message to first text sent back by the server
+    first_response: Option<Duration>,
+    /// Initial message to websocket close
+    session: Duration,
+}
+
+pub async fn run(options: &Settings, bench: &BenchOptions) -> anyhow::Result<()> {
+    let addr = (options.host.as_str(), options.port)
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid server address: {}:{}", options.host, options.port))?
+        .next()
+        .with_context(|| format!("No addresses found for {}:{}", options.host, options.port))?;
+
+    let redirect = fetch_redirect(addr).await?;
+    let ws_addr = SocketAddr::new(addr.ip(), redirect.WebSocketPort);
+    let url = format!("ws://{ws_addr}/");
+
+    info!("Starting {} sessions against {url}", bench.sessions);
+
+    let tasks: Vec<_> = (0..bench.sessions)
+        .map(|id| {
+            let url = url.clone();
+            let bench = bench.clone();
+            tokio::spawn(async move {
+                timeout(
+                    Duration::from_secs(bench.timeout),
+                    fake_session(&url, id, &bench),
+                )
+                .await
+                .context("Timed out")?
+            })
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    let mut failures = 0;
+    for (id, task) in tasks.into_iter().enumerate() {
+        match task.await? {
+            Ok(timings) => results.push(timings),
+            Err(e) => {
+                failures += 1;
+                error!("Session {id} failed: {e:#}");
+            }
+        }
+    }
+
+    println!("{} sessions, {} failed", bench.sessions, failures);
+    println!(
+        "{:<16}{:>12}{:>12}{:>12}{:>12}",
+        "", "p50", "p90", "p99", "max"
+    );
+    print_percentiles("connect", results.iter().map(|t| t.connect).collect());
+    print_percentiles(
+        "first response",
+        results.iter().filter_map(|t| t.first_response).collect(),
+    );
+    print_percentiles("session", results.iter().map(|t| t.session).collect());
+
+    if failures > 0 {
+        bail!("{failures} session(s) failed");
+    }
+
+    Ok(())
+}
+
+/// Get the websocket port the same way the extension does
+async fn fetch_redirect(addr: SocketAddr) -> anyhow::Result<msg::RedirectToWebSocket> {
+    let response = Client::new()
+        .get(format!("http://{addr}/").parse()?)
+        .await
+        .with_context(|| format!("Unable to connect to server at {addr}"))?;
+    let bytes = body::to_bytes(response.into_body()).await?;
+    serde_json::from_slice(&bytes).context("Invalid redirect response")
+}
+
+/// Connect, type for a while, and wait for the server to finish the session
+async fn fake_session(
+    url: &str,
+    id: usize,
+    bench: &BenchOptions,
+) -> anyhow::Result<SessionTimings> {
+    let mut request = url.into_client_request()?;
+    request
+        .headers_mut()
+        .insert(ORIGIN, HeaderValue::from_static(ORIGIN_VALUE));
+
+    let start = Instant::now();
+    let (websocket, _response) = connect_async(request).await.context("Unable to connect")?;
+    let connect = start.elapsed();
+
+    let (mut tx, mut rx) = websocket.split();
+
+    let mut text = "x".repeat(bench.size);
+    let update = |text: &str| -> anyhow::Result<Message> {
+        let message = msg::GetTextFromComponent {
+            selections: vec![msg::RangeInText {
+                start: text.len(),
+                end: text.len(),
+            }],
+            syntax: String::new(),
+            text: text.to_owned(),
+            title: format!("bench {id}"),
+            url: String::from("gtany-bench.invalid"),
+            resume_token: None,
+        };
+        Ok(Message::Text(serde_json::to_string(&message)?))
+    };
+
+    let start = Instant::now();
+    tx.send(update(&text)?).await?;
+
+    let typing = async {
+        for i in 0..bench.updates {
+            sleep(Duration::from_millis(bench.interval)).await;
+            text.push(char::from(b'a' + (i % 26) as u8));
+            tx.send(update(&text)?).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let responses = async {
+        let mut first_response = None;
+        while let Some(message) = rx.next().await {
+            match message? {
+                Message::Text(_) => {
+                    first_response.get_or_insert_with(|| start.elapsed());
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        anyhow::Ok(first_response)
+    };
+
+    let mut responses = pin!(responses);
+    let first_response = tokio::select! {
+        first_response = &mut responses => first_response?,
+        typed = typing => {
+            typed.context("Unable to send update")?;
+            responses.await?
+        }
+    };
+
+    Ok(SessionTimings {
+        connect,
+        first_response,
+        session: start.elapsed(),
+    })
+}
+
+fn print_percentiles(name: &str, mut durations: Vec<Duration>) {
+    if durations.is_empty() {
+        println!("{name:<16}{:>12}", "-");
+        return;
+    }
+
+    durations.sort();
+    let [p50, p90, p99, max] = [50, 90, 99, 100].map(|p| percentile(&durations, p));
+    println!(
+        "{name:<16}{:>12}{:>12}{:>12}{:>12}",
+        format!("{p50:.1?}"),
+        format!("{p90:.1?}"),
+        format!("{p99:.1?}"),
+        format!("{max:.1?}"),
+    );
+}
+
+/// Nearest-rank percentile of sorted, non-empty durations
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(50 => 5  ; "median")]
+    #[test_case(90 => 9  ; "p90")]
+    #[test_case(99 => 10 ; "p99")]
+    #[test_case(100 => 10; "max")]
+    #[test_case(0 => 1   ; "min")]
+    fn percentiles(p: usize) -> u64 {
+        let durations: Vec<_> = (1..=10).map(Duration::from_secs).collect();
+        percentile(&durations, p).as_secs()
+    }
+}
@@ -0,0 +1,46 @@
+//! Information about how this binary was built
+
+use crate::server::PROTOCOL_VERSION;
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub build_date: &'static str,
+    pub features: Vec<&'static str>,
+    pub protocol_version: u32,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: crate::version(),
+            build_date: option_env!("CARGO_BUILD_DATE").unwrap_or("unknown"),
+            features: enabled_features(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Cargo features this binary was compiled with
+fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "watch_changes") {
+        features.push("watch_changes");
+    }
+    if cfg!(feature = "systemd") {
+        features.push("systemd");
+    }
+    if cfg!(feature = "tray") {
+        features.push("tray");
+    }
+    if cfg!(feature = "clipboard") {
+        features.push("clipboard");
+    }
+    if cfg!(feature = "preview") {
+        features.push("preview");
+    }
+    if cfg!(feature = "capi") {
+        features.push("capi");
+    }
+    features
+}
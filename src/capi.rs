@@ -0,0 +1,199 @@
+//! C API to embed the server, e.g. in editor plugins written in other languages
+//!
+//! Build a shared library with
+//! `cargo rustc --release --lib --features capi --crate-type cdylib`. The
+//! declarations are in `contrib/gtany.h`.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString, OsString},
+    ptr,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use tokio::sync::oneshot;
+
+use crate::{
+    config,
+    server::{self, msg, SessionEvent},
+    settings::Settings,
+};
+
+/// Called with each session event as a NUL-terminated JSON object
+pub type EventCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+/// A server running on its own thread
+pub struct GtanyServer {
+    stop: oneshot::Sender<()>,
+    thread: JoinHandle<anyhow::Result<()>>,
+}
+
+/// The callback's `user_data`, which the caller promises can be used from any thread
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a SessionEvent,
+    url: &'a str,
+    title: &'a str,
+}
+
+/// Log to stderr, filtered with `RUST_LOG` like the `gtany` program
+///
+/// Returns -1 if a logger was already set.
+#[no_mangle]
+pub extern "C" fn gtany_init_logging() -> c_int {
+    let result = env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .try_init();
+    match result {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Start a server with the command line arguments in `argv`, without the program name
+///
+/// If `callback` isn't null, it's called from the server's threads with each
+/// session event as JSON, like `{"event": "start", "url": ..., "title": ...}`,
+/// and `user_data`. The string is only valid during the call.
+///
+/// Returns null if the arguments are invalid or the server can't be started,
+/// with the error logged.
+///
+/// # Safety
+///
+/// `argv` must point to `argc` NUL-terminated strings. `user_data` must be
+/// usable from any thread until the server is stopped.
+#[no_mangle]
+pub unsafe extern "C" fn gtany_server_start(
+    argv: *const *const c_char,
+    argc: usize,
+    callback: Option<EventCallback>,
+    user_data: *mut c_void,
+) -> *mut GtanyServer {
+    let args = (0..argc).map(|i| {
+        let arg = CStr::from_ptr(*argv.add(i));
+        OsString::from(arg.to_string_lossy().into_owned())
+    });
+    let args = std::iter::once(OsString::from("gtany"))
+        .chain(args)
+        .collect();
+
+    match start(
+        args,
+        callback.map(|callback| (callback, UserData(user_data))),
+    ) {
+        Ok(server) => Box::into_raw(Box::new(server)),
+        Err(e) => {
+            error!("Unable to start server: {e:#}");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Stop a server and free it
+///
+/// Active sessions get `--shutdown-grace` to send their final text. Returns 0
+/// if the server ran without errors, or -1 with the error logged.
+///
+/// # Safety
+///
+/// `server` must be null or returned by [`gtany_server_start`], and not be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn gtany_server_stop(server: *mut GtanyServer) -> c_int {
+    if server.is_null() {
+        return -1;
+    }
+    let server = Box::from_raw(server);
+
+    // already gone if the server failed
+    let _ = server.stop.send(());
+    match server.thread.join() {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            error!("Server failed: {e:#}");
+            -1
+        }
+        Err(_) => {
+            error!("Server panicked");
+            -1
+        }
+    }
+}
+
+fn start(
+    args: Vec<OsString>,
+    callback: Option<(EventCallback, UserData)>,
+) -> anyhow::Result<GtanyServer> {
+    let args = config::args_with_config(args, false)?;
+    let options = Settings::try_parse_from(args)?;
+    if options.command.is_some() {
+        anyhow::bail!("Subcommands can't be run as a server");
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Unable to start runtime")?;
+
+    let (stop, stopped) = oneshot::channel();
+    let stopped = async move {
+        let _ = stopped.await;
+    };
+    let events = move |event: &SessionEvent, page: &msg::GetTextFromComponent| {
+        let Some((callback, user_data)) = &callback else {
+            return;
+        };
+        let payload = Payload {
+            event,
+            url: &page.url,
+            title: &page.title,
+        };
+        let json = match serde_json::to_string(&payload) {
+            Ok(json) => CString::new(json).expect("serde_json escapes NUL"),
+            Err(e) => {
+                error!("Unable to serialize session event: {e}");
+                return;
+            }
+        };
+        callback(json.as_ptr(), user_data.0);
+    };
+
+    let thread = thread::Builder::new()
+        .name(String::from("gtany-server"))
+        .spawn(move || runtime.block_on(server::run_with_events(options, stopped, events)))?;
+
+    Ok(GtanyServer { stop, thread })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_with(args: &[&str]) -> *mut GtanyServer {
+        let args: Vec<_> = args.iter().map(|a| CString::new(*a).unwrap()).collect();
+        let argv: Vec<_> = args.iter().map(|a| a.as_ptr()).collect();
+        unsafe { gtany_server_start(argv.as_ptr(), argv.len(), None, ptr::null_mut()) }
+    }
+
+    #[test]
+    fn starts_and_stops() {
+        let server = start_with(&["--port", "0", "--editor", "true"]);
+        assert!(!server.is_null());
+        assert_eq!(0, unsafe { gtany_server_stop(server) });
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(start_with(&["--port", "not a port"]).is_null());
+        assert_eq!(-1, unsafe { gtany_server_stop(ptr::null_mut()) });
+    }
+}
@@ -0,0 +1,435 @@
+//! Settings from a TOML config file
+//!
+//! Keys are the long command line options, with `-` or `_` between words:
+//!
+//! ```toml
+//! editor = "nvim"
+//! port = 4002
+//! multi = true
+//! editor_for = ["github.com=code --wait"]
+//!
+//! [domain."*.wikipedia.org"]
+//! read_only = true
+//! ```
+//!
+//! The file is turned into arguments for the options not given on the command
+//! line or in the environment, so both take precedence over it. `domain`
+//! sections become rules, passed as JSON in the hidden `--domain-rule`.
+
+use std::{
+    any::TypeId,
+    ffi::OsString,
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, CommandFactory, Parser};
+use serde_json::json;
+
+use crate::settings::Settings;
+
+/// Parse the command line, filling in options from the config file
+///
+/// Exits on invalid arguments like [`Parser::parse`].
+pub fn load() -> anyhow::Result<Settings> {
+    let args = args_with_config(std::env::args_os().collect(), true)?;
+    Ok(Settings::parse_from(args))
+}
+
+/// `args` with the config file's options inserted after the program name
+///
+/// Reads the file given with `--config`, or the one at [`default_path`] if
+/// `default` is set and it exists.
+pub fn args_with_config(mut args: Vec<OsString>, default: bool) -> anyhow::Result<Vec<OsString>> {
+    // missing required options may come from the file, and other errors are
+    // reported by the final parse
+    let Ok(matches) = Settings::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        return Ok(args);
+    };
+
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None => match default_path().filter(|path| default && path.is_file()) {
+            Some(path) => path,
+            None => return Ok(args),
+        },
+    };
+
+    let config = read(&path)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let inserted =
+        config_args(&config, &matches, dir).with_context(|| format!("Invalid config {path:?}"))?;
+    debug!("Read {} options from {path:?}", inserted.len());
+
+    let at = args.len().min(1);
+    args.splice(at..at, inserted);
+    Ok(args)
+}
+
+/// `$XDG_CONFIG_HOME/gtany/config.toml`, or `%APPDATA%\gtany\config.toml` on Windows
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let dir = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+    Some(dir?.join("gtany").join("config.toml"))
+}
+
+fn read(path: &Path) -> anyhow::Result<toml::Table> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Unable to read config {path:?}"))?;
+    toml::from_str(&text).with_context(|| format!("Invalid config {path:?}"))
+}
+
+/// Arguments for the options in `config` that aren't already set in `matches`
+///
+/// Paths in `domain` sections are relative to `dir`, the config's directory.
+fn config_args(
+    config: &toml::Table,
+    matches: &ArgMatches,
+    dir: &Path,
+) -> anyhow::Result<Vec<OsString>> {
+    let command = Settings::command();
+    let mut args = Vec::new();
+
+    for (key, value) in config {
+        if key == "domain" {
+            args.extend(domain_rules(value, dir)?);
+            continue;
+        }
+        let long = key.replace('_', "-");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .filter(|arg| !arg.is_hide_set())
+            .filter(|_| !matches!(long.as_str(), "config" | "help" | "version"))
+        else {
+            bail!("Unknown option {key:?}");
+        };
+
+        let explicit = matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        );
+        if explicit {
+            debug!("Ignoring {key:?} from config, it was already given");
+            continue;
+        }
+
+        let flag = format!("--{long}");
+        match (value, arg.get_action()) {
+            (toml::Value::Boolean(set), ArgAction::SetTrue) => {
+                if *set {
+                    args.push(OsString::from(flag));
+                }
+            }
+            (toml::Value::Array(values), ArgAction::Append) => {
+                for value in values {
+                    args.push(option(&flag, key, value)?);
+                }
+            }
+            (value, ArgAction::Set | ArgAction::Append) => args.push(option(&flag, key, value)?),
+            (value, _) => bail!("Expected true or false for {key:?}, got {value}"),
+        }
+    }
+
+    Ok(args)
+}
+
+/// `--domain-rule` arguments for the `[domain."PATTERN"]` sections
+fn domain_rules(domains: &toml::Value, dir: &Path) -> anyhow::Result<Vec<OsString>> {
+    let Some(domains) = domains.as_table() else {
+        bail!(r#"Expected sections like [domain."github.com"], got domain = {domains}"#);
+    };
+    let mut args = Vec::with_capacity(domains.len());
+    for (pattern, rule) in domains {
+        let Some(rule) = rule.as_table() else {
+            bail!(r#"Expected a section like [domain."{pattern}"], got {rule}"#);
+        };
+        let mut rule = serde_json::to_value(rule).context("Unsupported value in rule")?;
+        if rule.get("domain").is_some() {
+            bail!(r#"[domain."{pattern}"] already gives the domain"#);
+        }
+        rule["domain"] = json!(pattern);
+        // relative to the config, like they are to the rules file
+        if let Some(template) = rule["template"].as_str() {
+            rule["template"] = json!(dir.join(template));
+        }
+        if let Some(paths) = rule.get_mut("path").and_then(|paths| paths.as_array_mut()) {
+            for path in paths {
+                if let Some(relative) = path.as_str() {
+                    *path = json!(dir.join(relative));
+                }
+            }
+        }
+        args.push(OsString::from(format!("--domain-rule={rule}")));
+    }
+    Ok(args)
+}
+
+/// JSON schema of the config file, whose keys are the long options
+///
+/// Keys use `_` between words, and are described by the options' help.
+/// Printed by `gtany config-schema`, e.g. for configuration UIs.
+pub fn schema() -> serde_json::Value {
+    let command = Settings::command();
+    let mut properties: serde_json::Map<_, _> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .filter_map(|arg| Some((arg.get_long()?, arg)))
+        .filter(|(long, _)| !matches!(*long, "config" | "help" | "version"))
+        .map(|(long, arg)| (long.replace('-', "_"), property(arg)))
+        .collect();
+
+    properties.insert(
+        String::from("domain"),
+        json!({
+            "type": "object",
+            "additionalProperties": { "type": "object" },
+            "description": "Rules by domain pattern, with the options of --rules",
+        }),
+    );
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "gtany config",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+/// Schema of a single option's value
+fn property(arg: &Arg) -> serde_json::Value {
+    let integer = {
+        let id = arg.get_value_parser().type_id();
+        id == TypeId::of::<u16>()
+            || id == TypeId::of::<u64>()
+            || id == TypeId::of::<usize>()
+            || id == TypeId::of::<NonZeroUsize>()
+    };
+    let typed = |value: &str| match value.parse::<u64>() {
+        Ok(n) if integer => json!(n),
+        _ => json!(value),
+    };
+
+    let mut value = match arg.get_action() {
+        ArgAction::SetTrue => json!({ "type": "boolean" }),
+        _ if integer => json!({ "type": "integer", "minimum": 0 }),
+        _ => json!({ "type": "string" }),
+    };
+    let possible: Vec<_> = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_owned())
+        .collect();
+    if !possible.is_empty() && !matches!(arg.get_action(), ArgAction::SetTrue) {
+        value["enum"] = json!(possible);
+    }
+
+    let mut defaults = arg
+        .get_default_values()
+        .iter()
+        .map(|value| value.to_string_lossy())
+        .flat_map(|value| match arg.get_value_delimiter() {
+            Some(delimiter) => value.split(delimiter).map(typed).collect(),
+            None => vec![typed(&value)],
+        });
+    let mut property = match arg.get_action() {
+        ArgAction::Append => {
+            let defaults: Vec<_> = defaults.collect();
+            let mut array = json!({ "type": "array", "items": value });
+            if !defaults.is_empty() {
+                array["default"] = json!(defaults);
+            }
+            array
+        }
+        ArgAction::SetTrue => {
+            value["default"] = json!(false);
+            value
+        }
+        _ => {
+            if let Some(default) = defaults.next() {
+                value["default"] = default;
+            }
+            value
+        }
+    };
+
+    if let Some(help) = arg.get_long_help().or(arg.get_help()) {
+        property["description"] = json!(help.to_string());
+    }
+    property
+}
+
+/// `--flag=value` for a single value
+fn option(flag: &str, key: &str, value: &toml::Value) -> anyhow::Result<OsString> {
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(n) => n.to_string(),
+        toml::Value::Float(n) => n.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Table(_) => {
+            bail!(r#"Sections like [{key}] aren't supported, only [domain."PATTERN"]"#)
+        }
+        other => bail!("Unsupported value for {key:?}: {other}"),
+    };
+    Ok(OsString::from(format!("{flag}={value}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// Parse `args` after writing `config` to a file passed with `--config`
+    fn parse(config: &str, args: &[&str]) -> anyhow::Result<Settings> {
+        let dir = TempDir::new("gtany-config").unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, config).unwrap();
+
+        let mut argv = vec![OsString::from("gtany"), OsString::from("--config")];
+        argv.push(path.into_os_string());
+        argv.extend(args.iter().map(OsString::from));
+        let argv = args_with_config(argv, false)?;
+        Ok(Settings::try_parse_from(argv)?)
+    }
+
+    #[test]
+    fn reads_options() {
+        let options = parse(
+            r#"
+                editor = "nvim"
+                host = "0.0.0.0"
+                port = 4002
+                multi = true
+                wait_for_delete = false
+                editor-for = ["github.com=code --wait", "gitlab.com=emacs"]
+            "#,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!("0.0.0.0", options.host);
+        assert_eq!(4002, options.port);
+        assert!(options.multi);
+        assert!(!options.wait_for_delete);
+        assert_eq!(2, options.editor_for.len());
+    }
+
+    #[test]
+    fn describes_options_in_schema() {
+        let schema = schema();
+        let properties = &schema["properties"];
+
+        assert_eq!("integer", properties["port"]["type"]);
+        assert_eq!(4001, properties["port"]["default"]);
+        assert_eq!("boolean", properties["multi"]["type"]);
+        assert_eq!("array", properties["editor_for"]["type"]);
+        assert_eq!("string", properties["editor_for"]["items"]["type"]);
+        assert_eq!(
+            json!(["append", "preserve", "strip"]),
+            properties["newline"]["enum"]
+        );
+        assert!(properties["wait_for_delete"]["description"]
+            .as_str()
+            .is_some_and(|help| !help.is_empty()));
+        assert!(properties.get("config").is_none());
+        assert!(properties.get("help").is_none());
+    }
+
+    #[test]
+    fn prefers_command_line() {
+        let options = parse(
+            r#"
+                editor = "nvim"
+                host = "0.0.0.0"
+                port = 4002
+                editor_for = ["github.com=code --wait"]
+            "#,
+            &["--port", "4003", "--editor-for", "gitlab.com=emacs"],
+        )
+        .unwrap();
+
+        assert_eq!("0.0.0.0", options.host);
+        assert_eq!(4003, options.port);
+        assert_eq!(1, options.editor_for.len());
+        assert_eq!("gitlab.com", options.editor_for[0].pattern);
+    }
+
+    #[test]
+    fn reads_domain_sections() {
+        let options = parse(
+            r#"
+                editor = "nvim"
+
+                [domain."github.com"]
+                editor = "code --wait"
+                template = "issue.md"
+
+                [domain."*.wikipedia.org"]
+                read_only = true
+            "#,
+            &[],
+        )
+        .unwrap();
+
+        let rules: Vec<serde_json::Value> = options
+            .domain_rule
+            .iter()
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect();
+        assert_eq!(2, rules.len());
+        let github = rules.iter().find(|rule| rule["domain"] == "github.com");
+        let github = github.unwrap();
+        assert_eq!("code --wait", github["editor"]);
+        let template = Path::new(github["template"].as_str().unwrap());
+        assert!(template.is_absolute() && template.ends_with("issue.md"));
+        assert!(rules.iter().any(|rule| rule["read_only"] == true));
+    }
+
+    #[test]
+    fn applies_before_subcommands() {
+        let options = parse("port = 4002", &["doctor"]).unwrap();
+        assert_eq!(4002, options.port);
+        assert!(options.command.is_some());
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(parse(r#"editr = "nvim""#, &["--editor", "nvim"]).is_err());
+        assert!(parse(r#"config = "other.toml""#, &["--editor", "nvim"]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse(r#"multi = "yes""#, &["--editor", "nvim"]).is_err());
+        assert!(parse(r#"port = "many""#, &["--editor", "nvim"]).is_err());
+        assert!(parse("[github]\nmulti = true", &["--editor", "nvim"]).is_err());
+        assert!(parse(r#"domain = "github.com""#, &["--editor", "nvim"]).is_err());
+        assert!(parse(r#"domain_rule = ["{}"]"#, &["--editor", "nvim"]).is_err());
+        assert!(parse("editor = ", &[]).is_err());
+    }
+
+    #[test]
+    fn fails_on_missing_config() {
+        let argv = [
+            "gtany",
+            "--config",
+            "/nonexistent/gtany.toml",
+            "--editor",
+            "nvim",
+        ];
+        assert!(args_with_config(argv.map(OsString::from).to_vec(), false).is_err());
+    }
+}
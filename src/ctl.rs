@@ -0,0 +1,103 @@
+//! Changing sessions of a running server
+
+use std::net::ToSocketAddrs;
+
+use anyhow::{bail, Context};
+use hyper::{
+    body,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request, Response,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::settings::{CtlCommand, Settings};
+
+/// Body of `POST /ctl/set-delay`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetDelay {
+    pub millis: u64,
+    /// Id of the session to change, or all active sessions
+    pub session: Option<u64>,
+}
+
+pub async fn run(options: &Settings, command: &CtlCommand) -> anyhow::Result<()> {
+    match *command {
+        CtlCommand::SetDelay { millis, session } => {
+            let changed: Vec<u64> =
+                post(options, "set-delay", &SetDelay { millis, session }).await?;
+            match (session, changed.is_empty()) {
+                (Some(id), true) => bail!("No active session {id}"),
+                (None, true) => println!("No active sessions"),
+                _ => {
+                    let ids: Vec<_> = changed.iter().map(u64::to_string).collect();
+                    println!("Set delay of session(s) {} to {millis}ms", ids.join(", "));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send `request` to the server's `/ctl/<command>` route
+///
+/// Goes over `--unix-socket` if set, otherwise to `--host` and `--port` with
+/// the `--ctl-token`.
+async fn post<T: Serialize, R: DeserializeOwned>(
+    options: &Settings,
+    command: &str,
+    request: &T,
+) -> anyhow::Result<R> {
+    let body = Body::from(serde_json::to_vec(request)?);
+
+    #[cfg(unix)]
+    if let Some(path) = &options.unix_socket {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/ctl/{command}"))
+            .header(hyper::header::HOST, "localhost")
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)?;
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .with_context(|| format!("Unable to connect to server at {path:?}"))?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Control connection failed: {e}");
+            }
+        });
+        return read(command, sender.send_request(request).await?).await;
+    }
+
+    let addr = (options.host.as_str(), options.port)
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid server address: {}:{}", options.host, options.port))?
+        .next()
+        .with_context(|| format!("No addresses found for {}:{}", options.host, options.port))?;
+
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{addr}/ctl/{command}"))
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(token) = &options.ctl_token {
+        request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let response = Client::new()
+        .request(request.body(body)?)
+        .await
+        .with_context(|| format!("Unable to connect to server at {addr}"))?;
+    read(command, response).await
+}
+
+/// The server's answer to `command`, or why it refused
+async fn read<R: DeserializeOwned>(command: &str, response: Response<Body>) -> anyhow::Result<R> {
+    let status = response.status();
+    let bytes = body::to_bytes(response.into_body()).await?;
+    if !status.is_success() {
+        bail!(
+            "Server refused {command} ({status}): {}",
+            String::from_utf8_lossy(&bytes).trim()
+        );
+    }
+    serde_json::from_slice(&bytes).context("Invalid response from server")
+}
@@ -13,28 +13,39 @@ pub trait MyStreamExt: Stream + Sized {
     /// A `wait` of zero returns all items in the original stream with no delay.
     ///
     /// Returns last item immediately if stream is closed.
-    fn debounce(self, wait: Duration) -> Debounce<Self> {
+    fn debounce<W: Wait>(self, wait: W) -> Debounce<Self, W> {
         Debounce::new(self, wait)
     }
 }
 
+/// How long [`Debounce`] waits, checked again for each item
+pub trait Wait {
+    fn wait(&self) -> Duration;
+}
+
+impl Wait for Duration {
+    fn wait(&self) -> Duration {
+        *self
+    }
+}
+
 impl<S: Stream + Sized> MyStreamExt for S {}
 
 #[must_use = "streams do nothing unless polled"]
 #[derive(Debug)]
 #[pin_project]
-pub struct Debounce<S: Stream> {
+pub struct Debounce<S: Stream, W = Duration> {
     #[pin]
     stream: Fuse<S>,
     #[pin]
     deadline: Sleep,
     last: Option<S::Item>,
-    duration: Duration,
+    duration: W,
 }
 
-impl<S: Stream> Debounce<S> {
-    fn new(stream: S, duration: Duration) -> Self {
-        let next = Instant::now() + duration;
+impl<S: Stream, W: Wait> Debounce<S, W> {
+    fn new(stream: S, duration: W) -> Self {
+        let next = Instant::now() + duration.wait();
         let deadline = sleep_until(next);
 
         Self {
@@ -46,7 +57,7 @@ impl<S: Stream> Debounce<S> {
     }
 }
 
-impl<S: Stream> Stream for Debounce<S> {
+impl<S: Stream, W: Wait> Stream for Debounce<S, W> {
     type Item = S::Item;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
@@ -60,14 +71,15 @@ impl<S: Stream> Stream for Debounce<S> {
                 return Poll::Ready(me.last.take());
             }
 
-            if me.duration.is_zero() {
+            let duration = me.duration.wait();
+            if duration.is_zero() {
                 return Poll::Ready(v);
             }
 
             // store for later
             *me.last = v;
 
-            let next = Instant::now() + *me.duration;
+            let next = Instant::now() + duration;
             me.deadline.as_mut().reset(next);
         }
 
@@ -88,6 +100,7 @@ impl<S: Stream> Stream for Debounce<S> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use proptest::prelude::*;
     use tokio_stream::{self as stream, StreamExt};
 
     #[tokio::test]
@@ -112,4 +125,58 @@ mod test {
         tokio::pin!(s);
         assert_eq!(vec![5], s.collect::<Vec<_>>().await);
     }
+
+    /// Debounce items sent after the given gaps (in millis) on a paused clock
+    fn debounce_with_gaps(gaps: &[u64], wait: u64) -> Vec<usize> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let items =
+                stream::iter(gaps.iter().copied().enumerate()).then(|(i, gap)| async move {
+                    tokio::time::sleep(Duration::from_millis(gap)).await;
+                    i
+                });
+            let s = items.debounce(Duration::from_millis(wait));
+            tokio::pin!(s);
+            s.collect::<Vec<_>>().await
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn debounce_keeps_order_and_final_item(
+            gaps in prop::collection::vec(0..200u64, 0..20),
+            wait in 0..100u64,
+        ) {
+            let out = debounce_with_gaps(&gaps, wait);
+
+            // never reordered or duplicated
+            prop_assert!(out.windows(2).all(|w| w[0] < w[1]), "{:?}", out);
+            // always ends with the last item
+            prop_assert_eq!(gaps.len().checked_sub(1), out.last().copied());
+            if wait == 0 {
+                prop_assert_eq!(gaps.len(), out.len());
+            }
+        }
+
+        #[test]
+        fn debounce_keeps_items_followed_by_a_pause(
+            gaps in prop::collection::vec(0..200u64, 1..20),
+            wait in 1..100u64,
+        ) {
+            let out = debounce_with_gaps(&gaps, wait);
+
+            for (i, next_gap) in gaps.iter().skip(1).enumerate() {
+                if *next_gap > wait {
+                    prop_assert!(out.contains(&i), "item {} missing from {:?}", i, out);
+                } else if *next_gap < wait {
+                    prop_assert!(!out.contains(&i), "item {} in {:?}", i, out);
+                }
+            }
+        }
+    }
 }
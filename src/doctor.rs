@@ -0,0 +1,273 @@
+//! Diagnose common setup problems
+//!
+//! Each check prints a pass/fail line, with a suggestion for failures.
+
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use hyper::{body, client::HttpConnector, header, Body, Client, Request, StatusCode};
+use tempdir::TempDir;
+use tokio::time::{timeout, Duration};
+
+use crate::server::{self, msg};
+use crate::settings::Settings;
+
+/// Time allowed for each network or file-watching operation
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+enum Outcome {
+    Pass(String),
+    Fail { problem: String, suggestion: String },
+    Skip(String),
+}
+
+use Outcome::*;
+
+fn fail(problem: impl Into<String>, suggestion: impl Into<String>) -> Outcome {
+    Fail {
+        problem: problem.into(),
+        suggestion: suggestion.into(),
+    }
+}
+
+/// Run all checks, returning an error if any failed
+pub async fn run(options: &Settings) -> anyhow::Result<()> {
+    let addr = server::resolve_host(&options.host, options.port)?;
+
+    let client = Client::new();
+
+    let redirect = check_redirect(&client, addr).await;
+    let server_running = matches!(redirect, Pass(_));
+    let mut outcomes = vec![("Server responds with redirect", redirect)];
+
+    let origin = if server_running {
+        check_origin(&client, addr).await
+    } else {
+        Skip(String::from("no server to test against"))
+    };
+    outcomes.push(("Origin check", origin));
+
+    outcomes.push(("Editor resolvable", check_editor(options.editor.as_deref())));
+    outcomes.push(("Temp dir writable", check_temp_dir().await));
+    outcomes.push(("File watching", check_watch(options).await));
+
+    let mut failures = 0;
+    for (name, outcome) in outcomes {
+        match outcome {
+            Pass(detail) => println!("[PASS] {name}: {detail}"),
+            Skip(reason) => println!("[SKIP] {name}: {reason}"),
+            Fail {
+                problem,
+                suggestion,
+            } => {
+                failures += 1;
+                println!("[FAIL] {name}: {problem}");
+                println!("       Suggestion: {suggestion}");
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{failures} check(s) failed");
+    }
+
+    Ok(())
+}
+
+/// A server is listening and returns valid redirect json
+async fn check_redirect(client: &Client<HttpConnector>, addr: SocketAddr) -> Outcome {
+    let uri = format!("http://{addr}/");
+    let response = match timeout(CHECK_TIMEOUT, client.get(uri.parse().unwrap())).await {
+        Err(_) => {
+            return fail(
+                format!("Timed out connecting to {addr}"),
+                "Check firewall rules",
+            )
+        }
+        Ok(Err(e)) if e.is_connect() => {
+            return fail(
+                format!("Nothing is listening on {addr}: {e}"),
+                "Start `gtany` (or its systemd socket) with the same --host and --port",
+            )
+        }
+        Ok(Err(e)) => return fail(format!("Request failed: {e}"), "Check --host and --port"),
+        Ok(Ok(response)) => response,
+    };
+
+    let not_gtany = format!("Another program may be using port {}", addr.port());
+
+    if response.status() != StatusCode::OK {
+        return fail(
+            format!("Unexpected status {}", response.status()),
+            not_gtany,
+        );
+    }
+
+    let bytes = match body::to_bytes(response.into_body()).await {
+        Ok(bytes) => bytes,
+        Err(e) => return fail(format!("Unable to read response: {e}"), not_gtany),
+    };
+
+    match serde_json::from_slice::<msg::RedirectToWebSocket>(&bytes) {
+        Ok(redirect) if redirect.ProtocolVersion != msg::PROTOCOL_VERSION => fail(
+            format!("Unsupported protocol version {}", redirect.ProtocolVersion),
+            "Update gtany",
+        ),
+        Ok(redirect) => Pass(format!(
+            "websocket port {}, protocol version {}",
+            redirect.WebSocketPort, redirect.ProtocolVersion
+        )),
+        Err(e) => fail(
+            format!(
+                "Invalid redirect json {:?}: {e}",
+                String::from_utf8_lossy(&bytes)
+            ),
+            not_gtany,
+        ),
+    }
+}
+
+/// Websockets from web pages are rejected and ones from extensions accepted
+async fn check_origin(client: &Client<HttpConnector>, addr: SocketAddr) -> Outcome {
+    const PAGE_ORIGIN: &str = "https://example.com";
+    const EXTENSION_ORIGIN: &str = "moz-extension://gtany-doctor";
+
+    let page = match websocket_status(client, addr, PAGE_ORIGIN).await {
+        Ok(status) => status,
+        Err(e) => return fail(format!("{e:#}"), "Check the server log"),
+    };
+    if page == StatusCode::SWITCHING_PROTOCOLS {
+        return fail(
+            format!("Websocket from {PAGE_ORIGIN} was accepted"),
+            "Web pages can connect to this server; update gtany",
+        );
+    }
+
+    let extension = match websocket_status(client, addr, EXTENSION_ORIGIN).await {
+        Ok(status) => status,
+        Err(e) => return fail(format!("{e:#}"), "Check the server log"),
+    };
+    if extension != StatusCode::SWITCHING_PROTOCOLS {
+        return fail(
+            format!("Websocket from {EXTENSION_ORIGIN} was refused with {extension}"),
+            "Check that a proxy isn't rewriting the Origin header",
+        );
+    }
+
+    Pass(String::from(
+        "page origins rejected, extension origins accepted",
+    ))
+}
+
+/// Returns the status of a websocket handshake, closing any accepted connection
+async fn websocket_status(
+    client: &Client<HttpConnector>,
+    addr: SocketAddr,
+    origin: &str,
+) -> anyhow::Result<StatusCode> {
+    let request = Request::get(format!("http://{addr}/"))
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+        .header(header::ORIGIN, origin)
+        .body(Body::empty())?;
+
+    let response = timeout(CHECK_TIMEOUT, client.request(request))
+        .await
+        .context("Timed out sending websocket handshake")?
+        .context("Unable to send websocket handshake")?;
+
+    Ok(response.status())
+}
+
+/// The editor command's program can be found
+fn check_editor(editor: Option<&str>) -> Outcome {
+    let suggestion = "Set --editor or $EDITOR to an installed program";
+
+    let Some(editor) = editor else {
+        return fail("No editor command set", suggestion);
+    };
+
+    let pieces = match server::split_command(editor) {
+        Ok(pieces) => pieces,
+        Err(e) => return fail(format!("Unable to parse {editor:?}: {e:#}"), suggestion),
+    };
+    let program = match pieces.first() {
+        Some(program) => program,
+        None => return fail("Editor command is empty", suggestion),
+    };
+
+    match find_program(Path::new(program)) {
+        Some(path) => Pass(format!("{program:?} is {path:?}")),
+        None => fail(format!("{program:?} not found in PATH"), suggestion),
+    }
+}
+
+fn find_program(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return program.is_file().then(|| program.to_owned());
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Session files can be created in the system temp dir
+async fn check_temp_dir() -> Outcome {
+    let suggestion = "Set $TMPDIR to a writable directory";
+
+    let dir = match TempDir::new("ghost-text") {
+        Ok(dir) => dir,
+        Err(e) => return fail(format!("Unable to create directory: {e}"), suggestion),
+    };
+    let path = dir.path().join("doctor.txt");
+    if let Err(e) = tokio::fs::write(&path, "test").await {
+        return fail(format!("Unable to write {path:?}: {e}"), suggestion);
+    }
+
+    Pass(format!("wrote {path:?}"))
+}
+
+/// Modifications to a session file are noticed
+#[cfg(feature = "watch_changes")]
+async fn check_watch(options: &Settings) -> Outcome {
+    use futures::StreamExt;
+
+    let suggestion = "Raise fs.inotify.max_user_watches or disable the `watch_changes` feature";
+
+    let dir = match TempDir::new("ghost-text") {
+        Ok(dir) => dir,
+        Err(e) => return Skip(format!("unable to create directory: {e}")),
+    };
+    let path = dir.path().join("doctor.txt");
+    if let Err(e) = tokio::fs::write(&path, "test").await {
+        return Skip(format!("unable to write {path:?}: {e}"));
+    }
+
+    let mut edits = match crate::server::watch_edits(&path, options) {
+        Ok(edits) => edits,
+        Err(e) => return fail(format!("Unable to watch {path:?}: {e:#}"), suggestion),
+    };
+
+    if let Err(e) = tokio::fs::write(&path, "modified").await {
+        return Skip(format!("unable to modify {path:?}: {e}"));
+    }
+
+    match timeout(CHECK_TIMEOUT, edits.next()).await {
+        Ok(Some(Ok(()))) => Pass(String::from("received modification event")),
+        Ok(Some(Err(e))) => fail(format!("{e:#}"), suggestion),
+        Ok(None) => fail("Watcher stopped unexpectedly", suggestion),
+        Err(_) => fail("No modification event received", suggestion),
+    }
+}
+
+#[cfg(not(feature = "watch_changes"))]
+async fn check_watch(_options: &Settings) -> Outcome {
+    Skip(String::from("built without the `watch_changes` feature"))
+}
@@ -0,0 +1,121 @@
+//! Scriptable stand-in for an editor, for tests and demos
+
+use std::{path::Path, str::FromStr};
+
+use anyhow::{bail, Context};
+use tokio::{
+    fs,
+    time::{sleep, Duration},
+};
+
+use crate::settings::FakeEditorOptions;
+
+/// A single action taken by the fake editor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Add text to the end of the buffer
+    Append(String),
+    /// Replace the buffer with text
+    Set(String),
+    /// Replace the buffer with the file contents
+    Reload,
+    /// Write the buffer to the file
+    Save,
+    /// Write the buffer to a new file and rename it over the file, like vim
+    SaveRename,
+    /// Wait for some milliseconds
+    Sleep(u64),
+    /// Exit immediately with a status code
+    Exit(i32),
+    /// Abort the process, as if it crashed
+    Abort,
+}
+
+impl FromStr for Step {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once('=') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+
+        let step = match (name, arg) {
+            ("append", Some(text)) => Step::Append(text.to_owned()),
+            ("set", Some(text)) => Step::Set(text.to_owned()),
+            ("reload", None) => Step::Reload,
+            ("save", None) => Step::Save,
+            ("save-rename", None) => Step::SaveRename,
+            ("sleep", Some(millis)) => Step::Sleep(millis.parse().context("Invalid millis")?),
+            ("exit", Some(code)) => Step::Exit(code.parse().context("Invalid exit code")?),
+            ("abort", None) => Step::Abort,
+            _ => bail!("Unknown step {s:?}"),
+        };
+
+        Ok(step)
+    }
+}
+
+pub async fn run(options: &FakeEditorOptions) -> anyhow::Result<()> {
+    let mut buffer = read(&options.file).await?;
+
+    for step in &options.steps {
+        debug!("Fake editor step: {step:?}");
+        match step {
+            Step::Append(text) => buffer.push_str(text),
+            Step::Set(text) => text.clone_into(&mut buffer),
+            Step::Reload => buffer = read(&options.file).await?,
+            Step::Save => fs::write(&options.file, &buffer)
+                .await
+                .with_context(|| format!("Unable to save {:?}", options.file))?,
+            Step::SaveRename => save_rename(&options.file, &buffer)
+                .await
+                .with_context(|| format!("Unable to save {:?}", options.file))?,
+            Step::Sleep(millis) => sleep(Duration::from_millis(*millis)).await,
+            Step::Exit(code) => std::process::exit(*code),
+            Step::Abort => std::process::abort(),
+        }
+    }
+
+    Ok(())
+}
+
+async fn save_rename(file: &Path, buffer: &str) -> std::io::Result<()> {
+    let mut temp = file.as_os_str().to_owned();
+    temp.push(".new");
+    fs::write(&temp, buffer).await?;
+    fs::rename(&temp, file).await
+}
+
+async fn read(file: &Path) -> anyhow::Result<String> {
+    fs::read_to_string(file)
+        .await
+        .with_context(|| format!("Unable to read {file:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("append=hi" => Step::Append(String::from("hi")))]
+    #[test_case("append=" => Step::Append(String::new()) ; "append nothing")]
+    #[test_case("set=a=b" => Step::Set(String::from("a=b")) ; "set with equals")]
+    #[test_case("reload" => Step::Reload)]
+    #[test_case("save" => Step::Save)]
+    #[test_case("save-rename" => Step::SaveRename)]
+    #[test_case("sleep=250" => Step::Sleep(250))]
+    #[test_case("exit=-1" => Step::Exit(-1))]
+    #[test_case("abort" => Step::Abort)]
+    fn parses_steps(s: &str) -> Step {
+        s.parse().unwrap()
+    }
+
+    #[test_case("save=now" ; "unexpected argument")]
+    #[test_case("append" ; "missing argument")]
+    #[test_case("sleep=soon" ; "invalid number")]
+    #[test_case("quit" ; "unknown step")]
+    fn rejects_invalid_steps(s: &str) {
+        assert!(s.parse::<Step>().is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Listing and searching texts saved in the drafts directory
+
+use std::fs;
+
+use anyhow::Context;
+
+use crate::server::{Drafts, Saved};
+use crate::settings::{HistoryCommand, Settings};
+
+pub fn run(options: &Settings, command: &HistoryCommand) -> anyhow::Result<()> {
+    let (query, print) = match *command {
+        HistoryCommand::List { print } => (None, print),
+        HistoryCommand::Search { ref query, print } => (Some(query.to_lowercase()), print),
+    };
+    let dir = options
+        .drafts_dir
+        .clone()
+        .context("No drafts to search, pass the server's `--drafts-dir`")?;
+    let drafts = Drafts::open(dir.clone())
+        .list()
+        .with_context(|| format!("Unable to read drafts in {dir:?}"))?;
+
+    let mut found = 0;
+    for draft in drafts {
+        let text = match (&query, print) {
+            (None, false) => String::new(),
+            _ => {
+                let bytes = fs::read(&draft.path)
+                    .with_context(|| format!("Unable to read draft {:?}", draft.path))?;
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+        };
+        if query
+            .as_ref()
+            .is_some_and(|query| !matches(&draft, &text, query))
+        {
+            continue;
+        }
+
+        found += 1;
+        println!("{}\t{}\t{}", draft.path.display(), draft.title, draft.url);
+        if print {
+            println!("{}", text.trim_end_matches('\n'));
+        }
+    }
+
+    match query {
+        Some(query) if found == 0 => eprintln!("No drafts contain {query:?}"),
+        None if found == 0 => eprintln!("No drafts in {dir:?}"),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Whether the draft's text, title, or url contain the lowercase `query`
+fn matches(draft: &Saved, text: &str, query: &str) -> bool {
+    [text, &draft.title, &draft.url]
+        .iter()
+        .any(|field| field.to_lowercase().contains(query))
+}
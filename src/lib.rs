@@ -0,0 +1,27 @@
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+extern crate log;
+
+pub mod bench;
+mod build_info;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod config;
+pub mod ctl;
+mod debounce;
+pub mod doctor;
+pub mod fake_editor;
+pub mod history;
+pub mod native_host;
+pub mod server;
+pub mod settings;
+#[cfg(all(feature = "systemd", target_os = "linux"))]
+pub mod systemd;
+
+pub fn version() -> &'static str {
+    option_env!("CARGO_GIT_VERSION")
+        .or(option_env!("CARGO_PKG_VERSION"))
+        .unwrap_or("unknown")
+}
@@ -1,32 +1,26 @@
-#[macro_use]
-extern crate serde_derive;
-
-#[macro_use]
-extern crate log;
 use log::LevelFilter;
 
-use clap::Parser;
-
-mod settings;
-use settings::Settings;
-mod debounce;
-mod server;
+use gtany::settings::Command;
 #[cfg(all(feature = "systemd", target_os = "linux"))]
-mod systemd;
-
-fn version() -> &'static str {
-    option_env!("CARGO_GIT_VERSION")
-        .or(option_env!("CARGO_PKG_VERSION"))
-        .unwrap_or("unknown")
-}
+use gtany::systemd;
+use gtany::{bench, config, ctl, doctor, fake_editor, history, native_host, server};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_logger()?;
 
-    let options = Settings::parse();
-
-    server::run(options).await?;
+    let options = config::load()?;
+
+    match options.command {
+        Some(Command::Doctor) => doctor::run(&options).await?,
+        Some(Command::Bench(ref bench)) => bench::run(&options, bench).await?,
+        Some(Command::Ctl(ref command)) => ctl::run(&options, command).await?,
+        Some(Command::History(ref command)) => history::run(&options, command)?,
+        Some(Command::NativeHost { .. }) => native_host::run(&options).await?,
+        Some(Command::FakeEditor(ref fake)) => fake_editor::run(fake).await?,
+        Some(Command::ConfigSchema) => println!("{:#}", config::schema()),
+        None => server::run(options).await?,
+    }
 
     Ok(())
 }
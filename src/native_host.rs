@@ -0,0 +1,291 @@
+//! Native messaging host for editor extensions like Textern
+//!
+//! The browser starts the host and exchanges JSON messages with it over
+//! stdin and stdout, each prefixed with its length as a native-endian `u32`.
+//! See <https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/Native_messaging>.
+//!
+//! Each text from the extension is edited in this process with
+//! [`LocalSessions`], like a session of the server but without listening on
+//! the network.
+
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::server::{msg, LocalSessions};
+use crate::settings::Settings;
+
+/// Largest message browsers accept from a host
+const MAX_OUTGOING: usize = 1024 * 1024;
+
+/// Message from the extension
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+enum Incoming {
+    /// Edit a text field's contents
+    NewText {
+        /// Passed back with each update, in whatever form the extension sent it
+        id: serde_json::Value,
+        text: String,
+        /// UTF-16 offset of the cursor
+        #[serde(default)]
+        caret: usize,
+        #[serde(default)]
+        url: String,
+    },
+}
+
+/// Message to the extension
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+enum Outgoing {
+    /// New contents for the text field
+    TextUpdate {
+        id: serde_json::Value,
+        text: String,
+    },
+    /// The editor for the text field closed
+    DeathNotify {
+        id: serde_json::Value,
+    },
+    Error {
+        error: String,
+    },
+}
+
+pub async fn run(options: &Settings) -> anyhow::Result<()> {
+    let sessions = Arc::new(LocalSessions::new(options)?);
+    bridge(sessions, options.max_text_size, io::stdin(), io::stdout()).await
+}
+
+/// Edit each text read from `input`, writing updates to `output`
+///
+/// Messages over `max` bytes are answered with an error. Returns once
+/// `input` is closed, which happens when the extension stops, and the
+/// editors opened until then have exited.
+async fn bridge(
+    sessions: Arc<LocalSessions>,
+    max: usize,
+    mut input: impl AsyncRead + Unpin,
+    mut output: impl AsyncWrite + Unpin + Send + 'static,
+) -> anyhow::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Outgoing>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let json = serde_json::to_vec(&message)?;
+            if let Err(e) = write_message(&mut output, &json).await {
+                match message {
+                    Outgoing::TextUpdate { id, .. } => {
+                        warn!("Unable to send text of {id}: {e}");
+                        let error = Outgoing::Error {
+                            error: format!("Unable to send text: {e}"),
+                        };
+                        write_message(&mut output, &serde_json::to_vec(&error)?).await?;
+                    }
+                    _ => return Err(e.into()),
+                }
+            }
+        }
+        anyhow::Ok(())
+    });
+
+    let mut editing = JoinSet::new();
+    loop {
+        let bytes = match read_message(&mut input, max).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("Ignoring message from extension: {e}");
+                let _ = tx.send(Outgoing::Error {
+                    error: format!("Ignored message: {e}"),
+                });
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let message = match serde_json::from_slice(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Ignoring invalid message from extension: {e}");
+                let _ = tx.send(Outgoing::Error {
+                    error: format!("Invalid message: {e}"),
+                });
+                continue;
+            }
+        };
+        let Incoming::NewText {
+            id,
+            text,
+            caret,
+            url,
+        } = message;
+
+        let start = msg::GetTextFromComponent {
+            selections: vec![msg::RangeInText {
+                start: caret,
+                end: caret,
+            }],
+            syntax: String::new(),
+            text,
+            title: String::new(),
+            url,
+            resume_token: None,
+        };
+        let (sessions, tx) = (sessions.clone(), tx.clone());
+        editing.spawn(async move {
+            let mut transport = |text: &str| {
+                let update = Outgoing::TextUpdate {
+                    id: id.clone(),
+                    text: text.to_owned(),
+                };
+                tx.send(update)
+                    .map_err(|_| anyhow!("Extension closed the connection"))
+            };
+            if let Err(e) = sessions.edit(&start, &mut transport).await {
+                error!("Session for {id} failed: {e:#}");
+                let _ = tx.send(Outgoing::Error {
+                    error: format!("{e:#}"),
+                });
+            }
+            let _ = tx.send(Outgoing::DeathNotify { id });
+        });
+    }
+
+    debug!("Extension closed the connection");
+    // let sessions send their final text
+    while let Some(edited) = editing.join_next().await {
+        edited?;
+    }
+    drop(tx);
+    writer.await?
+}
+
+/// Read one message, or `None` if the input is closed
+///
+/// Messages over `max` bytes are skipped with an `InvalidData` error,
+/// without allocating them, and the next one can be read after it.
+async fn read_message(
+    input: &mut (impl AsyncRead + Unpin),
+    max: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_ne_bytes(len);
+    if len as usize > max {
+        io::copy(&mut (&mut *input).take(len.into()), &mut io::sink()).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes is over --max-text-size of {max}"),
+        ));
+    }
+    let mut bytes = vec![0; len as usize];
+    input.read_exact(&mut bytes).await?;
+    Ok(Some(bytes))
+}
+
+async fn write_message(output: &mut (impl AsyncWrite + Unpin), bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_OUTGOING {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "message of {} bytes is over the browser's limit of {MAX_OUTGOING}",
+                bytes.len()
+            ),
+        ));
+    }
+    output
+        .write_all(&(bytes.len() as u32).to_ne_bytes())
+        .await?;
+    output.write_all(bytes).await?;
+    output.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_messages() {
+        let (mut a, mut b) = io::duplex(64);
+        write_message(&mut a, br#"{"type":"x"}"#).await.unwrap();
+        write_message(&mut a, b"").await.unwrap();
+        drop(a);
+
+        assert_eq!(
+            Some(br#"{"type":"x"}"#.to_vec()),
+            read_message(&mut b, 64).await.unwrap()
+        );
+        assert_eq!(Some(vec![]), read_message(&mut b, 64).await.unwrap());
+        assert_eq!(None, read_message(&mut b, 64).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn skips_messages_over_max() {
+        let (mut a, mut b) = io::duplex(64);
+        a.write_all(&u32::MAX.to_ne_bytes()).await.unwrap();
+        a.write_all(&[b' '; 8]).await.unwrap();
+        drop(a);
+        let e = read_message(&mut b, 4).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert_eq!(None, read_message(&mut b, 4).await.unwrap());
+
+        let (mut a, mut b) = io::duplex(64);
+        write_message(&mut a, b"too long").await.unwrap();
+        write_message(&mut a, b"ok").await.unwrap();
+        drop(a);
+        assert!(read_message(&mut b, 4).await.is_err());
+        assert_eq!(Some(b"ok".to_vec()), read_message(&mut b, 4).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_messages_over_browser_limit() {
+        let (mut a, _b) = io::duplex(64);
+        let e = write_message(&mut a, &vec![b' '; MAX_OUTGOING + 1])
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+
+    #[test]
+    fn reads_textern_messages() {
+        let message: Incoming = serde_json::from_str(
+            r#"{"type": "new_text", "payload": {"id": "tab-1", "text": "hi", "caret": 2, "url": "example.com", "prefs": {}}}"#,
+        )
+        .unwrap();
+        let Incoming::NewText {
+            id, text, caret, ..
+        } = message;
+        assert_eq!(
+            ("tab-1", "hi", 2),
+            (id.as_str().unwrap(), text.as_str(), caret)
+        );
+    }
+
+    #[test]
+    fn writes_textern_messages() {
+        let update = Outgoing::TextUpdate {
+            id: serde_json::json!("tab-1"),
+            text: String::from("hello"),
+        };
+        assert_eq!(
+            r#"{"type":"text_update","payload":{"id":"tab-1","text":"hello"}}"#,
+            serde_json::to_string(&update).unwrap()
+        );
+        let death = Outgoing::DeathNotify {
+            id: serde_json::json!("tab-1"),
+        };
+        assert_eq!(
+            r#"{"type":"death_notify","payload":{"id":"tab-1"}}"#,
+            serde_json::to_string(&death).unwrap()
+        );
+    }
+}
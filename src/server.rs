@@ -1,39 +1,192 @@
-use std::{net::ToSocketAddrs, path::Path, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ffi::OsString,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::{bail, Context};
 use tokio::{
-    sync::{mpsc, Semaphore},
-    time::{self, timeout, Duration},
+    fs,
+    net::TcpListener,
+    sync::{watch, Notify},
+    time::{timeout, Duration, Instant},
 };
-use tokio_stream::wrappers::UnboundedReceiverStream;
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::wrappers::{IntervalStream, TcpListenerStream};
 
 use futures::FutureExt;
-use futures::{pin_mut, stream::SplitSink, SinkExt, StreamExt};
-use url::Url;
+use futures::{
+    future::{self, FusedFuture, Future},
+    pin_mut,
+    stream::{BoxStream, Fuse, SplitSink, SplitStream},
+    Sink, SinkExt, Stream, StreamExt,
+};
 use warp::{
-    http::HeaderValue,
-    reject::reject,
+    http::{header, HeaderValue, StatusCode},
     ws::{Message, WebSocket},
-    Filter,
+    Filter, Rejection, Reply,
 };
 
+#[cfg(feature = "clipboard")]
+mod clipboard;
 mod editor;
+mod editorconfig;
+mod events;
+use editor::SessionEnv;
+pub use editor::{split_command, EditorFor};
+use events::Subscriber;
+pub use events::{Event as SessionEvent, SessionEvents};
+pub use file::ExtensionFor;
+pub use format::Formatter;
+mod dir_pool;
+mod drafts;
 mod file;
-use file::{watch_edits, LocalFile};
-mod msg;
+mod focus;
+mod format;
+pub use file::watch_edits;
+mod glob;
+mod handoff;
+mod help;
+mod hooks;
+use handoff::{Handoff, Record};
+mod idle;
+use dir_pool::DirPool;
+mod local;
+pub use drafts::{Drafts, Saved};
+use file::{LocalFile, TooLarge};
+use idle::Activity;
+pub use local::{LocalSessions, Transport};
+pub mod msg;
+pub use msg::PROTOCOL_VERSION;
+mod origin;
+pub use origin::{DefaultOriginPolicy, OriginPolicy};
+#[cfg(feature = "preview")]
+mod preview;
+mod queue;
+use queue::{EditorGroups, EditorQueue};
+mod resume;
+use resume::Resumable;
+mod rules;
+use rules::{Rule, Rules};
+mod session;
+use session::{Delay, SessionId, SessionInfo, Sessions};
+mod stats;
+use stats::{DomainStats, RejectedOrigin, Rejections, Stats};
 mod text;
+#[cfg(all(feature = "tray", target_os = "linux"))]
+mod tray;
+#[cfg(unix)]
+mod unix_socket;
 #[cfg(feature = "watch_changes")]
 mod watch_changes;
+mod webhook;
+#[cfg(target_os = "linux")]
+mod wsl;
+use webhook::Webhook;
+
+/// Internals exposed to the fuzz targets in `fuzz/`
+#[cfg(fuzzing)]
+pub mod fuzzing {
+    pub use super::file::get_filename;
+    pub use super::text::utf16_offset_to_utf8_line_col;
+}
 
-use crate::debounce::MyStreamExt;
-use crate::settings::Settings;
+/// Internals exposed to the benchmarks in `benches/`
+#[cfg(feature = "benches")]
+#[doc(hidden)]
+pub mod benches {
+    pub use super::file::{calculate_hash, LocalFile};
+    pub use super::text::utf16_offset_to_utf8_line_col;
+    pub use crate::debounce::MyStreamExt;
+}
+
+use crate::build_info::BuildInfo;
+use crate::ctl;
+use crate::debounce::{MyStreamExt, Wait};
+use crate::settings::{Duplicates, Settings};
 
 type WebSocketTx = SplitSink<WebSocket, Message>;
+type WebSocketRx = SplitStream<WebSocket>;
+/// Handed to a session continuing on a new websocket
+type Connection = (WebSocketTx, WebSocketRx);
+
+/// Websocket close code for a connection that is no longer needed
+const CLOSE_NORMAL: u16 = 1000;
+/// Websocket close code for a malformed message, see RFC 6455 section 7.4.1
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Websocket close code for a message that is too big to process
+const CLOSE_TOO_BIG: u16 = 1009;
+/// Websocket close code for a server problem that prevents editing
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// Log target of the time updates take, e.g. `RUST_LOG=gtany::timings=debug`
+const TIMINGS: &str = "gtany::timings";
+
+/// Bytes tungstenite buffers before writing to the socket, not configurable through warp
+const WRITE_BUFFER_SIZE: usize = 128 * 1024;
 
 #[derive(Debug, Clone)]
 struct State {
     options: Settings,
-    single_access: Arc<Semaphore>,
+    single_access: Arc<EditorQueue>,
+    /// Queues of rules' concurrency groups
+    groups: Arc<EditorGroups>,
+    webhook: Option<Webhook>,
+    /// Set by library users with [`run_with_events`]
+    events: Option<Subscriber>,
+    stats: Stats,
+    rejections: Rejections,
+    sessions: Sessions,
+    handoff: Handoff,
+    resumable: Resumable<Connection>,
+    rules: Rules,
+    /// Session directories to reuse
+    dirs: DirPool,
+    drafts: Option<Drafts>,
+    #[cfg(feature = "preview")]
+    previews: preview::Previews,
+    #[cfg(feature = "clipboard")]
+    clipboard: Option<clipboard::ClipboardBackup>,
+    /// Notified to stop the server
+    #[cfg_attr(not(all(feature = "tray", target_os = "linux")), allow(dead_code))]
+    shutdown: Arc<Notify>,
+    activity: Activity,
+}
+
+impl State {
+    /// Pass a session's event to the webhook and library user, if any
+    fn notify(&self, event: SessionEvent, page: &msg::GetTextFromComponent) {
+        if let Some(Subscriber(events)) = &self.events {
+            events.notify(&event, page);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event, page);
+        }
+    }
+}
+
+/// Query parameters of the websocket route
+#[derive(Debug, Deserialize)]
+struct ResumeQuery {
+    /// Token of a session to continue
+    resume: Option<String>,
+}
+
+/// Response body of the status endpoint
+#[derive(Debug, Serialize)]
+struct Status {
+    sessions: Vec<SessionInfo>,
+    domains: BTreeMap<String, DomainStats>,
+    /// Websockets refused by the origin check, by origin
+    rejected_origins: BTreeMap<String, RejectedOrigin>,
 }
 
 fn with_state<S: Clone + Send>(
@@ -42,173 +195,1051 @@ fn with_state<S: Clone + Send>(
     warp::any().map(move || state.clone())
 }
 
-/// Ensures the request Origin header is set to an extension uri.
-///
-/// If a Websocket request is sent by a browser, the origin will be set to:
-/// - `null`
-/// - the url of the initiating webpage
-/// - some form of `*-extension://*` if initiated by an extension
-///
-/// Restricting it to extensions prevents random websites from trying to exfiltrate or exploit.
-/// See: <https://christian-schneider.net/CrossSiteWebSocketHijacking.html>.
-fn is_extension_origin() -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Copy {
-    warp::header::value("origin")
-        .and_then(|origin: HeaderValue| async move {
-            // Verify websocket is from extension context
-            let origin = origin.to_str().map_err(|e| {
-                warn!("Rejecting request from non-string origin: {origin:?}: {e}");
-                reject()
-            })?;
-            let origin = Url::parse(origin).map_err(|e| {
-                warn!("Rejecting request from unparseable origin: {origin:?}: {e}");
-                reject()
-            })?;
-
-            if !origin.scheme().ends_with("extension") {
-                warn!("Rejecting request from non-extension origin: {origin:?}");
-                return Err(reject());
-            }
+/// Ensures the request Origin header is accepted by `policy`, counting rejections
+fn is_allowed_origin(
+    policy: Arc<dyn OriginPolicy>,
+    rejections: Rejections,
+) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+    warp::header::optional("origin")
+        .and_then(move |origin: Option<HeaderValue>| {
+            let policy = policy.clone();
+            let rejections = rejections.clone();
+            async move {
+                let forbidden = |reason: String| {
+                    let key = origin
+                        .as_ref()
+                        .map(|o| String::from_utf8_lossy(o.as_bytes()));
+                    let count = rejections.add(key.as_deref(), &reason);
+                    warn!("Rejecting request {reason} ({count} from this origin so far)");
+                    warp::reject::custom(ForbiddenOrigin(reason))
+                };
 
-            Ok(())
+                let origin = origin
+                    .as_ref()
+                    .map(|origin| {
+                        origin.to_str().map_err(|e| {
+                            forbidden(format!("from non-string origin: {origin:?}: {e}"))
+                        })
+                    })
+                    .transpose()?;
+
+                policy.check(origin).map_err(forbidden)
+            }
         })
         .untuple_one()
 }
 
+/// Whether a `/ctl` request with the `authorization` header may change sessions
+///
+/// Pages can't reach a `--unix-socket`, which is limited by its permissions,
+/// and can't know the `--ctl-token` that other requests need.
+fn is_allowed_ctl(token: Option<&str>, over_socket: bool, authorization: Option<&str>) -> bool {
+    let sent = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    over_socket || token.is_some_and(|token| !token.is_empty() && sent == Some(token))
+}
+
+/// Whether a Host header names this machine, like `localhost:4001` or `[::1]`
+///
+/// A page whose domain was rebound to the server's address still sends its
+/// own domain.
+fn is_loopback_host(host: &str) -> bool {
+    let Ok(url) = url::Url::parse(&format!("http://{host}/")) else {
+        return false;
+    };
+    match url.host() {
+        Some(url::Host::Domain(name)) => name == "localhost" || name.ends_with(".localhost"),
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+/// Websocket request from somewhere other than a browser extension
+#[derive(Debug)]
+struct ForbiddenOrigin(String);
+
+impl warp::reject::Reject for ForbiddenOrigin {}
+
+/// Explain why a websocket was refused instead of falling through to a 404
+async fn explain_forbidden(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<ForbiddenOrigin>() {
+        Some(ForbiddenOrigin(reason)) => Ok(warp::reply::with_status(
+            format!(
+                "Forbidden: only browser extensions may connect, got a request {reason}\n\
+                 See {} to set up the GhostText extension.\n",
+                help::SETUP_DOCS
+            ),
+            StatusCode::FORBIDDEN,
+        )),
+        None => Err(rejection),
+    }
+}
+
+/// Serve until stopped by Ctrl-C, SIGTERM, the tray, or `--idle-timeout`
+///
+/// On Ctrl-C or SIGTERM, active sessions close their editors and send their
+/// text to the browser, within `--shutdown-grace`.
 pub async fn run(options: Settings) -> anyhow::Result<()> {
+    run_profiles(options, None, None, future::pending(), true).await
+}
+
+/// Like [`run`], also stopping once `stop` resolves
+///
+/// Active sessions get `--shutdown-grace` to send their final text, as when
+/// stopped by the idle timeout. `stop` can be a tokio-util
+/// `CancellationToken::cancelled_owned()` or a oneshot receiver, for example.
+pub async fn run_with_shutdown(
+    options: Settings,
+    stop: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    run_profiles(options, None, None, stop, false).await
+}
+
+/// Like [`run_with_shutdown`], also passing the events of every session to `events`
+///
+/// The same events as sent to `--webhook`, e.g. to show sessions in an editor
+/// plugin embedding the server.
+pub async fn run_with_events(
+    options: Settings,
+    stop: impl Future<Output = ()> + Send + 'static,
+    events: impl SessionEvents + 'static,
+) -> anyhow::Result<()> {
+    run_profiles(
+        options,
+        None,
+        Some(Subscriber(Arc::new(events))),
+        stop,
+        false,
+    )
+    .await
+}
+
+/// Like [`run`], deciding which websockets to accept with `policy`
+///
+/// The policy applies to all `--profile`s and replaces `--allow-origin` and
+/// `--allow-null-origin`, see [`DefaultOriginPolicy`] to build on them.
+pub async fn run_with_origin_policy(
+    options: Settings,
+    policy: impl OriginPolicy + 'static,
+) -> anyhow::Result<()> {
+    run_profiles(
+        options,
+        Some(Arc::new(policy)),
+        None,
+        future::pending(),
+        false,
+    )
+    .await
+}
+
+async fn run_profiles(
+    options: Settings,
+    policy: Option<Arc<dyn OriginPolicy>>,
+    events: Option<Subscriber>,
+    stop: impl Future<Output = ()> + Send + 'static,
+    signals: bool,
+) -> anyhow::Result<()> {
+    let profiles = parse_profiles(&options)?;
+    let grace = Duration::from_secs(options.shutdown_grace);
+
+    // set on Ctrl-C or SIGTERM, to finish active sessions instead of waiting for them
+    let (drain, draining) = watch::channel(false);
+    let stop = async move {
+        if !signals {
+            return stop.await;
+        }
+        tokio::select! {
+            () = stop => {}
+            () = terminated() => {
+                info!("Stopping on signal");
+                drain.send_replace(true);
+            }
+        }
+    };
+
+    // shared by all profiles, so the idle timeout only applies once all are idle
+    let shared = Shared {
+        events,
+        draining,
+        unbound: Arc::new(AtomicUsize::new(profiles.len() + 1)),
+        shutdown: Arc::new(Notify::new()),
+        activity: Activity::default(),
+        handing_off: Arc::default(),
+    };
+    let stopped = shutdown_signal(
+        shared.shutdown.clone(),
+        options.idle_timeout.map(Duration::from_secs),
+        shared.activity.clone(),
+        stop,
+    )
+    .shared();
+
+    future::try_join_all(std::iter::once(options).chain(profiles).map(|options| {
+        let policy = policy
+            .clone()
+            .unwrap_or_else(|| Arc::new(DefaultOriginPolicy::new(&options)));
+        serve(options, policy, shared.clone(), stopped.clone())
+    }))
+    .await?;
+
+    // websockets outlive the server, let them send the final text
+    let Shared {
+        activity,
+        handing_off,
+        ..
+    } = shared;
+    let active = activity.active();
+    if active > 0 {
+        info!("Waiting up to {grace:?} for {active} session(s) to finish");
+        if timeout(grace, activity.until_idle()).await.is_err() {
+            warn!(
+                "Stopping with {} session(s) still active, their text is not sent back",
+                activity.active()
+            );
+            // their editors and files are taken over by the next server
+            handing_off.store(true, Ordering::SeqCst);
+        }
+    }
+
+    Ok(())
+}
+
+/// Settings of each `--profile`
+fn parse_profiles(options: &Settings) -> anyhow::Result<Vec<Settings>> {
+    let mut profiles = Vec::with_capacity(options.profile.len());
+    for args in &options.profile {
+        let argv = std::iter::once(String::from("gtany"))
+            .chain(split_command(args)?)
+            .map(OsString::from)
+            .collect();
+        let argv = crate::config::args_with_config(argv, false)
+            .with_context(|| format!("Invalid --profile {args:?}"))?;
+        let profile: Settings = clap::Parser::try_parse_from(argv)
+            .with_context(|| format!("Invalid --profile {args:?}"))?;
+        if profile.command.is_some() {
+            bail!("--profile {args:?} can't run a subcommand");
+        }
+        if !profile.profile.is_empty() {
+            bail!("--profile {args:?} can't contain other profiles");
+        }
+        if profile.idle_timeout.is_some() {
+            bail!("--idle-timeout applies to all profiles, pass it outside of --profile {args:?}");
+        }
+        profiles.push(profile);
+    }
+    Ok(profiles)
+}
+
+/// Handles shared by the profiles of a run
+#[derive(Clone)]
+struct Shared {
+    /// Set by library users with [`run_with_events`]
+    events: Option<Subscriber>,
+    /// Set on Ctrl-C or SIGTERM, to finish active sessions
+    draining: watch::Receiver<bool>,
+    /// Profiles not listening yet, ready once none are left
+    unbound: Arc<AtomicUsize>,
+    /// Notified to stop the server
+    shutdown: Arc<Notify>,
+    activity: Activity,
+    /// Set when stopping with sessions left, to leave them to the next server
+    /// with `--state-file`
+    handing_off: Arc<AtomicBool>,
+}
+
+/// Serve one profile until `stopped` resolves
+async fn serve(
+    options: Settings,
+    policy: Arc<dyn OriginPolicy>,
+    shared: Shared,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let Shared {
+        events,
+        mut draining,
+        unbound,
+        shutdown,
+        activity,
+        handing_off,
+    } = shared;
+    if let Some(max) = options.max_write_buffer_size {
+        // tungstenite panics on connection otherwise
+        if max <= WRITE_BUFFER_SIZE {
+            bail!("--max-write-buffer-size must be greater than {WRITE_BUFFER_SIZE} bytes");
+        }
+    }
+    for pattern in &options.allow_origin {
+        warn!("Accepting websockets from origins matching {pattern:?}, any such page can use your editor");
+    }
+    #[cfg(target_os = "linux")]
+    if options.wsl && !wsl::is_wsl() {
+        warn!("Not running inside WSL, --wsl may not work");
+    }
+    if options.allow_null_origin {
+        warn!("Accepting websockets without an origin, any local program can use your editor");
+    }
+
+    let listener = match options {
+        #[cfg(all(feature = "systemd", target_os = "linux"))]
+        Settings {
+            from_systemd: true, ..
+        } => Listener::Systemd(super::systemd::try_get_socket()?),
+        #[cfg(unix)]
+        Settings {
+            unix_socket: Some(ref path),
+            ..
+        } => {
+            let (listener, socket) = unix_socket::bind(path)?;
+            Listener::Unix(listener, socket)
+        }
+        _ => {
+            Listener::Tcp(bind_listener(&options.host, options.port, options.port_fallback).await?)
+        }
+    };
+
+    // advertise the port that was actually bound
+    let port = match &listener {
+        Listener::Tcp(listener) => listener.local_addr()?.port(),
+        #[cfg(all(feature = "systemd", target_os = "linux"))]
+        Listener::Systemd(_) => options.port,
+        #[cfg(unix)]
+        Listener::Unix(..) => options.port,
+    };
+
     let state = State {
         options: options.clone(),
-        single_access: Arc::new(Semaphore::new(1)),
+        single_access: Arc::new(EditorQueue::new()),
+        groups: Arc::new(EditorGroups::new(options.group_size.clone())),
+        webhook: options.webhook.clone().map(Webhook::new).transpose()?,
+        events,
+        stats: Stats::default(),
+        rejections: Rejections::default(),
+        sessions: Sessions::default(),
+        handoff: Handoff::load(options.state_file.as_deref(), handing_off.clone())?,
+        resumable: Resumable::default(),
+        rules: Rules::load(options.rules.as_deref(), &options.domain_rule)?,
+        dirs: match &options.state_file {
+            Some(state_file) => DirPool::new(options.dir_pool, options.max_dirs)
+                .for_handoff(handoff::sessions_dir(state_file), handing_off)
+                .context("Unable to create directory for --state-file sessions")?,
+            None => DirPool::new(options.dir_pool, options.max_dirs),
+        },
+        drafts: options.drafts_dir.clone().map(Drafts::new).transpose()?,
+        #[cfg(feature = "preview")]
+        previews: preview::Previews::new(match &listener {
+            Listener::Tcp(listener) => match listener.local_addr()? {
+                addr if addr.ip().is_unspecified() => format!("http://localhost:{port}"),
+                addr => format!("http://{addr}"),
+            },
+            #[cfg(all(feature = "systemd", target_os = "linux"))]
+            Listener::Systemd(_) => format!("http://localhost:{port}"),
+            #[cfg(unix)]
+            Listener::Unix(..) => format!("http://localhost:{port}"),
+        }),
+        #[cfg(feature = "clipboard")]
+        clipboard: options
+            .clipboard
+            .then(clipboard::ClipboardBackup::spawn)
+            .transpose()?,
+        shutdown,
+        activity,
     };
 
-    let (thread_update_snd, thread_update_rec) = mpsc::unbounded_channel::<ThreadStatus>();
-
     let ws_route = warp::path::end()
-        .and(is_extension_origin())
-        .and(with_state(state.clone()))
         // The `ws()` filter will prepare the Websocket handshake.
         .and(warp::ws())
-        .map(move |state: State, ws: warp::ws::Ws| {
+        .and(is_allowed_origin(policy, state.rejections.clone()))
+        .and(warp::query::<ResumeQuery>())
+        .and(with_state(state.clone()))
+        .map(move |ws: warp::ws::Ws, query: ResumeQuery, state: State| {
+            let ws = configure_websocket(ws, &state.options);
+            // counted as active until the connection is handled or the upgrade is dropped
+            let active = state.activity.start();
             // And then our closure will be called when it completes...
-            let thread_update_snd = thread_update_snd.clone();
             ws.on_upgrade(|websocket| async move {
-                let use_timeout = state.options.idle_timeout.is_some();
-                if use_timeout {
-                    thread_update_snd
-                        .send(ThreadStatus::Started)
-                        .unwrap_or_else(|e| error!("Cannot send to thread update channel: {}", e));
-                }
-
-                handle_websocket(state, websocket)
+                handle_websocket(state, websocket, query.resume)
                     .await
                     .unwrap_or_else(|e| error!("Error handling websocket: {:?}", e));
 
-                if use_timeout {
-                    thread_update_snd
-                        .send(ThreadStatus::Finished)
-                        .unwrap_or_else(|e| error!("Cannot send to thread update channel: {}", e));
-                }
+                drop(active);
             })
-        });
+        })
+        // other requests for the index are handled below
+        .recover(explain_forbidden);
 
     let index = warp::path::end()
-        .and(with_state(options.clone()))
-        .map(redirect_to_websocket);
+        // the extension and some proxies probe with HEAD
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(port))
+        .map(|accept: Option<String>, port| {
+            let reply = if help::wants_html(accept.as_deref()) {
+                warp::reply::html(help::page(port)).into_response()
+            } else {
+                redirect_to_websocket(port).into_response()
+            };
+            // the port can change between runs
+            warp::reply::with_header(reply, header::CACHE_CONTROL, "no-cache")
+        });
+
+    // instead of falling through to a 404
+    let index_methods = warp::path::end().map(|| {
+        let reply =
+            warp::reply::with_status("Method Not Allowed\n", StatusCode::METHOD_NOT_ALLOWED);
+        warp::reply::with_header(reply, header::ALLOW, "GET, HEAD")
+    });
+
+    let version = warp::path("version")
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&BuildInfo::current()));
+
+    #[cfg(unix)]
+    let over_socket = matches!(listener, Listener::Unix(..));
+    #[cfg(not(unix))]
+    let over_socket = false;
+    let ctl_token = options.ctl_token.clone();
+
+    let status = warp::path("status")
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .map({
+            let ctl_token = ctl_token.clone();
+            move |host: Option<String>, authorization: Option<String>, state: State| {
+                // pages and files of sessions, so not for pages that rebind their domain to us
+                if !host.as_deref().is_some_and(is_loopback_host)
+                    && !is_allowed_ctl(ctl_token.as_deref(), over_socket, authorization.as_deref())
+                {
+                    warn!("Rejecting status request for host {host:?}");
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                warp::reply::json(&Status {
+                    sessions: state.sessions.list(),
+                    domains: state.stats.snapshot(),
+                    rejected_origins: state.rejections.snapshot(),
+                })
+                .into_response()
+            }
+        });
+
+    let set_delay = warp::path!("ctl" / "set-delay")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_state(state.sessions.clone()))
+        .map(
+            move |authorization: Option<String>, request: ctl::SetDelay, sessions: Sessions| {
+                if !is_allowed_ctl(ctl_token.as_deref(), over_socket, authorization.as_deref()) {
+                    warn!("Rejecting control request without a valid --ctl-token");
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                let delay = Duration::from_millis(request.millis);
+                let changed = sessions.set_delay(request.session, delay);
+                info!("Set delay of session(s) {changed:?} to {delay:?}");
+                warp::reply::json(&changed).into_response()
+            },
+        );
 
     // since websocket filter is more restrictive match on it first
     let routes = ws_route
         .or(index)
-        .with(warp::log::log("gtany::server::request"));
+        .or(version)
+        .or(status)
+        .or(set_delay)
+        .or(index_methods);
 
-    let mut addrs = (options.host.as_str(), options.port)
-        .to_socket_addrs()
-        .with_context(|| format!("Invalid server address: {}:{}", options.host, options.port))?;
-    let addr = addrs.next().unwrap();
+    #[cfg(feature = "preview")]
+    let routes = routes.or(state.previews.clone().routes(options.max_text_size));
+
+    let routes = routes.with(warp::log::log("gtany::server::request"));
 
     let server = warp::serve(routes);
 
-    match options {
-        Settings {
-            idle_timeout: None,
-            #[cfg(all(feature = "systemd", target_os = "linux"))]
-                from_systemd: false,
-            ..
-        } => {
-            info!("Listening on http://{}", addr);
-            server.bind(addr).await;
+    if options.state_file.is_some() {
+        tokio::spawn(state.handoff.clone().expire_recovered());
+    }
+
+    let sessions = state.sessions.clone();
+    tokio::spawn(async move {
+        while !*draining.borrow_and_update() {
+            if draining.changed().await.is_err() {
+                return;
+            }
         }
-        Settings {
-            idle_timeout: Some(timeout_sec),
-            #[cfg(all(feature = "systemd", target_os = "linux"))]
-                from_systemd: false,
-            ..
-        } => {
-            info!("Listening on http://{}", addr);
-            debug!("Idle timeout after {} secs", timeout_sec);
-            let timeout_task =
-                idle_timeout(time::Duration::from_secs(timeout_sec), thread_update_rec);
-            let (_addr, serve_task) = server.bind_with_graceful_shutdown(addr, timeout_task);
-            serve_task.await;
+        let finishing = sessions.finish_all();
+        if finishing > 0 {
+            info!("Finishing {finishing} active session(s)");
         }
+    });
+
+    #[cfg(all(feature = "tray", target_os = "linux"))]
+    if options.tray {
+        tray::spawn(state.sessions.clone(), state.shutdown.clone());
+    }
+
+    if unbound.fetch_sub(1, Ordering::SeqCst) == 1 {
         #[cfg(all(feature = "systemd", target_os = "linux"))]
-        Settings {
-            idle_timeout: None,
-            from_systemd: true,
-            ..
-        } => {
-            let listener_stream = super::systemd::try_get_socket()?;
-            info!("Listening on systemd socket");
-            server.serve_incoming(listener_stream).await;
+        super::systemd::notify("READY=1");
+    }
+
+    match listener {
+        Listener::Tcp(listener) => {
+            info!("Listening on http://{}", listener.local_addr()?);
+            server
+                .serve_incoming_with_graceful_shutdown(TcpListenerStream::new(listener), stopped)
+                .await;
         }
         #[cfg(all(feature = "systemd", target_os = "linux"))]
-        Settings {
-            idle_timeout: Some(timeout_sec),
-            from_systemd: true,
-            ..
-        } => {
-            let listener_stream = super::systemd::try_get_socket()?;
+        Listener::Systemd(listener_stream) => {
             info!("Listening on systemd socket");
-            debug!("Idle timeout after {} secs", timeout_sec);
-            let timeout_task =
-                idle_timeout(time::Duration::from_secs(timeout_sec), thread_update_rec);
             server
-                .serve_incoming_with_graceful_shutdown(listener_stream, timeout_task)
+                .serve_incoming_with_graceful_shutdown(listener_stream, stopped)
+                .await;
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener_stream, socket) => {
+            info!("Listening on {:?}", socket.path());
+            server
+                .serve_incoming_with_graceful_shutdown(listener_stream, stopped)
                 .await;
         }
     }
 
+    state.stats.log_summary();
+    state.rejections.log_summary();
+
     Ok(())
 }
 
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(all(feature = "systemd", target_os = "linux"))]
+    Systemd(UnixListenerStream),
+    /// Removes the socket once the server stops
+    #[cfg(unix)]
+    Unix(UnixListenerStream, unix_socket::SocketFile),
+}
+
+/// Bind to the first available port of `port..=port + fallback`
+async fn bind_listener(host: &str, port: u16, fallback: u16) -> anyhow::Result<TcpListener> {
+    let addr = resolve_host(host, port)?;
+
+    let last_port = port.saturating_add(fallback);
+    for port in port..=last_port {
+        let addr = SocketAddr::new(addr.ip(), port);
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && port < last_port => {
+                warn!("Port {} is in use, trying {}", port, port + 1);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Unable to bind to {}", addr)),
+        }
+    }
+
+    unreachable!("Last port either binds or returns an error")
+}
+
+/// Resolve `--host` to the address to bind to
+///
+/// Accepts IP literals, with or without brackets around IPv6 addresses, and
+/// hostnames. A hostname with several addresses uses the first one returned by
+/// the resolver.
+pub fn resolve_host(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    let unbracketed = match host.strip_prefix('[') {
+        Some(rest) => rest
+            .strip_suffix(']')
+            .with_context(|| format!("Invalid host {host:?}: missing closing bracket"))?,
+        None => host,
+    };
+
+    let bracketed = unbracketed.len() != host.len();
+    match unbracketed.parse::<IpAddr>() {
+        Ok(ip @ IpAddr::V6(_)) => return Ok(SocketAddr::new(ip, port)),
+        Ok(ip @ IpAddr::V4(_)) if !bracketed => return Ok(SocketAddr::new(ip, port)),
+        _ => {}
+    }
+    if bracketed {
+        bail!("Invalid host {host:?}: only IPv6 addresses go in brackets");
+    }
+    if host.contains(':') {
+        bail!("Invalid host {host:?}: set the port with --port");
+    }
+
+    let addrs: Vec<_> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Unable to resolve host {host:?}"))?
+        .collect();
+    let addr = *addrs
+        .first()
+        .with_context(|| format!("No addresses found for {host:?}"))?;
+    if addrs.len() > 1 {
+        let others: Vec<_> = addrs[1..].iter().map(|a| a.ip().to_string()).collect();
+        info!(
+            "{host:?} resolves to several addresses, using {} over {}",
+            addr.ip(),
+            others.join(", ")
+        );
+    } else {
+        debug!("{host:?} resolves to {}", addr.ip());
+    }
+
+    Ok(addr)
+}
+
+/// Apply websocket size limits from the command line
+fn configure_websocket(mut ws: warp::ws::Ws, options: &Settings) -> warp::ws::Ws {
+    // enforced as frames arrive, before the message is in memory
+    let max_message_size = options.max_message_size.unwrap_or_else(|| {
+        options
+            .max_text_size
+            .saturating_mul(2)
+            .saturating_add(64 * 1024)
+    });
+    ws = ws.max_message_size(max_message_size);
+    if let Some(max) = options.max_frame_size {
+        ws = ws.max_frame_size(max);
+    }
+    if let Some(max) = options.max_write_buffer_size {
+        ws = ws.max_write_buffer_size(max);
+    }
+    ws
+}
+
 /// Send initial json redirect info for Ghost Text protocol
-fn redirect_to_websocket(options: Settings) -> String {
-    serde_json::to_string(&msg::RedirectToWebSocket {
-        WebSocketPort: options.port.to_owned(),
-        ProtocolVersion: 1,
+fn redirect_to_websocket(port: u16) -> warp::reply::Json {
+    warp::reply::json(&msg::RedirectToWebSocket {
+        WebSocketPort: port,
+        ProtocolVersion: PROTOCOL_VERSION,
     })
-    .unwrap()
 }
 
 /// Communicate over a websocket, manage an intermediate file, spawn an editor, watch for changes
-async fn handle_websocket(state: State, stream: WebSocket) -> anyhow::Result<()> {
+async fn handle_websocket(
+    state: State,
+    stream: WebSocket,
+    resume: Option<String>,
+) -> anyhow::Result<()> {
     let (mut tx, mut rx) = stream.split();
+    let send_timeout = Duration::from_secs(state.options.send_timeout);
 
-    let init_message: Message = rx.next().await.expect("Need an initial edit message")?;
+    if let Some(token) = resume {
+        return match state.resumable.resume(&token, (tx, rx)) {
+            Ok(()) => Ok(()),
+            Err((mut tx, _rx)) => {
+                let reason = "Unknown or expired resume token";
+                send_close(&mut tx, send_timeout, CLOSE_PROTOCOL_ERROR, reason).await;
+                bail!(reason);
+            }
+        };
+    }
 
-    debug!("First message: {:?}", init_message);
-    let init_message: msg::GetTextFromComponent = if init_message.is_text() {
-        serde_json::from_str(init_message.to_str().expect("Is a text msg"))
-            .context("Couldn't parse initial websocket message")?
-    } else {
-        bail!("Initial websocket message not text")
+    let mut init_message = match read_init_message(&mut rx).await {
+        Ok(init_message) => init_message,
+        Err(e) => {
+            let reason = "Invalid initial message";
+            send_close(&mut tx, send_timeout, CLOSE_PROTOCOL_ERROR, reason).await;
+            return Err(e);
+        }
+    };
+
+    if let Some(token) = &init_message.resume_token {
+        match state.resumable.resume(token, (tx, rx)) {
+            Ok(()) => return Ok(()),
+            Err(connection) => {
+                info!("Unknown or expired resume token, starting a new session");
+                (tx, rx) = connection;
+            }
+        }
+    }
+
+    let duplicate = state
+        .sessions
+        .find_detached(&init_message.url, &init_message.title);
+    if let Some((id, token)) = duplicate {
+        match state.options.duplicates {
+            Duplicates::Adopt => match state.resumable.resume(&token, (tx, rx)) {
+                Ok(()) => {
+                    info!(
+                        "Continuing session {id} for reopened page {:?}",
+                        init_message.title
+                    );
+                    return Ok(());
+                }
+                Err(connection) => (tx, rx) = connection,
+            },
+            Duplicates::Replace => {
+                info!(
+                    "Ending session {id}, replaced by a new one for {:?}",
+                    init_message.title
+                );
+                state.sessions.kill(id);
+            }
+            Duplicates::Allow => {}
+        }
+    }
+
+    if let (true, Some(millis)) = (init_message.text.is_empty(), state.options.wait_for_text) {
+        if let Err(e) =
+            wait_for_text(&mut rx, &mut init_message, Duration::from_millis(millis)).await
+        {
+            send_close(
+                &mut tx,
+                send_timeout,
+                CLOSE_PROTOCOL_ERROR,
+                format!("{e:#}"),
+            )
+            .await;
+            return Err(e);
+        }
+    }
+
+    state.notify(SessionEvent::Start, &init_message);
+
+    let domain = init_message.domain();
+    let start = Instant::now();
+
+    let result = edit_session(&state, &mut tx, rx, &init_message).await;
+    if let Err(e) = &result {
+        send_close(&mut tx, send_timeout, close_code(e), format!("{e:#}")).await;
+    }
+
+    state.stats.add_session(domain.as_deref(), start.elapsed());
+
+    let event = match &result {
+        Ok(Ending::Finished) => SessionEvent::End,
+        Ok(Ending::Detached) => SessionEvent::Detached,
+        Err(e) => SessionEvent::Error {
+            error: format!("{e:#}"),
+        },
+    };
+    state.notify(event, &init_message);
+
+    result.map(drop)
+}
+
+/// Let the client know why the connection is ending, if it's still listening
+async fn send_close(
+    tx: &mut WebSocketTx,
+    send_timeout: Duration,
+    code: u16,
+    reason: impl Into<String>,
+) {
+    // must fit in a close frame
+    const MAX_REASON_LEN: usize = 123;
+
+    let mut reason = reason.into();
+    if reason.len() > MAX_REASON_LEN {
+        let end = (0..=MAX_REASON_LEN)
+            .rev()
+            .find(|&i| reason.is_char_boundary(i))
+            .unwrap_or(0);
+        reason.truncate(end);
+    }
+
+    let close = Message::close_with(code, reason);
+    if let Err(e) = send_with_timeout(tx, send_timeout, close).await {
+        debug!("Unable to send websocket close: {:#}", e);
+    }
+}
+
+/// Send a message, treating the browser as disconnected if it stalls for `limit`
+async fn send_with_timeout<S>(tx: &mut S, limit: Duration, message: Message) -> anyhow::Result<()>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    match timeout(limit, tx.send(message)).await {
+        Ok(result) => Ok(result?),
+        Err(_) => bail!("Timed out sending to browser after {limit:?}, assuming it disconnected"),
+    }
+}
+
+/// Wait for the first edit message, skipping pings
+async fn read_init_message(rx: &mut WebSocketRx) -> anyhow::Result<msg::GetTextFromComponent> {
+    loop {
+        let message = rx
+            .next()
+            .await
+            .context("Websocket closed before initial message")?
+            .context("Websocket error before initial message")?;
+
+        debug!("First message: {:?}", message);
+
+        if let Ok(text) = message.to_str() {
+            return serde_json::from_str(text).context("Couldn't parse initial websocket message");
+        } else if message.is_ping() || message.is_pong() {
+            continue;
+        } else if message.is_close() {
+            bail!("Websocket closed before initial message");
+        } else {
+            bail!("Initial websocket message not text");
+        }
+    }
+}
+
+/// Wait up to `limit` for the page to fill in an empty field
+///
+/// Takes the text and selections of the first update with text, or keeps the
+/// empty text if there is none in time.
+async fn wait_for_text(
+    rx: &mut WebSocketRx,
+    init_message: &mut msg::GetTextFromComponent,
+    limit: Duration,
+) -> anyhow::Result<()> {
+    let wait = async {
+        loop {
+            let message = rx
+                .next()
+                .await
+                .context("Websocket closed while waiting for text")?
+                .context("Websocket error while waiting for text")?;
+            if message.is_close() {
+                bail!("Websocket closed while waiting for text");
+            }
+            let Ok(text) = message.to_str() else {
+                continue;
+            };
+            let update: msg::UpdateTextFromComponent =
+                serde_json::from_str(text).context("Couldn't parse websocket message")?;
+            if !update.text.is_empty() {
+                debug!("Received text after waiting");
+                init_message.text = update.text.into_owned();
+                init_message.selections = update.selections;
+                return Ok(());
+            }
+        }
     };
 
+    match timeout(limit, wait).await {
+        Ok(result) => result,
+        Err(_) => {
+            debug!("No text after {limit:?}, starting with an empty field");
+            Ok(())
+        }
+    }
+}
+
+/// How a session ended without errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ending {
+    /// The text was sent back, if allowed
+    Finished,
+    /// The page detached from the field, so nothing was sent back
+    Detached,
+}
+
+/// Sync the file and websocket until the editor exits
+///
+/// If the browser disconnects, the editor stays open until a new websocket
+/// resumes the session or `--resume-timeout` passes. If the page detaches
+/// from the field, syncing stops, and the editor is closed unless
+/// `--keep-detached` is set.
+async fn edit_session(
+    state: &State,
+    tx: &mut WebSocketTx,
+    rx: WebSocketRx,
+    init_message: &msg::GetTextFromComponent,
+) -> anyhow::Result<Ending> {
+    let domain = init_message.domain();
+    let domain = domain.as_deref();
+
+    let send_timeout = Duration::from_secs(state.options.send_timeout);
+    let resume_timeout = Duration::from_secs(state.options.resume_timeout);
+    let ping_interval = Duration::from_secs(state.options.ping_interval);
+    let finalize_after = state.options.finalize_after.map(Duration::from_secs);
+    let close_grace = Duration::from_secs(state.options.close_grace);
+
+    let rule = state.rules.resolve(domain);
+    let filter_in = state
+        .options
+        .filter_in
+        .as_deref()
+        .map(split_command)
+        .transpose()?;
+    let filter_in = filter_in.as_deref();
+    let filter_out = state
+        .options
+        .filter_out
+        .as_deref()
+        .map(split_command)
+        .transpose()?;
+    editor::check_terminal(
+        &state.options,
+        editor::command_for(&state.options, &rule, domain),
+    )?;
+    check_text_size(&init_message.text, state.options.max_text_size)?;
+
+    let session = state.sessions.register(init_message);
+    if let Some(label) = &rule.label {
+        session.set_label(label);
+    }
+    // the extension always sends at least the cursor
+    if init_message.selections.is_empty() {
+        session.warn(String::from(
+            "Page sent no selections, the cursor starts at the beginning",
+        ));
+    }
+    let mut resumes = state.resumable.register();
+    let resume_token = (!resume_timeout.is_zero()).then(|| resumes.token().to_owned());
+    let resume_token = resume_token.as_deref();
+
     // store client cursor changes and pass back and forth...
-    let mut cursors = init_message.selections.clone();
+    let mut cursors = Cursors {
+        text: init_message.text.clone(),
+        selections: init_message.selections.clone(),
+    };
 
-    // create file
-    let mut file = LocalFile::create(&init_message).await?;
+    // create file, or continue with a previous server's editor
+    let max_text_size = state.options.max_text_size;
+    let newline = rule.newline.unwrap_or(state.options.newline);
+    let mut recovered = state.handoff.claim(&init_message.url, &init_message.title);
+    let mut file = None;
+    if let Some(record) = &recovered {
+        match LocalFile::adopt(record.path.clone(), max_text_size, newline, &state.dirs).await {
+            Ok(adopted) => {
+                info!("Reconnected {:?} to editor {}", record.title, record.pid);
+                file = Some(adopted);
+            }
+            Err(e) => {
+                session.warn(format!("Unable to reconnect to editor {}: {e}", record.pid));
+                recovered = None;
+            }
+        }
+    }
+    let mut file = match file {
+        Some(file) => file,
+        None => {
+            let extension = file::extension_for(&state.options, &rule, domain);
+            let mut file = LocalFile::create(
+                init_message,
+                extension,
+                rule.label.as_deref(),
+                max_text_size,
+                newline,
+                &state.dirs,
+            )
+            .await?;
+            if filter_in.is_some() {
+                let path = file.as_ref().to_owned();
+                let env = SessionEnv {
+                    msg: init_message,
+                    rule: &rule,
+                    file: &path,
+                };
+                let text = filtered_in(filter_in, env, &init_message.text).await;
+                file.maybe_update(&text).await?;
+            }
+            file.mark_synced();
+            file
+        }
+    };
+    state.stats.add_received(domain, init_message.text.len());
     let file_path = file.as_ref().to_owned();
 
+    // sent while the file was created, so the editor opens the latest text
+    let (early, rx) = take_early_updates(rx, &init_message.url);
+    let latest;
+    let init_message = match early {
+        Some(update) => {
+            check_text_size(&update.text, max_text_size)?;
+            debug!("Applying update sent before the file was ready");
+            let env = SessionEnv {
+                msg: init_message,
+                rule: &rule,
+                file: &file_path,
+            };
+            let text = filtered_in(filter_in, env, &update.text).await;
+            if file.maybe_update(&text).await? {
+                state.stats.add_received(domain, update.text.len());
+            }
+            file.mark_synced();
+            latest = msg::GetTextFromComponent {
+                text: update.text.clone(),
+                selections: update.selections.clone(),
+                ..init_message.clone()
+            };
+            cursors = update;
+            &latest
+        }
+        None => init_message,
+    };
+    let env = SessionEnv {
+        msg: init_message,
+        rule: &rule,
+        file: &file_path,
+    };
+    session.set_file(&file_path);
+    // declared after the file, so it's saved before the file is removed
+    let mut draft = match &state.drafts {
+        Some(drafts) if !rule.read_only => {
+            Some(drafts.guard(&file_path, init_message, session.id()))
+        }
+        _ => None,
+    };
+
+    let mut templated = false;
+    if let (None, true, Some(template)) = (&recovered, init_message.text.is_empty(), &rule.template)
+    {
+        match apply_template(template, &mut file, max_text_size).await {
+            Ok(()) => templated = true,
+            Err(e) => session.warn(format!("{e:#}")),
+        }
+    }
+
+    if state.options.history {
+        file.keep_history();
+    }
+
+    if state.options.editorconfig || !rule.editorconfig.is_empty() {
+        let extension = file_path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let contents = editorconfig::contents(
+            extension,
+            &init_message.syntax,
+            &rule.editorconfig_properties(),
+        );
+        let path = file_path.with_file_name(editorconfig::FILE_NAME);
+        if let Err(e) = fs::write(&path, contents).await {
+            session.warn(format!("Unable to write {path:?}: {e}"));
+        }
+    }
+
+    if let Some(command) = &state.options.on_start {
+        if let Err(e) = hooks::run(command, env).await {
+            session.warn(format!("Start hook failed: {e:#}"));
+        }
+    }
+
+    #[cfg(feature = "preview")]
+    let _preview = state.options.preview.then(|| {
+        let preview = state.previews.register(file_path.clone());
+        info!("Previewing {:?} at {}", init_message.title, preview.url());
+        preview
+    });
+
+    let outgoing = Outgoing {
+        send_timeout,
+        resume_token,
+        strip_marker: rule.template_marker.as_deref().filter(|_| templated),
+        strip_invisible: state.options.strip_invisible,
+        formatter: file_path.extension().and_then(|extension| {
+            state
+                .options
+                .formatters
+                .iter()
+                .find(|formatter| extension == formatter.extension.as_str())
+        }),
+        filter_out: filter_out.as_deref(),
+        env,
+    };
+
+    if recovered.is_some() && !rule.read_only {
+        // the browser only has the text from before the restart
+        let sent = send_current_file_contents(tx, &outgoing, &mut file, &mut cursors).await?;
+        state.stats.add_sent(domain, sent);
+    }
+
     // moar futures:
     // - pass off to editor, wait for exit
     // - add async file watcher to check for writes
@@ -219,34 +1250,103 @@ async fn handle_websocket(state: State, stream: WebSocket) -> anyhow::Result<()>
 
     const EDIT_DELAY_MS: u64 = 200;
 
-    let rx = {
-        let msg_delay = Duration::from_millis(state.options.delay);
+    let msg_delay = session.delay(state.options.delay.map(Duration::from_millis));
+    // set by any message from the browser, cleared when pinging it
+    let answered = Arc::new(AtomicBool::new(true));
+    let mut rx = browser_messages(rx, msg_delay.clone(), answered.clone());
 
-        // async closures not stable
-        async fn ws_error(m: Result<Message, warp::Error>) -> Option<Message> {
-            m.map(|m| {
-                trace!("Received websocket msg: {:?}", m);
-                m
-            })
-            .map_err(|e| error!("Websocket error: {}", e))
-            .ok()
+    // notified to ask the editor to close if the session ends first
+    let close = Notify::new();
+    let editor = match recovered {
+        Some(record) => wait_for_adopted(state, session.id(), record).left_future(),
+        None => lock_and_spawn(state, &rule, &file_path, init_message, session.id(), &close)
+            .right_future(),
+    }
+    .fuse();
+    let watched = if rule.read_only {
+        // nothing is sent back
+        Ok(None)
+    } else if rule.no_watch.unwrap_or(state.options.no_watch) {
+        debug!("Not watching {file_path:?}, the text is sent when the editor exits");
+        Ok(None)
+    } else {
+        watch_edits(&file_path, &state.options).map(Some)
+    };
+    let edits = match watched {
+        Ok(Some(edits)) => edits.left_stream(),
+        Ok(None) => futures::stream::pending().right_stream(),
+        Err(e) => {
+            // the final send when the editor exits still works
+            session.warn(format!(
+                "Unable to watch file, changes will be sent when the editor exits: {e:#}"
+            ));
+            futures::stream::pending().right_stream()
         }
-
-        rx.filter_map(ws_error)
-            .debounce(msg_delay)
-            .inspect(|m| debug!("Debounced websocket msg: {m:?}"))
-            .fuse()
     };
-
-    let editor = lock_and_spawn(&state, &file_path, &init_message).fuse();
-    let edits = watch_edits(&file_path)
-        .context("watch_edits")?
+    let edits = edits
+        .scan((), |(), edit| {
+            future::ready(match edit {
+                // when the file changed, for timings
+                Ok(()) => Some(Instant::now()),
+                Err(e) => {
+                    session.warn(format!(
+                        "File watching failed, changes will be sent when the editor exits: {e:#}"
+                    ));
+                    None
+                }
+            })
+        })
         .debounce(Duration::from_millis(EDIT_DELAY_MS))
         .inspect(|e| debug!("Debounced notify event: {e:?}"))
         .fuse();
-    pin_mut!(rx, editor, edits);
+    let killed = session.killed().fuse();
+    let mut pings = if ping_interval.is_zero() {
+        futures::stream::pending().right_stream()
+    } else {
+        let start = Instant::now() + ping_interval;
+        IntervalStream::new(tokio::time::interval_at(start, ping_interval)).left_stream()
+    }
+    .fuse();
+    // set while the browser is disconnected
+    let expired = futures::future::Fuse::<tokio::time::Sleep>::terminated();
+    // set while connected with --finalize-after
+    let inactive = futures::future::Fuse::<tokio::time::Sleep>::terminated();
+    pin_mut!(editor, edits, killed, expired, inactive);
 
+    // last file change or browser message, for --finalize-after
+    let mut last_seen = Instant::now();
+    // set when sending fails or the websocket closes
+    let mut disconnected: Option<anyhow::Error> = None;
+    // set once the page detaches from the field, after which nothing is synced
+    let mut detached = false;
+    // cleared if writing the page's text fails, after which only the final text is sent
+    let mut syncing = true;
+    // set if the browser didn't resume the session in time
+    let mut abandoned = false;
     loop {
+        if let Some(e) = disconnected.take() {
+            if resume_timeout.is_zero() {
+                return Err(e);
+            }
+            warn!(
+                "Browser disconnected from session {}, keeping the editor open for {resume_timeout:?}: {e:#}",
+                session.id()
+            );
+            rx = futures::stream::pending().boxed().fuse();
+            expired.set(tokio::time::sleep(resume_timeout).fuse());
+            if let Some(token) = resume_token {
+                session.detach(token);
+            }
+        }
+
+        // pings don't count, they're answered by the browser even if the page is idle
+        match finalize_after {
+            Some(after) if expired.is_terminated() => {
+                inactive.set(tokio::time::sleep_until(last_seen + after).fuse());
+            }
+            _ => inactive.set(futures::future::Fuse::terminated()),
+        }
+
         futures::select! {
             e = editor => {
                 if let Err(e) = e {
@@ -255,55 +1355,444 @@ async fn handle_websocket(state: State, stream: WebSocket) -> anyhow::Result<()>
                 debug!("Editor closed!");
                 break;
             },
-            _edit = edits.select_next_some() => {
+            () = killed => {
+                warn!("Session {} killed, closing editor", session.id());
+                break;
+            },
+            () = inactive => {
+                info!("Session {} inactive, finishing", session.id());
+                break;
+            },
+            () = expired => {
+                abandoned = true;
+                break;
+            },
+            (mut new_tx, new_rx) = resumes.select_next_some() => {
+                if detached {
+                    send_close(&mut new_tx, send_timeout, CLOSE_NORMAL, "Page detached from the field").await;
+                    continue;
+                }
+                if !expired.is_terminated() {
+                    info!("Browser resumed session {}", session.id());
+                } else {
+                    // the old connection is likely stale, e.g. after sleeping
+                    info!("Moving session {} to a new websocket", session.id());
+                    send_close(tx, send_timeout, CLOSE_NORMAL, "Resumed from another connection").await;
+                }
+                *tx = new_tx;
+                last_seen = Instant::now();
+                answered.store(true, Ordering::Relaxed);
+                rx = browser_messages(new_rx, msg_delay.clone(), answered.clone());
+                expired.set(futures::future::Fuse::terminated());
+                session.attach();
+
+                if rule.read_only {
+                    continue;
+                }
+                // the browser may have missed updates
+                match send_current_file_contents(tx, &outgoing, &mut file, &mut cursors).await {
+                    Ok(sent) => state.stats.add_sent(domain, sent),
+                    Err(e) => disconnected = Some(e),
+                }
+            },
+            _ = pings.select_next_some() => {
+                if detached || !expired.is_terminated() {
+                    continue;
+                }
+                // a dropped connection isn't closed until sending to it fails,
+                // which may take long after e.g. a network change
+                if !answered.swap(false, Ordering::Relaxed) {
+                    disconnected = Some(anyhow::anyhow!("Browser didn't answer a ping within {ping_interval:?}"));
+                    continue;
+                }
+                if let Err(e) = send_with_timeout(tx, send_timeout, Message::ping(Vec::new())).await {
+                    disconnected = Some(e);
+                }
+            },
+            modified = edits.select_next_some() => {
                 debug!("File modified");
-                send_current_file_contents(&mut tx, &mut file, &cursors).await?;
+                last_seen = Instant::now();
+                if detached || !syncing {
+                    continue;
+                }
+                if !expired.is_terminated() {
+                    // sent when the browser resumes
+                    continue;
+                }
+                if !fs::try_exists(&file_path).await.unwrap_or(true) {
+                    debug!("File missing, ignoring change");
+                    continue;
+                }
+                // our own write, or an edit that was undone
+                if let Ok(true) = file.is_synced().await {
+                    debug!("File matches the browser's text, ignoring change");
+                    continue;
+                }
+                match send_current_file_contents(tx, &outgoing, &mut file, &mut cursors).await {
+                    Ok(sent) => {
+                        state.stats.add_sent(domain, sent);
+                        let elapsed = modified.elapsed();
+                        debug!(target: TIMINGS, "Sent file change {elapsed:?} after it was saved ({EDIT_DELAY_MS}ms debounce)");
+                        state.stats.add_file_to_browser(domain, elapsed);
+                    }
+                    Err(e) => disconnected = Some(e),
+                }
             },
-            msg = rx.select_next_some() => {
-                if !msg.is_text() {
-                    error!("Received non-update msg: {:?}", msg);
+            msg = rx.next() => {
+                let Some((received, msg)) = msg else {
+                    disconnected = Some(anyhow::anyhow!("Websocket closed"));
+                    continue;
+                };
+                // even an update with the same text, which some pages re-send
+                // periodically, shows the page is still alive
+                last_seen = received;
+                let update_msg = match msg.to_str() {
+                    _ if is_detaching_close(&msg) => None,
+                    Ok(text) => match serde_json::from_str::<msg::UpdateTextFromComponent>(text) {
+                        Ok(update_msg) => Some(update_msg),
+                        Err(e) => {
+                            session.warn(format!("Ignoring invalid update from browser: {e}"));
+                            continue;
+                        }
+                    },
+                    Err(()) => {
+                        error!("Received non-update msg: {:?}", msg);
+                        continue;
+                    }
+                };
+                detached = update_msg.as_ref().is_none_or(|update_msg| {
+                    update_msg.url.as_ref().is_some_and(|url| *url != init_message.url)
+                });
+                let Some(update_msg) = update_msg.filter(|_| !detached) else {
+                    if !state.options.keep_detached {
+                        info!("Page detached from session {}, closing editor", session.id());
+                        break;
+                    }
+                    session.warn(String::from(
+                        "Page detached from the field, keeping the editor open without syncing",
+                    ));
+                    rx = futures::stream::pending().boxed().fuse();
+                    continue;
+                };
+                check_text_size(&update_msg.text, state.options.max_text_size)?;
+                if !syncing {
                     continue;
                 }
-                let update_msg: msg::GetTextFromComponent = serde_json::from_str(
-                    msg.to_str().expect("Is a text msg")).context("Could not parse websocket message")?;
                 debug!("Handling update msg");
-                cursors = update_msg.selections.to_owned();
-                let did_write = file.maybe_update(&update_msg).await?;
-
-                #[cfg(feature = "watch_changes")]
-                if did_write {
-                    debug!("Ignoring next edit notification");
-                    match timeout(Duration::from_millis(EDIT_DELAY_MS / 2 * 3), edits.select_next_some()).await {
-                        Ok(_) => debug!("Got next edit notification"),
-                        Err(_) => warn!("Timed out waiting for next edit notification"),
+                let filtered = filtered_in(filter_in, env, &update_msg.text).await;
+                match file.maybe_update(&filtered).await {
+                    Ok(true) => {
+                        state.stats.add_received(domain, update_msg.text.len());
+                        let elapsed = received.elapsed();
+                        debug!(target: TIMINGS, "Wrote browser update {elapsed:?} after receiving it ({:?} debounce)", msg_delay.wait());
+                        state.stats.add_browser_to_file(domain, elapsed);
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        session.warn(format!(
+                            "Unable to write the page's text, changes will be sent when the editor exits: {e:#}"
+                        ));
+                        syncing = false;
+                        continue;
                     }
                 }
+                file.mark_synced();
+                cursors = Cursors {
+                    text: update_msg.text.into_owned(),
+                    selections: update_msg.selections,
+                };
             },
         }
     }
 
+    if !editor.is_terminated() && !close_grace.is_zero() {
+        close.notify_one();
+        // each of the editor's close steps takes at most the grace period
+        match timeout(close_grace * 2, &mut editor).await {
+            Ok(Ok(())) => debug!("Editor closed"),
+            Ok(Err(e)) => warn!("{e:#}"),
+            Err(_) => warn!("Editor didn't close within {close_grace:?}, killing it"),
+        }
+    }
+
+    // before the file is sent back, so it can change it
+    if let Some(command) = &state.options.on_end {
+        if let Err(e) = hooks::run(command, env).await {
+            session.warn(format!("End hook failed: {e:#}"));
+        }
+    }
+
+    if abandoned {
+        bail!("Browser didn't resume the session within {resume_timeout:?}");
+    }
+    if !expired.is_terminated() {
+        bail!("Editor exited while the browser was disconnected");
+    }
+
+    if detached {
+        debug!("Page detached, not sending the file back");
+        send_close(
+            tx,
+            send_timeout,
+            CLOSE_NORMAL,
+            "Page detached from the field",
+        )
+        .await;
+        return Ok(Ending::Detached);
+    }
+
     // return updated file text
-    send_current_file_contents(&mut tx, &mut file, &cursors).await?;
+    if rule.read_only {
+        debug!("Read-only session, not sending the file back");
+    } else if fs::try_exists(&file_path).await.unwrap_or(true) {
+        let text = outgoing_text(&outgoing, &mut file).await?;
+        #[cfg(feature = "clipboard")]
+        if let Some(clipboard) = &state.clipboard {
+            clipboard.copy(&text);
+        }
+        let sent = send_text(tx, &outgoing, &mut file, &mut cursors, &text).await?;
+        state.stats.add_sent(domain, sent);
+    } else {
+        // changes were already sent when saved
+        debug!("File deleted, skipping final update");
+    }
+    if let Some(draft) = &mut draft {
+        draft.delivered();
+    }
 
     // close gracefully
-    tx.close().await.context("closing websocket tx handle")?;
+    timeout(send_timeout, tx.close())
+        .await
+        .context("Timed out closing websocket")?
+        .context("closing websocket tx handle")?;
+
+    Ok(Ending::Finished)
+}
 
+/// Whether the browser closed the websocket without a status code
+///
+/// The extension does so when it's stopped for the field, unlike browsers
+/// closing a tab or going offline.
+fn is_detaching_close(msg: &Message) -> bool {
+    msg.is_close() && msg.close_frame().is_none()
+}
+
+/// Debounced messages from the browser, with when they were received
+///
+/// Sets `answered` for every message, and leaves out pings and pongs so they
+/// can't replace an update.
+fn browser_messages(
+    rx: impl Stream<Item = Result<Message, warp::Error>> + Send + 'static,
+    delay: Delay,
+    answered: Arc<AtomicBool>,
+) -> Fuse<BoxStream<'static, (Instant, Message)>> {
+    // async closures not stable
+    async fn ws_error(m: Result<Message, warp::Error>) -> Option<Message> {
+        m.map(|m| {
+            trace!("Received websocket msg: {:?}", m);
+            m
+        })
+        .map_err(|e| error!("Websocket error: {}", e))
+        .ok()
+    }
+
+    rx.filter_map(ws_error)
+        .inspect(move |_| answered.store(true, Ordering::Relaxed))
+        .filter(|m| future::ready(!m.is_ping() && !m.is_pong()))
+        .map(|m| (Instant::now(), m))
+        .inspect({
+            let delay = delay.clone();
+            move |(_, m)| delay.observe(m.as_bytes().len())
+        })
+        .debounce(delay)
+        .inspect(|(_, m)| debug!("Debounced websocket msg: {m:?}"))
+        .boxed()
+        .fuse()
+}
+
+/// Take the updates the browser already sent, e.g. while the session's file was created
+///
+/// Returns the text and selections of the latest, and the other messages,
+/// starting with the first one that isn't an update of the same page.
+fn take_early_updates<S>(mut rx: S, url: &str) -> (Option<Cursors>, impl Stream<Item = S::Item>)
+where
+    S: Stream<Item = Result<Message, warp::Error>> + Unpin,
+{
+    let parse = |message: &Message| {
+        let text = message.to_str().ok()?;
+        let update: msg::UpdateTextFromComponent = serde_json::from_str(text).ok()?;
+        if update.url.is_some_and(|update_url| update_url != url) {
+            return None;
+        }
+        Some(Cursors {
+            text: update.text.into_owned(),
+            selections: update.selections,
+        })
+    };
+
+    let mut latest = None;
+    let mut next = None;
+    while let Some(Some(message)) = rx.next().now_or_never() {
+        match message.as_ref().ok().and_then(parse) {
+            Some(update) => latest = Some(update),
+            None => {
+                next = Some(message);
+                break;
+            }
+        }
+    }
+    (latest, futures::stream::iter(next).chain(rx))
+}
+
+/// Run text from the browser through `--filter-in`, keeping it as is if that fails
+async fn filtered_in<'t>(
+    filter: Option<&[String]>,
+    env: SessionEnv<'_>,
+    text: &'t str,
+) -> Cow<'t, str> {
+    let Some(filter) = filter else {
+        return Cow::Borrowed(text);
+    };
+    match format::pipe("Filter", filter, text, Some(env)).await {
+        Ok(filtered) => Cow::Owned(filtered),
+        Err(e) => {
+            warn!("{e:#}, writing unfiltered text");
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+/// Start an empty field from a template
+async fn apply_template(template: &Path, file: &mut LocalFile, max: usize) -> anyhow::Result<()> {
+    let text = fs::read_to_string(template)
+        .await
+        .with_context(|| format!("Unable to read template {template:?}"))?;
+    // written back with a trailing newline
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    check_text_size(text, max)?;
+    debug!("Starting from template {template:?}");
+    file.maybe_update(text).await?;
+    Ok(())
+}
+
+fn check_text_size(text: &str, max: usize) -> Result<(), TooLarge> {
+    if text.len() > max {
+        return Err(TooLarge { max: max as u64 });
+    }
     Ok(())
 }
 
-/// Acquire a global lock if configured and start the editor process
+/// Pick the websocket close code for an error ending the session
+fn close_code(e: &anyhow::Error) -> u16 {
+    if TooLarge::is_cause_of(e) {
+        CLOSE_TOO_BIG
+    } else {
+        CLOSE_INTERNAL_ERROR
+    }
+}
+
+/// Acquire the lock of the session's group, or the global one if configured,
+/// and start the editor process
 async fn lock_and_spawn(
     state: &State,
+    rule: &Rule,
     file_path: impl AsRef<Path>,
     msg: &msg::GetTextFromComponent,
+    id: SessionId,
+    close: &Notify,
 ) -> anyhow::Result<()> {
-    let lock = if !state.options.multi {
-        Some(state.single_access.acquire().await?)
-    } else {
-        None
+    // opened in the running editor instead of waiting for it
+    let remote = state
+        .options
+        .editor_remote
+        .as_ref()
+        .filter(|_| rule.group.is_none() && !state.options.multi)
+        .filter(|_| {
+            let editor = editor::command_for(&state.options, rule, msg.domain().as_deref());
+            editor == state.options.editor.as_deref()
+        })
+        .filter(|_| state.single_access.try_acquire().is_none())
+        .map(|remote| Rule {
+            editor: Some(remote.clone()),
+            ..rule.clone()
+        });
+    let rule = match &remote {
+        Some(remote) => {
+            info!(
+                "Editor is open, opening {:?} with --editor-remote",
+                msg.title
+            );
+            remote
+        }
+        None => rule,
     };
 
-    editor::spawn_editor(&state.options, file_path.as_ref(), msg).await?;
+    let lock = async {
+        let lock = match &rule.group {
+            Some(group) => match state.groups.queue(group) {
+                Some(queue) => Some(queue.acquire(&msg.title).await?),
+                None => None,
+            },
+            None if remote.is_some() || state.options.multi => None,
+            None => Some(state.single_access.acquire(&msg.title).await?),
+        };
+        anyhow::Ok(lock)
+    };
+    let lock = tokio::select! {
+        lock = lock => lock?,
+        () = close.notified() => {
+            debug!("Session ended while waiting for the editor");
+            return Ok(());
+        }
+    };
+
+    // the running editor can't be asked to compare files
+    let diff = match &state.drafts {
+        Some(drafts) if state.options.diff_draft && !rule.read_only && remote.is_none() => {
+            drafts.latest(&msg.url, &msg.title).unwrap_or_else(|e| {
+                warn!("Unable to look for drafts of {:?}: {e}", msg.title);
+                None
+            })
+        }
+        _ => None,
+    };
+    if let Some(draft) = &diff {
+        info!("Comparing {:?} with its draft {draft:?}", msg.title);
+    }
+
+    let on_spawn = |pid| {
+        state.sessions.set_editor_pid(id, pid);
+        let record = Record {
+            path: file_path.as_ref().to_owned(),
+            url: msg.url.clone(),
+            title: msg.title.clone(),
+            pid,
+        };
+        state.handoff.track(id, record)
+    };
+    let exit = editor::spawn_editor(
+        &state.options,
+        rule,
+        file_path.as_ref(),
+        diff.as_deref(),
+        msg,
+        on_spawn,
+        close,
+    )
+    .await?;
+    if exit == editor::Exit::Forked && state.options.wait_for_delete {
+        tokio::select! {
+            () = file::wait_for_delete(file_path.as_ref()) => {}
+            () = close.notified() => debug!("Session ended before the file was deleted"),
+        }
+    }
+    #[cfg(windows)]
+    if exit == editor::Exit::Forked && state.options.wait_for_unlock {
+        tokio::select! {
+            () = file::wait_for_unlock(file_path.as_ref()) => {}
+            () = close.notified() => debug!("Session ended before the file was closed"),
+        }
+    }
 
     // the editor has either failed or finished, so allow another process to spawn
     drop(lock);
@@ -311,60 +1800,373 @@ async fn lock_and_spawn(
     Ok(())
 }
 
+/// Wait for an editor started by a previous server to exit
+async fn wait_for_adopted(state: &State, id: SessionId, record: Record) -> anyhow::Result<()> {
+    let pid = record.pid;
+    let _tracked = state.handoff.track(id, record);
+    handoff::wait_for_exit(pid).await;
+    Ok(())
+}
+
+/// Per-session settings for sending the file to the browser
+struct Outgoing<'a> {
+    send_timeout: Duration,
+    resume_token: Option<&'a str>,
+    /// Set for text started from a template
+    strip_marker: Option<&'a str>,
+    strip_invisible: bool,
+    formatter: Option<&'a Formatter>,
+    /// `--filter-out`
+    filter_out: Option<&'a [String]>,
+    /// For the formatter and filter
+    env: SessionEnv<'a>,
+}
+
+impl Outgoing<'_> {
+    /// The text to send for the file contents
+    fn prepare<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let text = match self.strip_marker {
+            Some(marker) => text::strip_marked_lines(text, marker),
+            None => Cow::Borrowed(text),
+        };
+        if !self.strip_invisible {
+            return text;
+        }
+        let (text, removed) = text::strip_invisible(text);
+        if removed > 0 {
+            info!("Removed {removed} invisible characters");
+        }
+        text
+    }
+}
+
+/// The browser's selections, and the text they're in
+struct Cursors {
+    text: String,
+    selections: Vec<msg::RangeInText>,
+}
+
+impl Cursors {
+    /// Move the selections to the same places in `text`, which replaces the browser's
+    fn update(&mut self, text: &str) -> &[msg::RangeInText] {
+        if self.text != text {
+            let change = text::TextChange::new(&self.text, text);
+            for selection in &mut self.selections {
+                selection.start = change.remap(selection.start);
+                selection.end = change.remap(selection.end);
+            }
+            text.clone_into(&mut self.text);
+        }
+        &self.selections
+    }
+}
+
+/// Returns the number of bytes of text sent
 async fn send_current_file_contents(
     stream: &mut WebSocketTx,
+    outgoing: &Outgoing<'_>,
     file: &mut file::LocalFile,
-    cursors: &[msg::RangeInText],
-) -> anyhow::Result<()> {
+    cursors: &mut Cursors,
+) -> anyhow::Result<usize> {
+    let text = outgoing_text(outgoing, file).await?;
+    send_text(stream, outgoing, file, cursors, &text).await
+}
+
+/// The file's contents as the browser gets them, after `--formatter` and `--filter-out`
+async fn outgoing_text(
+    outgoing: &Outgoing<'_>,
+    file: &mut file::LocalFile,
+) -> anyhow::Result<String> {
     let text = file.get_current_contents().await?;
+    let mut text = outgoing.prepare(text);
+    if let Some(formatter) = outgoing.formatter {
+        match formatter.format(&text, Some(outgoing.env)).await {
+            Ok(formatted) => text = Cow::Owned(formatted),
+            Err(e) => warn!("{e:#}, sending unformatted text"),
+        }
+    }
+    if let Some(filter) = outgoing.filter_out {
+        match format::pipe("Filter", filter, &text, Some(outgoing.env)).await {
+            Ok(filtered) => text = Cow::Owned(filtered),
+            Err(e) => warn!("{e:#}, sending unfiltered text"),
+        }
+    }
+    Ok(text.into_owned())
+}
 
-    debug!("Sending update msg");
-    stream
-        .send(Message::text(serde_json::to_string(
-            &msg::SetTextInComponent {
-                text: &text,
-                selections: cursors.to_owned(),
-            },
-        )?))
-        .await?;
+/// Send `text` from [`outgoing_text`] with the browser's moved selections
+///
+/// Returns the number of bytes of text sent.
+async fn send_text(
+    stream: &mut WebSocketTx,
+    outgoing: &Outgoing<'_>,
+    file: &mut file::LocalFile,
+    cursors: &mut Cursors,
+    text: &str,
+) -> anyhow::Result<usize> {
+    // rough size of the json around the text, to avoid reallocating for large texts
+    const JSON_OVERHEAD: usize = 32;
 
-    Ok(())
-}
+    let cursors = cursors.update(text);
 
-enum ThreadStatus {
-    Started,
-    Finished,
-}
+    let mut json = Vec::with_capacity(text.len() + JSON_OVERHEAD * (cursors.len() + 1));
+    serde_json::to_writer(
+        &mut json,
+        &msg::SetTextInComponent {
+            text,
+            selections: cursors,
+            resume_token: outgoing.resume_token,
+        },
+    )?;
+    let json = String::from_utf8(json).expect("serde_json writes valid UTF-8");
 
-async fn idle_timeout(
-    duration: time::Duration,
-    status_updater: mpsc::UnboundedReceiver<ThreadStatus>,
-) {
-    let mut alive_count: usize = 0;
-    let mut updater = UnboundedReceiverStream::new(status_updater);
+    debug!("Sending update msg");
+    let sent = text.len();
+    send_with_timeout(stream, outgoing.send_timeout, Message::text(json)).await?;
+    file.mark_synced();
 
-    loop {
-        let update = if alive_count == 0 {
-            time::timeout(duration, updater.next()).await
-        } else {
-            Ok(updater.next().await)
-        };
+    Ok(sent)
+}
 
-        match update {
-            Err(_) /* time::error::Elapsed, compiler doesn't like writing it inside the match arm */ => {
-                info!("Stopping after idle timeout of {} secs", duration.as_secs());
-                break;
+/// Resolves on Ctrl-C, or SIGTERM on unix
+async fn terminated() {
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
             }
-            Ok(None) => {
-                error!("All thread status sending handles dropped; stopping");
-                break;
+            Err(e) => {
+                warn!("Unable to listen for SIGTERM: {e}");
+                future::pending().await
             }
-            Ok(Some(ThreadStatus::Started)) => {
-                alive_count += 1;
+        }
+    };
+    #[cfg(not(unix))]
+    let term = future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                warn!("Unable to listen for Ctrl-C: {e}");
+                future::pending().await
             }
-            Ok(Some(ThreadStatus::Finished)) => {
-                alive_count -= 1;
+        }
+        () = term => {}
+    }
+}
+
+/// Resolves when the server should stop, either on request or after an optional idle timeout
+async fn shutdown_signal(
+    requested: Arc<Notify>,
+    idle: Option<Duration>,
+    activity: Activity,
+    stop: impl Future<Output = ()>,
+) {
+    let idle = async {
+        match idle {
+            Some(duration) => {
+                debug!("Idle timeout after {} secs", duration.as_secs());
+                activity.idle_timeout(duration).await
             }
+            None => future::pending().await,
         }
+    };
+    // pinged on a timer, so systemd restarts the server if the runtime hangs,
+    // but not if only the accept loop or a session is stuck
+    let watchdog = async {
+        #[cfg(all(feature = "systemd", target_os = "linux"))]
+        if let Some(interval) = super::systemd::watchdog_interval() {
+            debug!(
+                "Notifying systemd watchdog every {} ms",
+                interval.as_millis()
+            );
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                super::systemd::notify("WATCHDOG=1");
+            }
+        }
+        future::pending::<()>().await
+    };
+    tokio::select! {
+        _ = idle => {}
+        _ = watchdog => {}
+        _ = requested.notified() => info!("Stopping on request"),
+        _ = stop => info!("Stopping on request"),
+    }
+    #[cfg(all(feature = "systemd", target_os = "linux"))]
+    super::systemd::notify("STOPPING=1");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, sink};
+    use test_case::test_case;
+
+    #[test_case("127.0.0.1" => "127.0.0.1:4001" ; "ipv4")]
+    #[test_case("::1" => "[::1]:4001" ; "ipv6")]
+    #[test_case("[::1]" => "[::1]:4001" ; "bracketed ipv6")]
+    #[test_case("[::]" => "[::]:4001" ; "unspecified ipv6")]
+    fn resolves_ip_literals(host: &str) -> String {
+        resolve_host(host, 4001).unwrap().to_string()
+    }
+
+    #[test_case("[::1" ; "unclosed bracket")]
+    #[test_case("[127.0.0.1]" ; "bracketed ipv4")]
+    #[test_case("[localhost]" ; "bracketed hostname")]
+    #[test_case("localhost:4001" ; "port in host")]
+    #[test_case("[::1]:4001" ; "port after brackets")]
+    fn rejects_invalid_hosts(host: &str) {
+        assert!(resolve_host(host, 4001).is_err());
+    }
+
+    #[test_case(None, false, None => false ; "no token")]
+    #[test_case(None, false, Some("Bearer ") => false ; "no token with empty bearer")]
+    #[test_case(None, true, None => true ; "socket")]
+    #[test_case(Some(""), false, Some("Bearer ") => false ; "empty token")]
+    #[test_case(Some("secret"), false, None => false ; "missing")]
+    #[test_case(Some("secret"), false, Some("Bearer other") => false ; "wrong")]
+    #[test_case(Some("secret"), false, Some("secret") => false ; "no scheme")]
+    #[test_case(Some("secret"), false, Some("Bearer secret") => true ; "matching")]
+    fn checks_ctl_token(
+        token: Option<&str>,
+        over_socket: bool,
+        authorization: Option<&str>,
+    ) -> bool {
+        is_allowed_ctl(token, over_socket, authorization)
+    }
+
+    #[test_case("localhost:4001" => true ; "localhost")]
+    #[test_case("127.0.0.1:4001" => true ; "ipv4")]
+    #[test_case("[::1]:4001" => true ; "ipv6")]
+    #[test_case("127.0.0.1" => true ; "without port")]
+    #[test_case("evil.example.com:4001" => false ; "rebound domain")]
+    #[test_case("localhost.example.com" => false ; "localhost prefix")]
+    #[test_case("192.168.1.2:4001" => false ; "lan address")]
+    #[test_case("" => false ; "empty")]
+    fn checks_loopback_hosts(host: &str) -> bool {
+        is_loopback_host(host)
+    }
+
+    #[test]
+    fn resolves_hostnames() {
+        let addr = resolve_host("localhost", 4001).unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(4001, addr.port());
+    }
+
+    #[tokio::test]
+    async fn stops_when_asked() {
+        let options: Settings =
+            clap::Parser::parse_from(["gtany", "--port", "0", "--editor", "true"]);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = run_with_shutdown(options, async {
+            let _ = stopped.await;
+        });
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            stop.send(()).unwrap();
+        };
+
+        let (result, ()) = timeout(Duration::from_secs(5), future::join(server, stop))
+            .await
+            .expect("stopped");
+        result.unwrap();
+    }
+
+    #[test]
+    fn moves_cursors_with_edits() {
+        let mut cursors = Cursors {
+            text: String::from("Dear Bob, thanks!"),
+            selections: vec![msg::RangeInText { start: 9, end: 16 }],
+        };
+        let moved = cursors.update("Dear Alice, thanks!");
+        assert_eq!((11, 18), (moved[0].start, moved[0].end));
+        assert_eq!("Dear Alice, thanks!", cursors.text);
+    }
+
+    #[tokio::test]
+    async fn takes_early_updates() {
+        let update = |text: &str, url: &str| {
+            let json = serde_json::json!({ "text": text, "selections": [], "url": url });
+            Ok(Message::text(json.to_string()))
+        };
+        let messages = futures::stream::iter(vec![
+            update("one", "example.com"),
+            update("two", "example.com"),
+            update("elsewhere", "example.org"),
+            update("three", "example.com"),
+        ]);
+
+        let (latest, rest) = take_early_updates(messages, "example.com");
+        assert_eq!("two", latest.unwrap().text);
+        let rest: Vec<_> = rest
+            .map(|m| m.unwrap().to_str().unwrap().to_owned())
+            .collect()
+            .await;
+        assert_eq!(2, rest.len());
+        assert!(rest[0].contains("elsewhere"));
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn prepares_text_like_sent() {
+        let message = msg::GetTextFromComponent {
+            selections: vec![],
+            syntax: String::new(),
+            text: String::from("hello"),
+            title: String::from("title"),
+            url: String::from("example.com"),
+            resume_token: None,
+        };
+        let pool = DirPool::default();
+        let mut file = LocalFile::create(
+            &message,
+            None,
+            None,
+            usize::MAX,
+            crate::settings::Newline::Preserve,
+            &pool,
+        )
+        .await
+        .unwrap();
+        let filter =
+            split_command(r#"sh -c 'printf "%s " "$GHOST_TEXT_URL"; tr a-z A-Z'"#).unwrap();
+        let path = file.as_ref().to_owned();
+        let outgoing = Outgoing {
+            send_timeout: Duration::from_secs(1),
+            resume_token: None,
+            strip_marker: None,
+            strip_invisible: false,
+            formatter: None,
+            filter_out: Some(&filter),
+            env: SessionEnv {
+                msg: &message,
+                rule: &rules::Rule::default(),
+                file: &path,
+            },
+        };
+
+        assert_eq!(
+            "example.com HELLO",
+            outgoing_text(&outgoing, &mut file).await.unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_within_timeout() {
+        let mut tx = sink::drain();
+        let sent = send_with_timeout(&mut tx, Duration::from_secs(1), Message::text("hi")).await;
+        assert!(sent.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_send_times_out() {
+        let mut tx = sink::unfold((), |(), _: Message| future::pending::<io::Result<()>>());
+        let sent = send_with_timeout(&mut tx, Duration::from_secs(1), Message::text("hi")).await;
+        assert!(sent.is_err());
     }
 }
@@ -0,0 +1,48 @@
+//! Copy text sent back to the browser to the clipboard as a backup
+//!
+//! Pages sometimes lose the update, e.g. when they re-render the field. A
+//! thread owns the clipboard, since on X11 and Wayland its contents are served
+//! by the process that set them.
+
+use std::{io, sync::mpsc, thread};
+
+#[derive(Debug, Clone)]
+pub struct ClipboardBackup(mpsc::Sender<String>);
+
+impl ClipboardBackup {
+    pub fn spawn() -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel::<String>();
+        thread::Builder::new()
+            .name(String::from("clipboard"))
+            .spawn(move || {
+                // opened on first use, so a missing display only matters then
+                let mut clipboard = None;
+                for text in rx {
+                    if clipboard.is_none() {
+                        match arboard::Clipboard::new() {
+                            Ok(opened) => clipboard = Some(opened),
+                            Err(e) => {
+                                warn!("Unable to open clipboard: {e}");
+                                continue;
+                            }
+                        }
+                    }
+                    let Some(clipboard) = &mut clipboard else {
+                        continue;
+                    };
+                    match clipboard.set_text(text) {
+                        Ok(()) => debug!("Copied text to clipboard"),
+                        Err(e) => warn!("Unable to copy text to clipboard: {e}"),
+                    }
+                }
+            })?;
+
+        Ok(Self(tx))
+    }
+
+    /// Copy in the background
+    pub fn copy(&self, text: &str) {
+        // the thread only stops if it panicked
+        let _ = self.0.send(text.to_owned());
+    }
+}
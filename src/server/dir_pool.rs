@@ -0,0 +1,256 @@
+//! Reusing session directories between sessions
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tempdir::TempDir;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Emptied session directories kept for new sessions instead of removed
+///
+/// Keeps at most `--dir-pool` idle directories and removes any beyond that,
+/// or every directory when it's 0. With `--max-dirs`, at most that many
+/// directories are in use at once.
+#[derive(Debug, Clone, Default)]
+pub struct DirPool {
+    pool: Option<Arc<Pool>>,
+    /// A permit for each directory in use
+    limit: Option<Arc<Semaphore>>,
+    handoff: Option<Arc<HandoffDirs>>,
+}
+
+/// Where directories go with `--state-file`
+#[derive(Debug)]
+struct HandoffDirs {
+    /// Next to the state file, instead of in the temp dir
+    root: PathBuf,
+    /// Set when the server stops with sessions left, to leave their files
+    handing_off: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+struct Pool {
+    idle: Mutex<Vec<PathBuf>>,
+    capacity: usize,
+}
+
+impl DirPool {
+    /// `max` directories in use at once, or any number if it's 0
+    pub fn new(capacity: usize, max: usize) -> Self {
+        Self {
+            pool: (capacity > 0).then(|| {
+                Arc::new(Pool {
+                    idle: Mutex::new(Vec::with_capacity(capacity)),
+                    capacity,
+                })
+            }),
+            limit: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            handoff: None,
+        }
+    }
+
+    /// Create directories in `root`, and leave them once `handing_off` is set
+    ///
+    /// For `--state-file`, so the next server finds the files of editors that
+    /// are still open, even if the temp dir is cleared on restart.
+    pub fn for_handoff(mut self, root: PathBuf, handing_off: Arc<AtomicBool>) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        self.handoff = Some(Arc::new(HandoffDirs { root, handing_off }));
+        Ok(self)
+    }
+
+    /// The directory session directories are created in
+    pub fn root(&self) -> PathBuf {
+        match &self.handoff {
+            Some(handoff) => handoff.root.clone(),
+            None => std::env::temp_dir(),
+        }
+    }
+
+    /// Permit to use one more directory, to hold until it's released
+    ///
+    /// Fails if `--max-dirs` directories are already in use.
+    pub fn permit(&self) -> io::Result<Option<OwnedSemaphorePermit>> {
+        let Some(limit) = &self.limit else {
+            return Ok(None);
+        };
+        match limit.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(io::Error::other(
+                "All session directories of --max-dirs are in use",
+            )),
+        }
+    }
+
+    /// An empty directory in the temp dir whose name starts with `prefix`
+    pub fn take(&self, prefix: &str) -> io::Result<PathBuf> {
+        let reused = self
+            .pool
+            .as_ref()
+            .and_then(|pool| pool.idle.lock().unwrap().pop());
+        match reused {
+            Some(dir) => {
+                debug!("Reusing session directory {dir:?}");
+                Ok(dir)
+            }
+            None => Ok(TempDir::new_in(self.root(), prefix)?.into_path()),
+        }
+    }
+
+    /// Keep `dir` for another session, or remove it
+    ///
+    /// Kept directories are emptied on a blocking thread when called from
+    /// the runtime, so the session isn't held up by it.
+    pub fn release(&self, dir: PathBuf) {
+        let handing_off = self.handoff.as_ref();
+        if handing_off.is_some_and(|handoff| handoff.handing_off.load(Ordering::SeqCst)) {
+            info!("Leaving {dir:?} for the next server");
+            return;
+        }
+        let Some(pool) = self.pool.clone() else {
+            remove(&dir);
+            return;
+        };
+        let recycle = move || pool.recycle(dir);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(recycle)),
+            Err(_) => recycle(),
+        }
+    }
+}
+
+impl Pool {
+    fn is_full(&self) -> bool {
+        self.idle.lock().unwrap().len() >= self.capacity
+    }
+
+    fn recycle(&self, dir: PathBuf) {
+        if self.is_full() {
+            remove(&dir);
+            return;
+        }
+        if let Err(e) = empty(&dir) {
+            warn!("Unable to empty {dir:?} for reuse: {e}");
+            remove(&dir);
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.capacity {
+            idle.push(dir);
+        } else {
+            // filled up while emptying
+            drop(idle);
+            remove(&dir);
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for dir in self.idle.get_mut().unwrap().drain(..) {
+            remove(&dir);
+        }
+    }
+}
+
+/// Remove everything inside `dir`
+fn empty(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        warn!("Unable to remove {dir:?}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREFIX: &str = "gtany-pool-test";
+
+    #[test]
+    fn reuses_emptied_directories() {
+        let pool = DirPool::new(1, 0);
+        let dir = pool.take(PREFIX).unwrap();
+        fs::create_dir(dir.join("scratch")).unwrap();
+        fs::write(dir.join("scratch").join("render.html"), "<p>hi</p>").unwrap();
+        fs::write(dir.join("title.txt"), "hello").unwrap();
+
+        pool.release(dir.clone());
+        let reused = pool.take(PREFIX).unwrap();
+        assert_eq!(dir, reused);
+        assert_eq!(0, fs::read_dir(&reused).unwrap().count());
+
+        pool.release(reused);
+        drop(pool);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn removes_directories_beyond_capacity() {
+        let pool = DirPool::new(1, 0);
+        let first = pool.take(PREFIX).unwrap();
+        let second = pool.take(PREFIX).unwrap();
+
+        pool.release(first.clone());
+        pool.release(second.clone());
+        assert!(first.exists());
+        assert!(!second.exists());
+    }
+
+    #[test]
+    fn removes_directories_without_pool() {
+        let pool = DirPool::default();
+        let dir = pool.take(PREFIX).unwrap();
+        pool.release(dir.clone());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn limits_directories_in_use() {
+        let pool = DirPool::new(0, 1);
+        let permit = pool.permit().unwrap();
+        assert!(permit.is_some());
+        assert!(pool.permit().is_err());
+
+        drop(permit);
+        assert!(pool.permit().unwrap().is_some());
+        assert!(DirPool::default().permit().unwrap().is_none());
+    }
+
+    #[test]
+    fn leaves_directories_when_handing_off() {
+        let root = TempDir::new(PREFIX).unwrap();
+        let handing_off = Arc::new(AtomicBool::new(false));
+        let pool = DirPool::default()
+            .for_handoff(root.path().join("sessions"), handing_off.clone())
+            .unwrap();
+
+        let finished = pool.take(PREFIX).unwrap();
+        assert_eq!(Some(pool.root().as_path()), finished.parent());
+        pool.release(finished.clone());
+        assert!(!finished.exists());
+
+        let left = pool.take(PREFIX).unwrap();
+        handing_off.store(true, Ordering::SeqCst);
+        pool.release(left.clone());
+        assert!(left.exists());
+    }
+}
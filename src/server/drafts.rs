@@ -0,0 +1,234 @@
+//! Saving texts that couldn't be sent back to the browser
+//!
+//! A session's file is removed with its directory when the session ends, so
+//! without a drafts directory, edits made after the browser went away are
+//! lost. Each draft is a copy of the file with a JSON file of the page's
+//! details next to it.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use super::{msg, session::SessionId};
+
+/// Directory to save undelivered texts in
+#[derive(Debug, Clone)]
+pub struct Drafts {
+    dir: PathBuf,
+}
+
+/// Details of the page a draft is from, saved next to it
+#[derive(Debug, Serialize)]
+struct Metadata<'a> {
+    url: &'a str,
+    title: &'a str,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+}
+
+/// A draft in the drafts directory, with the details of its page
+#[derive(Debug, Deserialize)]
+pub struct Saved {
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub url: String,
+    pub title: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl Drafts {
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("Unable to create drafts dir {dir:?}"))?;
+        Ok(Self { dir })
+    }
+
+    /// Drafts already saved in `dir`, without creating it
+    pub fn open(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Save `file` when the returned guard is dropped, unless it was delivered
+    pub fn guard(&self, file: &Path, msg: &msg::GetTextFromComponent, id: SessionId) -> Draft {
+        Draft {
+            drafts: self.clone(),
+            file: file.to_owned(),
+            url: msg.url.clone(),
+            title: msg.title.clone(),
+            id,
+            delivered: false,
+        }
+    }
+
+    /// Saved drafts, oldest first
+    ///
+    /// Skips drafts without readable details, like ones saved by hand.
+    pub fn list(&self) -> io::Result<Vec<Saved>> {
+        let mut saved = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let metadata_path = entry?.path();
+            if metadata_path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            let path = metadata_path.with_extension("");
+            let details = fs::read(&metadata_path)
+                .ok()
+                .and_then(|json| serde_json::from_slice(&json).ok());
+            match details {
+                Some(details) if path.is_file() => saved.push(Saved { path, ..details }),
+                _ => debug!("Skipping draft details {metadata_path:?}"),
+            }
+        }
+        saved.sort_by(|a, b| (a.timestamp, &a.path).cmp(&(b.timestamp, &b.path)));
+        Ok(saved)
+    }
+
+    /// The newest draft of the page with `url` and `title`
+    pub fn latest(&self, url: &str, title: &str) -> io::Result<Option<PathBuf>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .rev()
+            .find(|saved| saved.url == url && saved.title == title)
+            .map(|saved| saved.path))
+    }
+}
+
+/// Saves a session's file to the drafts directory when dropped
+///
+/// Dropped when the session ends for any reason, including the server
+/// stopping while it's active.
+#[derive(Debug)]
+pub struct Draft {
+    drafts: Drafts,
+    file: PathBuf,
+    url: String,
+    title: String,
+    id: SessionId,
+    delivered: bool,
+}
+
+impl Draft {
+    /// The browser has the text, or never will, so don't save it
+    pub fn delivered(&mut self) {
+        self.delivered = true;
+    }
+
+    /// Returns the path of the saved draft
+    fn save(&self) -> io::Result<PathBuf> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let name = self.file.file_name().unwrap_or_default().to_string_lossy();
+        let path = self
+            .drafts
+            .dir
+            .join(format!("{timestamp}-{}-{name}", self.id));
+
+        fs::copy(&self.file, &path)?;
+
+        let metadata = Metadata {
+            url: &self.url,
+            title: &self.title,
+            timestamp,
+        };
+        let json = serde_json::to_vec_pretty(&metadata).map_err(io::Error::from)?;
+        let mut metadata_path = path.clone().into_os_string();
+        metadata_path.push(".json");
+        fs::write(metadata_path, json)?;
+
+        Ok(path)
+    }
+}
+
+impl Drop for Draft {
+    fn drop(&mut self) {
+        if self.delivered {
+            return;
+        }
+        // deleted to end the session after its changes were sent
+        if !self.file.exists() {
+            return;
+        }
+        // blocking, but only once per session and before its directory is removed
+        match self.save() {
+            Ok(path) => info!("Saved text of {:?} to {path:?}", self.title),
+            Err(e) => error!("Unable to save text of {:?} as a draft: {e}", self.title),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> msg::GetTextFromComponent {
+        msg::GetTextFromComponent {
+            selections: vec![],
+            syntax: String::new(),
+            text: String::new(),
+            title: String::from("title"),
+            url: String::from("example.com"),
+            resume_token: None,
+        }
+    }
+
+    #[test]
+    fn saves_undelivered_text() {
+        let dir = tempdir::TempDir::new("gtany-drafts").unwrap();
+        let file = dir.path().join("example.com.txt");
+        fs::write(&file, "hello").unwrap();
+        let drafts = Drafts::new(dir.path().join("drafts")).unwrap();
+
+        let mut delivered = drafts.guard(&file, &message(), 0);
+        delivered.delivered();
+        drop(delivered);
+        assert_eq!(0, fs::read_dir(&drafts.dir).unwrap().count());
+
+        drop(drafts.guard(&file, &message(), 1));
+        let mut saved: Vec<_> = fs::read_dir(&drafts.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        saved.sort();
+        assert_eq!(2, saved.len());
+        assert!(saved[0].to_string_lossy().ends_with("-1-example.com.txt"));
+        assert_eq!("hello", fs::read_to_string(&saved[0]).unwrap());
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&fs::read(&saved[1]).unwrap()).unwrap();
+        assert_eq!("example.com", metadata["url"]);
+        assert_eq!("title", metadata["title"]);
+    }
+
+    #[test]
+    fn finds_latest_draft_of_page() {
+        let dir = tempdir::TempDir::new("gtany-drafts").unwrap();
+        let file = dir.path().join("example.com.txt");
+        let drafts = Drafts::new(dir.path().join("drafts")).unwrap();
+        let save = |text: &str, msg: &msg::GetTextFromComponent, id: SessionId| {
+            fs::write(&file, text).unwrap();
+            let mut draft = drafts.guard(&file, msg, id);
+            let path = draft.save().unwrap();
+            draft.delivered();
+            path
+        };
+
+        save("first", &message(), 0);
+        let latest = save("second", &message(), 1);
+        let other = msg::GetTextFromComponent {
+            title: String::from("other"),
+            ..message()
+        };
+        save("elsewhere", &other, 2);
+        fs::write(drafts.dir.join("notes.json"), "not details").unwrap();
+
+        assert_eq!(3, drafts.list().unwrap().len());
+        assert_eq!(Some(latest), drafts.latest("example.com", "title").unwrap());
+        assert_eq!(None, drafts.latest("example.com", "missing").unwrap());
+    }
+}
@@ -1,61 +1,812 @@
-use std::path::Path;
+use std::{
+    env,
+    ffi::OsString,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    str::FromStr,
+};
 
 use anyhow::bail;
 use anyhow::Context;
-use tokio::process::Command;
+use tokio::{
+    process::{Child, Command},
+    sync::Notify,
+    time::{timeout, Duration, Instant},
+};
 
+use super::file;
+use super::focus;
+use super::glob;
 use super::msg;
-use super::text::utf16_offset_to_utf8_line_col;
+use super::rules::Rule;
+use super::text::{utf16_offset_to_display_line_col, utf16_offset_to_utf8_line_col};
+#[cfg(target_os = "linux")]
+use super::wsl;
 use super::Settings;
 
+/// Editors that run in the terminal they are started from
+const TERMINAL_EDITORS: &[&str] = &[
+    "vi", "vim", "nvim", "nano", "pico", "micro", "kak", "hx", "helix", "joe", "ee", "mg", "ne",
+    "vis",
+];
+
+/// An editor command for pages on matching domains, parsed from `PATTERN=COMMAND`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditorFor {
+    pub pattern: String,
+    pub command: String,
+}
+
+impl FromStr for EditorFor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, command)) = s.split_once('=') else {
+            bail!("Expected PATTERN=COMMAND, got {s:?}");
+        };
+        if pattern.is_empty() {
+            bail!("Missing domain pattern in {s:?}");
+        }
+        if split_command(command)?.is_empty() {
+            bail!("Missing editor command in {s:?}");
+        }
+
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            command: command.to_owned(),
+        })
+    }
+}
+
+/// The editor command for pages on `domain`
+///
+/// From the domain's rule, the first matching `--editor-for`, or `--editor`.
+pub fn command_for<'a>(
+    options: &'a Settings,
+    rule: &'a Rule,
+    domain: Option<&str>,
+) -> Option<&'a str> {
+    if let Some(editor) = &rule.editor {
+        return Some(editor);
+    }
+    let editor_for = domain.and_then(|domain| {
+        options
+            .editor_for
+            .iter()
+            .find(|editor_for| glob::matches(&editor_for.pattern, domain))
+    });
+    match editor_for {
+        Some(editor_for) => {
+            debug!("Using editor {:?} for {domain:?}", editor_for.command);
+            Some(&editor_for.command)
+        }
+        None => options.editor.as_deref(),
+    }
+}
+
+/// Fail if the editor needs a terminal but the server isn't running in one, and no `--terminal` is set
+///
+/// Otherwise the editor would exit right away or hang without any way to
+/// reach it, e.g. when run as a systemd service.
+pub fn check_terminal(options: &Settings, editor: Option<&str>) -> anyhow::Result<()> {
+    if options.terminal.is_some() {
+        return Ok(());
+    }
+
+    let Some(program) = editor
+        .and_then(|editor| split_command(editor).ok())
+        .and_then(|pieces| pieces.into_iter().next())
+    else {
+        return Ok(());
+    };
+
+    if lacks_terminal(&program) {
+        bail!("Editor {program:?} needs a terminal, use a graphical editor, run it in a terminal emulator, or pass `--terminal`");
+    }
+
+    Ok(())
+}
+
+/// Whether `program` needs a terminal that the server isn't running in
+fn lacks_terminal(program: &str) -> bool {
+    if io::stdin().is_terminal() && io::stdout().is_terminal() {
+        return false;
+    }
+    let has_display = env::var_os("DISPLAY").is_some() || env::var_os("WAYLAND_DISPLAY").is_some();
+    needs_terminal(program, has_display)
+}
+
+fn needs_terminal(program: &str, has_display: bool) -> bool {
+    let name = program_name(program);
+
+    // opens a window when it can
+    if name == "emacs" {
+        return !has_display;
+    }
+
+    TERMINAL_EDITORS.contains(&name.as_str())
+}
+
+/// Lowercase name of a program without its directory or extension, e.g. `code` for `Code.exe`
+fn program_name(program: &str) -> String {
+    Path::new(program)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or(program)
+        .to_ascii_lowercase()
+}
+
+/// Split an editor command into the program and its arguments
+///
+/// Follows the platform's conventions: shell words on unix, and on Windows
+/// the rules programs use to parse their command line, which keep the
+/// backslashes in paths like `"C:\Program Files\Notepad++\notepad++.exe"`.
+/// A command that is the path of an existing file, like that path without
+/// quotes in `EDITOR`, is the program alone.
+pub fn split_command(editor: &str) -> anyhow::Result<Vec<String>> {
+    let trimmed = editor.trim_matches([' ', '\t']);
+    if trimmed.contains(' ') && Path::new(trimmed).is_file() {
+        return Ok(vec![trimmed.to_owned()]);
+    }
+    if cfg!(windows) {
+        Ok(split_windows(editor))
+    } else {
+        shell_words::split(editor).context("Could not parse editor command")
+    }
+}
+
+/// Split a command line like `CommandLineToArgvW`
+///
+/// Backslashes are literal unless they precede a quote, and an unclosed quote
+/// runs to the end of the line.
+fn split_windows(command: &str) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut chars = command.trim_start_matches([' ', '\t']).chars().peekable();
+
+    // the program name can't contain quotes, so backslashes are never escapes
+    let mut program = String::new();
+    let mut quoted = false;
+    for c in chars.by_ref() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => break,
+            c => program.push(c),
+        }
+    }
+    if program.is_empty() && chars.peek().is_none() {
+        return pieces;
+    }
+    pieces.push(program);
+
+    let mut piece: Option<String> = None;
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' if !quoted => {
+                pieces.extend(piece.take());
+            }
+            '\\' => {
+                let mut backslashes = 1;
+                while chars.next_if_eq(&'\\').is_some() {
+                    backslashes += 1;
+                }
+                let piece = piece.get_or_insert_with(String::new);
+                if chars.peek() == Some(&'"') {
+                    // pairs of backslashes before a quote are escaped backslashes
+                    piece.extend(std::iter::repeat_n('\\', backslashes / 2));
+                    if backslashes % 2 == 1 {
+                        piece.push('"');
+                        chars.next();
+                    }
+                } else {
+                    piece.extend(std::iter::repeat_n('\\', backslashes));
+                }
+            }
+            '"' => {
+                let piece = piece.get_or_insert_with(String::new);
+                if quoted && chars.next_if_eq(&'"').is_some() {
+                    // doubled quotes inside quotes are a literal quote
+                    piece.push('"');
+                } else {
+                    quoted = !quoted;
+                }
+            }
+            c => piece.get_or_insert_with(String::new).push(c),
+        }
+    }
+    pieces.extend(piece);
+
+    pieces
+}
+
+/// Successful exits quicker than this are assumed to have forked
+const FORK_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// How the editor process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Ran until the user was done editing, or failed
+    Finished,
+    /// Exited successfully right away, likely handing the file to an existing instance
+    Forked,
+}
+
 /// Returns on process exit
-pub async fn spawn_editor(
+///
+/// `on_spawn` is called with the editor's process id, and what it returns is
+/// kept while the editor runs. Once `close` is notified, it's asked to exit
+/// within `--close-grace`. With a `diff` draft, the editor compares the file
+/// with it if it can.
+pub async fn spawn_editor<T>(
     options: &Settings,
+    rule: &Rule,
     file_path: &Path,
+    diff: Option<&Path>,
     msg: &msg::GetTextFromComponent,
-) -> anyhow::Result<()> {
-    info!("New session from: {:?}", msg.title);
+    on_spawn: impl FnOnce(u32) -> T,
+    close: &Notify,
+) -> anyhow::Result<Exit> {
+    match &rule.label {
+        Some(label) => info!("New {label:?} session from: {:?}", msg.title),
+        None => info!("New session from: {:?}", msg.title),
+    }
+
+    let domain = msg.domain();
+    let editor = command_for(options, rule, domain.as_deref()).context("No editor command set")?;
+
+    // Terminal editors need to stay in the foreground process group to use the terminal
+    #[cfg(unix)]
+    let own_group = !io::stdin().is_terminal();
+
+    // tried in order while the program isn't found
+    let editors: Vec<&str> = std::iter::once(editor)
+        .chain(options.editor_fallback.iter().map(String::as_str))
+        .collect();
+    let start = Instant::now();
+    let mut i = 0;
+    let (mut child, program, file_arg) = loop {
+        let candidate = editors[i];
+        i += 1;
+        if let Err(e) = check_terminal(options, Some(candidate)) {
+            match editors.get(i) {
+                Some(next) => {
+                    warn!("{e}, trying {next:?}");
+                    continue;
+                }
+                None => return Err(e),
+            }
+        }
+        let (mut command, program, file_arg) =
+            editor_command(options, rule, candidate, file_path, diff, msg).await?;
+        #[cfg(unix)]
+        if own_group {
+            command.process_group(0);
+        }
+        match command.spawn() {
+            Ok(child) => {
+                if candidate != editor {
+                    info!("Running fallback editor {candidate:?}");
+                }
+                break (child, program, file_arg);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match editors.get(i) {
+                Some(next) => warn!("Editor {program:?} not found, trying {next:?}"),
+                None => return Err(e).with_context(|| format!("Editor {program:?} not found")),
+            },
+            Err(e) => return Err(e).with_context(|| format!("Unable to run editor {program:?}")),
+        }
+    };
+    let file_arg = file_arg.as_str();
+
+    let _tracked = child.id().map(on_spawn);
+
+    // not awaited, the editor is usable while its window is being focused
+    if let (Some(command), Some(pid)) = (&options.focus, child.id()) {
+        let (command, file_path) = (command.clone(), file_path.to_owned());
+        tokio::spawn(async move { focus::focus(&command, pid, &file_path).await });
+    }
+
+    #[cfg(unix)]
+    let group = child
+        .id()
+        .filter(|_| own_group)
+        .map(|pid| ProcessGroup(pid as libc::pid_t));
 
-    let file_path = file_path
+    // signals go to the whole group, like a terminal's shell and editor
+    #[cfg(unix)]
+    let signaled = match &group {
+        Some(ProcessGroup(group)) => Some(-group),
+        None => child.id().map(|pid| pid as libc::pid_t),
+    };
+
+    let exit_status = tokio::select! {
+        status = child.wait() => status?,
+        () = close.notified() => {
+            #[cfg(unix)]
+            let closed = close_gracefully(options, &mut child, signaled, file_arg).await;
+            #[cfg(not(unix))]
+            let closed = close_gracefully(options, &mut child, file_arg).await;
+            closed?
+        }
+    };
+    let elapsed = start.elapsed();
+
+    // leave anything the editor started in the background alone
+    #[cfg(unix)]
+    std::mem::forget(group);
+
+    if !exit_status.success() {
+        error!("Editor process exited with status: {}", exit_status);
+        return Ok(Exit::Finished);
+    }
+
+    if elapsed < FORK_THRESHOLD {
+        #[cfg(windows)]
+        let wait_for_unlock = options.wait_for_unlock;
+        #[cfg(not(windows))]
+        let wait_for_unlock = false;
+
+        if options.wait_for_delete {
+            info!(
+                "Editor {program:?} exited after {elapsed:.1?}, syncing until the file is deleted"
+            );
+        } else if wait_for_unlock {
+            info!(
+                "Editor {program:?} exited after {elapsed:.1?}, syncing until the file is closed"
+            );
+        } else {
+            warn!(
+                "Editor {program:?} exited after {elapsed:.1?}, it may have handed the file to a running instance. \
+                Pass its flag to wait for the file to close (e.g. `code --wait`), \
+                or use `--wait-for-delete` to keep syncing until the file is deleted."
+            );
+        }
+        return Ok(Exit::Forked);
+    }
+
+    Ok(Exit::Finished)
+}
+
+/// The command to run `editor` on the file, with the program and the file's argument
+async fn editor_command(
+    options: &Settings,
+    rule: &Rule,
+    editor: &str,
+    file_path: &Path,
+    diff: Option<&Path>,
+    msg: &msg::GetTextFromComponent,
+) -> anyhow::Result<(Command, String, String)> {
+    let file_path_str = file_path
         .to_str()
         .expect("Internally created file paths should be safe UTF-8");
 
+    let domain = msg.domain();
+    let mut pieces = split_command(editor)?;
+
+    if pieces.is_empty() {
+        bail!("Empty editor command");
+    }
+    if let Some(terminal) = &options.terminal {
+        if lacks_terminal(&pieces[0]) {
+            let mut wrapped = split_command(terminal).context("Invalid --terminal command")?;
+            debug!("Running {:?} in {wrapped:?}", pieces[0]);
+            wrapped.append(&mut pieces);
+            pieces = wrapped;
+        }
+    }
+
+    let display_columns = pieces
+        .iter()
+        .any(|piece| options.display_columns.contains(&program_name(piece)));
+    let to_line_col = match display_columns {
+        true => utf16_offset_to_display_line_col,
+        false => utf16_offset_to_utf8_line_col,
+    };
     let (line, col) = msg
         .selections
-        .get(0)
-        .map(|s| utf16_offset_to_utf8_line_col(s.start, &msg.text))
+        .first()
+        .map(|s| to_line_col(s.start, &msg.text))
         .unwrap_or((1, 1));
 
-    let mut pieces =
-        shell_words::split(&options.editor).context("Could not parse editor command")?;
+    let program = pieces[0].clone();
+    let mut command = Command::new(&program);
 
-    if pieces.is_empty() {
-        bail!("Empty editor command");
+    #[cfg(target_os = "linux")]
+    let windows_path = match options.wsl && wsl::is_windows_program(&program) {
+        true => Some(wsl::windows_path(file_path).await?),
+        false => None,
+    };
+    #[cfg(not(target_os = "linux"))]
+    let windows_path: Option<String> = None;
+    let file_arg = windows_path.as_deref().unwrap_or(file_path_str);
+
+    #[cfg(windows)]
+    let raw_args = options.raw_args;
+    #[cfg(not(windows))]
+    let raw_args = false;
+
+    if raw_args {
+        #[cfg(windows)]
+        {
+            let args = substitute_raw(raw_args_of(editor), file_path_str, line, col);
+            debug!("Opening editor {program:?} with raw arguments {args:?}");
+            command.raw_arg(args);
+        }
+    } else {
+        if rule.read_only && !add_read_only_flags(&mut pieces) {
+            warn!("No known read-only flag for {editor:?}, changes will not be sent to the page");
+        }
+        if options.window_title {
+            let title = match &domain {
+                Some(domain) => format!("{} - {domain}", msg.title),
+                None => msg.title.clone(),
+            };
+            if !add_title_flags(&mut pieces, &title) {
+                debug!("No known title flag for {editor:?}");
+            }
+        }
+        if let Some(lang) = &rule.lang {
+            if !add_lang_flags(&mut pieces, lang) {
+                debug!("No known spell language flag for {editor:?}");
+            }
+        }
+        // the draft's path isn't converted for Windows editors under WSL
+        let diffed = diff
+            .and_then(Path::to_str)
+            .filter(|_| windows_path.is_none())
+            .is_some_and(|draft| add_diff_args(&mut pieces, file_arg, draft));
+        if diff.is_some() && !diffed {
+            warn!("No known diff mode for {editor:?}, opening the page's text alone");
+        }
+        if !diffed {
+            perform_substitutions(&mut pieces, file_arg, line, col);
+        }
+        #[cfg(target_os = "linux")]
+        if windows_path.is_some() {
+            add_windows_wait_flags(&mut pieces);
+            let rule_env = rule.env.keys().map(String::as_str);
+            command.env("WSLENV", wsl::forward_env(env::var_os("WSLENV"), rule_env));
+        }
+        debug!("Opening editor {:?}", pieces);
+        // quoted by std, including the escaping needed for batch files like `code.cmd`
+        command.args(&pieces[1..]);
     }
 
-    perform_substitutions(&mut pieces, file_path, line, col);
+    SessionEnv {
+        msg,
+        rule,
+        file: file_path,
+    }
+    .apply(&mut command);
+    if !rule.path.is_empty() || !options.editor_path.is_empty() {
+        let dirs = rule.path.iter().chain(&options.editor_path);
+        let existing = rule.env.get("PATH").map(OsString::from);
+        let path = prepend_path(dirs, existing.or_else(|| env::var_os("PATH")))
+            .context("Invalid editor PATH")?;
+        debug!("Editor PATH: {path:?}");
+        command.env("PATH", path);
+    }
 
-    let program = &pieces[0];
+    // reaped by tokio in the background if dropped early
+    command.kill_on_drop(true);
 
-    let args = &pieces[1..];
+    let file_arg = file_arg.to_owned();
+    Ok((command, program, file_arg))
+}
 
-    debug!("Opening editor {:?}", pieces);
+/// Ask the editor to exit, first with `--close-command` or SIGINT, then with
+/// SIGTERM, waiting `--close-grace` for each
+///
+/// `signaled` is the process, or negated process group, to send signals to.
+/// Fails if the editor is still running, to be killed by the caller.
+async fn close_gracefully(
+    options: &Settings,
+    child: &mut Child,
+    #[cfg(unix)] signaled: Option<libc::pid_t>,
+    file_arg: &str,
+) -> anyhow::Result<ExitStatus> {
+    let grace = Duration::from_secs(options.close_grace);
+    info!("Asking the editor to close within {grace:?}");
 
-    let exit_status = Command::new(program)
-        .args(args)
-        .env("GHOST_TEXT_URL", &msg.url)
-        .env("GHOST_TEXT_TITLE", &msg.title)
-        .spawn()?
-        .wait()
-        .await?;
+    match &options.close_command {
+        Some(close_command) => {
+            if let Err(e) = run_close_command(close_command, file_arg, child.id()).await {
+                warn!("Unable to run --close-command: {e:#}");
+            }
+        }
+        #[cfg(unix)]
+        None => send_signal(signaled, libc::SIGINT),
+        #[cfg(not(unix))]
+        None => debug!("No --close-command to ask the editor to close"),
+    }
+    if let Ok(status) = timeout(grace, child.wait()).await {
+        return Ok(status?);
+    }
 
-    if !exit_status.success() {
-        error!("Editor process exited with status: {}", exit_status);
+    #[cfg(unix)]
+    {
+        debug!("Editor still running, sending SIGTERM");
+        send_signal(signaled, libc::SIGTERM);
+        if let Ok(status) = timeout(grace, child.wait()).await {
+            return Ok(status?);
+        }
+    }
+
+    bail!("Editor didn't close within {grace:?}, killing it");
+}
+
+/// Run `--close-command` with %f and %p replaced by the file and editor's pid
+async fn run_close_command(command: &str, file_arg: &str, pid: Option<u32>) -> anyhow::Result<()> {
+    let mut pieces = split_command(command)?;
+    if pieces.is_empty() {
+        bail!("Empty command");
+    }
+    let pid = pid.map(|pid| pid.to_string()).unwrap_or_default();
+    for piece in pieces.iter_mut().skip(1) {
+        replace_in_place(piece, "%f", file_arg);
+        replace_in_place(piece, "%p", &pid);
     }
+    debug!("Running close command {pieces:?}");
 
+    let status = Command::new(&pieces[0])
+        .args(&pieces[1..])
+        .kill_on_drop(true)
+        .status()
+        .await?;
+    if !status.success() {
+        bail!("Exited with {status}");
+    }
     Ok(())
 }
 
+#[cfg(unix)]
+fn send_signal(pid: Option<libc::pid_t>, signal: libc::c_int) {
+    // already exited, and 0 would signal the server's own group
+    let Some(pid) = pid.filter(|&pid| pid != 0) else {
+        return;
+    };
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        debug!(
+            "Unable to send signal {signal} to {pid}: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Kills the whole process group when dropped
+///
+/// Catches children of the editor, like a terminal emulator's shell, that
+/// would otherwise be left running when a session ends early.
+#[cfg(unix)]
+struct ProcessGroup(libc::pid_t);
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        debug!("Killing editor process group {}", self.0);
+        // SAFETY: kill has no memory safety requirements
+        if unsafe { libc::kill(-self.0, libc::SIGKILL) } != 0 {
+            debug!(
+                "Unable to kill process group {}: {}",
+                self.0,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// `existing` PATH with `dirs` searched first
+fn prepend_path<'a>(
+    dirs: impl IntoIterator<Item = &'a PathBuf>,
+    existing: Option<OsString>,
+) -> Result<OsString, env::JoinPathsError> {
+    let existing = existing.iter().flat_map(env::split_paths);
+    env::join_paths(dirs.into_iter().cloned().chain(existing))
+}
+
+/// All selections as a JSON list for wrapper scripts
+/// The session's details for the editor, hooks and filters
+///
+/// Sets the rule's `env`, then GHOST_TEXT_URL, GHOST_TEXT_TITLE,
+/// GHOST_TEXT_SELECTIONS, GHOST_TEXT_SCRATCH, and GHOST_TEXT_LANG and
+/// GHOST_TEXT_LABEL if the rule has them.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionEnv<'a> {
+    pub msg: &'a msg::GetTextFromComponent,
+    pub rule: &'a Rule,
+    /// The session's file
+    pub file: &'a Path,
+}
+
+impl SessionEnv<'_> {
+    pub fn apply(&self, command: &mut Command) {
+        let rule = self.rule;
+        command
+            .envs(&rule.env)
+            .env("GHOST_TEXT_URL", &self.msg.url)
+            .env("GHOST_TEXT_TITLE", &self.msg.title)
+            .env("GHOST_TEXT_SELECTIONS", selections_json(self.msg))
+            .env("GHOST_TEXT_SCRATCH", file::scratch_dir(self.file))
+            .envs(rule.lang.as_ref().map(|lang| ("GHOST_TEXT_LANG", lang)))
+            .envs(rule.label.as_ref().map(|label| ("GHOST_TEXT_LABEL", label)));
+    }
+}
+
+fn selections_json(msg: &msg::GetTextFromComponent) -> String {
+    serde_json::to_string(&msg.selections()).expect("selections serialize to JSON")
+}
+
+/// Add the flag to open the file read-only after the first known editor in the command
+///
+/// Looks past the program for wrappers like `x-terminal-emulator -e vim`.
+/// Returns false if there is no known editor.
+fn add_read_only_flags(command: &mut Vec<String>) -> bool {
+    let Some((i, flags)) = command
+        .iter()
+        .enumerate()
+        .find_map(|(i, piece)| Some((i, read_only_flags(&program_name(piece))?)))
+    else {
+        return false;
+    };
+
+    command.splice(i + 1..i + 1, flags.iter().map(|f| f.to_string()));
+    true
+}
+
+fn read_only_flags(editor: &str) -> Option<&'static [&'static str]> {
+    Some(match editor {
+        "vi" | "vim" | "nvim" | "gvim" => &["-R"],
+        "nano" => &["-v"],
+        "kak" => &["-ro"],
+        "micro" => &["-readonly", "true"],
+        // can't edit anyway
+        "less" | "more" | "view" | "bat" => &[],
+        _ => return None,
+    })
+}
+
+/// Add flags to set the window title after each known editor or terminal in the command
+///
+/// Returns false if there are none.
+fn add_title_flags(command: &mut Vec<String>, title: &str) -> bool {
+    let known: Vec<_> = command
+        .iter()
+        .enumerate()
+        .filter_map(|(i, piece)| Some((i, title_flags(&program_name(piece), title)?)))
+        .collect();
+
+    // from the back to keep the indices valid
+    for (i, flags) in known.iter().rev() {
+        command.splice(i + 1..i + 1, flags.iter().cloned());
+    }
+    !known.is_empty()
+}
+
+fn title_flags(program: &str, title: &str) -> Option<Vec<String>> {
+    let title = title.to_string();
+    Some(match program {
+        "vim" | "nvim" | "gvim" => vec![
+            "--cmd".to_string(),
+            format!(
+                "let &titlestring = '{}' | set title",
+                title.replace('\'', "''")
+            ),
+        ],
+        "emacs" | "alacritty" | "foot" | "kitty" | "xfce4-terminal" => {
+            vec!["--title".to_string(), title]
+        }
+        "xterm" | "urxvt" | "rxvt" => vec!["-T".to_string(), title],
+        "st" => vec!["-t".to_string(), title],
+        _ => return None,
+    })
+}
+
+/// Add flags to spell check in `lang`, like `de-DE`, after the first known editor in the command
+///
+/// Returns false if there is none.
+fn add_lang_flags(command: &mut Vec<String>, lang: &str) -> bool {
+    let Some((i, flags)) = command
+        .iter()
+        .enumerate()
+        .find_map(|(i, piece)| Some((i, lang_flags(&program_name(piece), lang)?)))
+    else {
+        return false;
+    };
+
+    command.splice(i + 1..i + 1, flags);
+    true
+}
+
+fn lang_flags(program: &str, lang: &str) -> Option<Vec<String>> {
+    // `de_DE`, as spell checkers name their dictionaries
+    let (language, region) = lang.split_once(['-', '_']).unwrap_or((lang, ""));
+    let dictionary = match region {
+        "" => language.to_ascii_lowercase(),
+        region => format!(
+            "{}_{}",
+            language.to_ascii_lowercase(),
+            region.to_ascii_uppercase()
+        ),
+    };
+    Some(match program {
+        // vim's spell files are lowercase, e.g. `de_de`
+        "vim" | "nvim" | "gvim" => vec![
+            "-c".to_string(),
+            format!(
+                "setlocal spell spelllang={}",
+                dictionary.to_ascii_lowercase()
+            ),
+        ],
+        "emacs" => vec![
+            "--eval".to_string(),
+            format!("(setq ispell-dictionary \"{dictionary}\")"),
+        ],
+        _ => return None,
+    })
+}
+
+/// Add arguments to compare the file with `draft` for the last known editor in the command
+///
+/// Returns false if there is no known editor, or the command places the file
+/// itself with `%f`, `%l`, or `%c`.
+fn add_diff_args(command: &mut Vec<String>, file: &str, draft: &str) -> bool {
+    let placeholders = ["%f", "%l", "%c"];
+    if command
+        .iter()
+        .skip(1)
+        .any(|piece| placeholders.iter().any(|p| piece.contains(p)))
+    {
+        return false;
+    }
+    let Some(mut args) = command
+        .iter()
+        .rev()
+        .find_map(|piece| diff_args(&program_name(piece), file, draft))
+    else {
+        return false;
+    };
+
+    // already given, e.g. `code --wait`
+    if command.iter().any(|piece| piece == "--wait") {
+        args.retain(|arg| arg != "--wait");
+    }
+    command.append(&mut args);
+    true
+}
+
+fn diff_args(editor: &str, file: &str, draft: &str) -> Option<Vec<String>> {
+    let (file, draft) = (file.to_string(), draft.to_string());
+    Some(match editor {
+        "vim" | "nvim" => vec!["-d".to_string(), file, draft],
+        // stays in the foreground, rather than detaching from the console
+        "gvim" => vec!["-f".to_string(), "-d".to_string(), file, draft],
+        "code" | "code-insiders" | "code-oss" | "codium" => {
+            vec!["--diff".to_string(), file, draft, "--wait".to_string()]
+        }
+        _ => return None,
+    })
+}
+
+/// Keep Windows editors started from WSL running until the file is closed
+///
+/// Otherwise they hand the file to an instance that is already running and
+/// exit right away.
+#[cfg(target_os = "linux")]
+fn add_windows_wait_flags(command: &mut Vec<String>) {
+    let flags: &[&str] = match program_name(&command[0]).as_str() {
+        "code" | "code-insiders" | "codium" => &["--wait"],
+        "notepad++" => &["-multiInst", "-nosession"],
+        _ => return,
+    };
+    for flag in flags.iter().rev() {
+        if !command.iter().skip(1).any(|arg| arg == flag) {
+            command.insert(1, flag.to_string());
+        }
+    }
+}
+
 /// Add filename, cursor line, and cursor column to the command
 fn perform_substitutions(command: &mut Vec<String>, file_path: &str, line: usize, col: usize) {
     const FILE: &str = "%f";
@@ -75,8 +826,8 @@ fn perform_substitutions(command: &mut Vec<String>, file_path: &str, line: usize
         return;
     }
 
-    let editor = &command[command.len() - 1];
-    if let Some(mut additions) = format_known_editors(editor, file_path, line, col) {
+    let editor = program_name(&command[command.len() - 1]);
+    if let Some(mut additions) = format_known_editors(&editor, file_path, line, col) {
         debug!("Recognized editor {editor:?}: adding {additions:?}");
         command.append(&mut additions);
         return;
@@ -85,6 +836,42 @@ fn perform_substitutions(command: &mut Vec<String>, file_path: &str, line: usize
     command.push(file_path.to_string());
 }
 
+/// The arguments of a Windows command line, as written
+#[cfg(windows)]
+fn raw_args_of(command: &str) -> &str {
+    let command = command.trim_start_matches([' ', '\t']);
+    let mut quoted = false;
+    for (i, c) in command.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => return command[i..].trim_start_matches([' ', '\t']),
+            _ => {}
+        }
+    }
+    ""
+}
+
+/// Add filename, cursor line, and cursor column to unparsed arguments
+///
+/// The path is quoted when it's appended, placeholders are replaced as is.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn substitute_raw(args: &str, file_path: &str, line: usize, col: usize) -> String {
+    let mut args = args.to_owned();
+    let replaced_file = replace_in_place(&mut args, "%f", file_path);
+    replace_in_place(&mut args, "%l", &line.to_string());
+    replace_in_place(&mut args, "%c", &col.to_string());
+
+    if !replaced_file {
+        if !args.is_empty() {
+            args.push(' ');
+        }
+        args.push('"');
+        args.push_str(file_path);
+        args.push('"');
+    }
+    args
+}
+
 fn replace_in_place(source: &mut String, pattern: &str, replacement: &str) -> bool {
     let start = match source.find(pattern) {
         None => return false,
@@ -103,16 +890,302 @@ fn format_known_editors(editor: &str, file: &str, line: usize, col: usize) -> Op
     use std::format as f;
     Some(match editor {
         "vi" | "vim" | "nvim" => vec![f!("+{line}"), f!("+norm! {col}|"), file.to_string()],
+        // stays in the foreground, rather than detaching from the console
+        "gvim" => vec![
+            "-f".to_string(),
+            f!("+{line}"),
+            f!("+norm! {col}|"),
+            file.to_string(),
+        ],
         "emacs" | "emacsclient" | "gedit" | "kak" => vec![f!("+{line}:{col}"), file.to_string()],
         "nano" => vec![f!("+{line},{col}"), file.to_string()],
         "joe" | "ee" => vec![f!("+{line}"), file.to_string()],
-        "code" | "code-oss" | "codium" => vec![
+        "code" | "code-insiders" | "code-oss" | "codium" => vec![
             "--goto".to_string(),
             f!("{file}:{line}:{col}"),
             "--wait".to_string(),
         ],
         "subl" => vec![f!("{file}:{line}:{col}"), "--wait".to_string()],
         "micro" => vec![file.to_string(), f!("+{line}:{col}")],
+        // a new instance, so it exits when the file is closed
+        "notepad++" => vec![
+            "-multiInst".to_string(),
+            "-nosession".to_string(),
+            f!("-n{line}"),
+            f!("-c{col}"),
+            file.to_string(),
+        ],
         _ => return None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("vim", false => true)]
+    #[test_case("/usr/bin/nvim", true => true ; "full path")]
+    #[test_case("emacs", false => true ; "emacs without display")]
+    #[test_case("emacs", true => false ; "emacs with display")]
+    #[test_case("gvim", false => false)]
+    #[test_case("code", false => false)]
+    fn detects_terminal_editors(program: &str, has_display: bool) -> bool {
+        needs_terminal(program, has_display)
+    }
+
+    #[test_case(r#"notepad.exe"# => vec!["notepad.exe"] ; "program only")]
+    #[test_case(r#"  notepad.exe  "# => vec!["notepad.exe"] ; "surrounding spaces")]
+    #[test_case(
+        r#""C:\Program Files\Notepad++\notepad++.exe" -multiInst -nosession"#
+        => vec![r#"C:\Program Files\Notepad++\notepad++.exe"#, "-multiInst", "-nosession"]
+        ; "notepad++"
+    )]
+    #[test_case(
+        r#""C:\Users\me\AppData\Local\Programs\Microsoft VS Code\bin\code.cmd" --wait"#
+        => vec![r#"C:\Users\me\AppData\Local\Programs\Microsoft VS Code\bin\code.cmd"#, "--wait"]
+        ; "vs code"
+    )]
+    #[test_case(
+        r#"C:\tools\edit.bat "%f" --line=%l"#
+        => vec![r#"C:\tools\edit.bat"#, "%f", "--line=%l"]
+        ; "batch wrapper"
+    )]
+    #[test_case(r#"prog "a \"b\" c""# => vec!["prog", r#"a "b" c"#] ; "escaped quotes")]
+    #[test_case(r#"prog "say ""hi""""# => vec!["prog", r#"say "hi""#] ; "doubled quotes")]
+    #[test_case(r#"prog "C:\dir\\" next"# => vec!["prog", r#"C:\dir\"#, "next"] ; "backslash before quote")]
+    #[test_case(r#"prog a\\b"# => vec!["prog", r#"a\\b"#] ; "literal backslashes")]
+    #[test_case(r#"prog "" x"# => vec!["prog", "", "x"] ; "empty argument")]
+    #[test_case(r#"prog "unclosed arg"# => vec!["prog", "unclosed arg"] ; "unclosed quote")]
+    fn splits_windows_commands(command: &str) -> Vec<&str> {
+        // leaked to compare with the expected &str
+        split_windows(command)
+            .into_iter()
+            .map(|piece| &*Box::leak(piece.into_boxed_str()))
+            .collect()
+    }
+
+    #[test_case(r#"C:\Program Files\Vim\vim91\gvim.exe"# => strings(&["-f", "+3", "+norm! 7|", r#"C:\tmp\a b.txt"#]) ; "gvim")]
+    #[test_case(r#"C:\Program Files\Microsoft VS Code Insiders\bin\code-insiders.cmd"# => strings(&["--goto", r#"C:\tmp\a b.txt:3:7"#, "--wait"]) ; "vs code")]
+    #[test_case(r#"C:\Program Files\Notepad++\notepad++.exe"# => strings(&["-multiInst", "-nosession", "-n3", "-c7", r#"C:\tmp\a b.txt"#]) ; "notepad++")]
+    fn formats_windows_editors(program: &str) -> Vec<String> {
+        let mut command = vec![program.replace('\\', std::path::MAIN_SEPARATOR_STR)];
+        perform_substitutions(&mut command, r#"C:\tmp\a b.txt"#, 3, 7);
+        command.split_off(1)
+    }
+
+    #[test]
+    fn keeps_unquoted_program_paths() {
+        let dir = tempdir::TempDir::new("gtany editor").unwrap();
+        let program = dir.path().join("my editor");
+        std::fs::write(&program, "").unwrap();
+        let program = program.to_str().unwrap();
+
+        assert_eq!(vec![program], split_command(program).unwrap());
+    }
+
+    #[test_case("code" => "code")]
+    #[test_case(r#"C:\Program Files\Microsoft VS Code\Code.exe"# => "code" ; "windows path")]
+    #[test_case("/usr/bin/nvim" => "nvim" ; "unix path")]
+    #[test_case("code.cmd" => "code" ; "batch file")]
+    fn names_programs(program: &str) -> String {
+        // written with backslashes, which only separate directories on Windows
+        program_name(&program.replace('\\', std::path::MAIN_SEPARATOR_STR))
+    }
+
+    #[test_case("" => r#""C:\tmp\a b.txt""# ; "appends quoted path")]
+    #[test_case("-multiInst" => r#"-multiInst "C:\tmp\a b.txt""# ; "after arguments")]
+    #[test_case(r#"-n%l -c%c "%f""# => r#"-n3 -c7 "C:\tmp\a b.txt""# ; "placeholders")]
+    fn substitutes_raw_args(args: &str) -> String {
+        substitute_raw(args, r#"C:\tmp\a b.txt"#, 3, 7)
+    }
+
+    #[test_case("vim" => Some(String::from("vim -R")) ; "vim")]
+    #[test_case("x-terminal-emulator -e nvim" => Some(String::from("x-terminal-emulator -e nvim -R")) ; "wrapped")]
+    #[test_case("micro %f" => Some(String::from("micro -readonly true %f")) ; "placeholders")]
+    #[test_case("less" => Some(String::from("less")) ; "pager")]
+    #[test_case("code --wait" => None ; "unknown")]
+    fn adds_read_only_flags(command: &str) -> Option<String> {
+        let mut pieces: Vec<String> = command.split(' ').map(String::from).collect();
+        add_read_only_flags(&mut pieces).then(|| pieces.join(" "))
+    }
+
+    #[test]
+    fn parses_editor_for() {
+        assert_eq!(
+            EditorFor {
+                pattern: String::from("*.github.com"),
+                command: String::from("code --wait"),
+            },
+            "*.github.com=code --wait".parse().unwrap()
+        );
+        assert!("code".parse::<EditorFor>().is_err());
+        assert!("=code".parse::<EditorFor>().is_err());
+        assert!("github.com=".parse::<EditorFor>().is_err());
+    }
+
+    #[test_case(Some("github.com") => Some("code --wait".to_owned()) ; "matching pattern")]
+    #[test_case(Some("gist.github.com") => Some("gvim -f".to_owned()) ; "later pattern")]
+    #[test_case(Some("example.com") => Some("nvim".to_owned()) ; "no match")]
+    #[test_case(None => Some("nvim".to_owned()) ; "unknown domain")]
+    fn picks_editor_for_domain(domain: Option<&str>) -> Option<String> {
+        let options: Settings = clap::Parser::parse_from([
+            "gtany",
+            "--editor",
+            "nvim",
+            "--editor-for",
+            "github.com=code --wait",
+            "--editor-for",
+            "*.github.com=gvim -f",
+        ]);
+        command_for(&options, &Rule::default(), domain).map(String::from)
+    }
+
+    #[test]
+    fn prefers_rule_editor() {
+        let options: Settings = clap::Parser::parse_from([
+            "gtany",
+            "--editor",
+            "nvim",
+            "--editor-for",
+            "*=code --wait",
+        ]);
+        let rule = Rule {
+            editor: Some(String::from("emacs")),
+            ..Rule::default()
+        };
+        assert_eq!(
+            Some("emacs"),
+            command_for(&options, &rule, Some("github.com"))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn prepends_path() {
+        let (tools, node) = (PathBuf::from("/tools"), PathBuf::from("/opt/node/bin"));
+        assert_eq!(
+            "/tools:/opt/node/bin:/usr/bin:/bin",
+            prepend_path([&tools, &node], Some(OsString::from("/usr/bin:/bin"))).unwrap()
+        );
+        assert_eq!("/tools", prepend_path([&tools], None).unwrap());
+        assert!(prepend_path([&PathBuf::from("a:b")], None).is_err());
+    }
+
+    #[test]
+    fn lists_selections() {
+        let msg = msg::GetTextFromComponent {
+            selections: vec![
+                msg::RangeInText { start: 0, end: 0 },
+                msg::RangeInText { start: 4, end: 8 },
+            ],
+            syntax: String::new(),
+            text: String::from("one\ntwo\n"),
+            title: String::new(),
+            url: String::new(),
+            resume_token: None,
+        };
+
+        let selections: serde_json::Value = serde_json::from_str(&selections_json(&msg)).unwrap();
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "start": 0, "end": 0, "start_byte": 0, "end_byte": 0,
+                    "start_line": 1, "start_column": 1, "end_line": 1, "end_column": 1,
+                },
+                {
+                    "start": 4, "end": 8, "start_byte": 4, "end_byte": 8,
+                    "start_line": 2, "start_column": 1, "end_line": 3, "end_column": 1,
+                },
+            ]),
+            selections
+        );
+    }
+
+    #[test_case("nvim" => Some(strings(&[
+        "nvim", "--cmd", "let &titlestring = 'it''s - a.b' | set title"
+    ])) ; "vim")]
+    #[test_case("kitty -e nvim %f" => Some(strings(&[
+        "kitty", "--title", "it's - a.b", "-e",
+        "nvim", "--cmd", "let &titlestring = 'it''s - a.b' | set title", "%f"
+    ])) ; "terminal and editor")]
+    #[test_case("xterm -e nano" => Some(strings(&["xterm", "-T", "it's - a.b", "-e", "nano"])) ; "terminal")]
+    #[test_case("code --wait" => None ; "unknown")]
+    fn adds_title_flags(command: &str) -> Option<Vec<String>> {
+        let mut pieces: Vec<String> = command.split(' ').map(String::from).collect();
+        add_title_flags(&mut pieces, "it's - a.b").then_some(pieces)
+    }
+
+    #[test_case("nvim %f" => Some(strings(&["nvim", "-c", "setlocal spell spelllang=de_de", "%f"])) ; "vim")]
+    #[test_case("emacs" => Some(strings(&["emacs", "--eval", r#"(setq ispell-dictionary "de_DE")"#])) ; "emacs")]
+    #[test_case("code --wait" => None ; "unknown")]
+    fn adds_lang_flags(command: &str) -> Option<Vec<String>> {
+        let mut pieces: Vec<String> = command.split(' ').map(String::from).collect();
+        add_lang_flags(&mut pieces, "de-de").then_some(pieces)
+    }
+
+    #[test_case("nvim" => Some(strings(&["nvim", "-d", "a.txt", "draft.txt"])) ; "vim")]
+    #[test_case("gvim" => Some(strings(&["gvim", "-f", "-d", "a.txt", "draft.txt"])) ; "gvim")]
+    #[test_case("code --wait" => Some(strings(&["code", "--wait", "--diff", "a.txt", "draft.txt"])) ; "code")]
+    #[test_case("kitty -e nvim" => Some(strings(&["kitty", "-e", "nvim", "-d", "a.txt", "draft.txt"])) ; "wrapped")]
+    #[test_case("nvim %f" => None ; "placeholders")]
+    #[test_case("nano" => None ; "unknown")]
+    fn adds_diff_args(command: &str) -> Option<Vec<String>> {
+        let mut pieces: Vec<String> = command.split(' ').map(String::from).collect();
+        add_diff_args(&mut pieces, "a.txt", "draft.txt").then_some(pieces)
+    }
+
+    #[test_case("code.exe %f" => strings(&["code.exe", "--wait", "%f"]) ; "code")]
+    #[test_case("code.exe --goto %f --wait" => strings(&["code.exe", "--goto", "%f", "--wait"]) ; "code with wait")]
+    #[test_case("notepad++.exe %f" => strings(&["notepad++.exe", "-multiInst", "-nosession", "%f"]) ; "notepad++")]
+    #[test_case("gvim.exe %f" => strings(&["gvim.exe", "%f"]) ; "unknown")]
+    #[cfg(target_os = "linux")]
+    fn adds_windows_wait_flags(command: &str) -> Vec<String> {
+        let mut command = split_command(command).unwrap();
+        add_windows_wait_flags(&mut command);
+        command
+    }
+
+    fn strings(pieces: &[&str]) -> Vec<String> {
+        pieces.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn kills_process_group() {
+        use std::{
+            io::{BufRead, BufReader},
+            os::unix::process::CommandExt,
+            process::Stdio,
+            thread::sleep,
+        };
+
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "sleep 60 & echo $!; wait"])
+            .process_group(0)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let grandchild: u32 = line.trim().parse().unwrap();
+
+        drop(ProcessGroup(child.id() as libc::pid_t));
+        child.wait().unwrap();
+
+        // exited, or waiting to be reaped by init
+        let stopped = || match std::fs::read_to_string(format!("/proc/{grandchild}/stat")) {
+            Ok(stat) => stat.contains(") Z "),
+            Err(_) => true,
+        };
+        for _ in 0..50 {
+            if stopped() {
+                return;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("background child of editor still running");
+    }
+}
@@ -0,0 +1,80 @@
+//! `.editorconfig` next to the session file, for editors that read them
+//!
+//! Properties follow the page's syntax, with any from the domain's rule on
+//! top, like `max_line_length = 72` for mailing lists.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+/// Name of the file in the session directory
+pub const FILE_NAME: &str = ".editorconfig";
+
+/// Contents for a file with `extension` and the page's reported `syntax`
+pub fn contents(extension: &str, syntax: &str, overrides: &BTreeMap<String, String>) -> String {
+    let syntax = syntax.to_ascii_lowercase();
+    let mut properties = BTreeMap::from([
+        ("charset", "utf-8"),
+        ("end_of_line", "lf"),
+        ("insert_final_newline", "true"),
+    ]);
+
+    if extension == "md" || syntax.contains("markdown") || syntax == "gfm" {
+        // two trailing spaces are a line break
+        properties.insert("trim_trailing_whitespace", "false");
+    }
+    let indent = match syntax.as_str() {
+        "go" | "golang" | "makefile" => Some(("tab", None)),
+        "python" | "rust" => Some(("space", Some("4"))),
+        "yaml" | "json" | "javascript" | "typescript" | "html" | "css" => {
+            Some(("space", Some("2")))
+        }
+        _ => None,
+    };
+    if let Some((style, size)) = indent {
+        properties.insert("indent_style", style);
+        if let Some(size) = size {
+            properties.insert("indent_size", size);
+        }
+    }
+
+    for (key, value) in overrides {
+        properties.insert(key, value);
+    }
+
+    let mut contents = String::from("root = true\n\n[*]\n");
+    for (key, value) in properties {
+        writeln!(contents, "{key} = {value}").unwrap();
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_trailing_whitespace_in_markdown() {
+        let contents = contents("md", "", &BTreeMap::new());
+        assert_eq!(
+            "root = true\n\n[*]\ncharset = utf-8\nend_of_line = lf\ninsert_final_newline = true\ntrim_trailing_whitespace = false\n",
+            contents
+        );
+    }
+
+    #[test]
+    fn indents_by_syntax() {
+        let contents = contents("txt", "Python", &BTreeMap::new());
+        assert!(contents.contains("indent_size = 4\nindent_style = space\n"));
+        assert!(!contents.contains("trim_trailing_whitespace"));
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let overrides = BTreeMap::from([
+            (String::from("max_line_length"), String::from("72")),
+            (String::from("indent_size"), String::from("8")),
+        ]);
+        let contents = contents("txt", "python", &overrides);
+        assert!(contents.contains("indent_size = 8\n"));
+        assert!(contents.contains("max_line_length = 72\n"));
+    }
+}
@@ -0,0 +1,42 @@
+//! Session lifecycle events for webhooks and library users
+
+use std::{fmt, sync::Arc};
+
+use super::msg;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Start,
+    End,
+    Detached,
+    Error { error: String },
+}
+
+/// Receives the events of every session
+///
+/// Implemented for closures. Called from the server's tasks, so it should
+/// return quickly.
+pub trait SessionEvents: Send + Sync {
+    /// `page` is the first message of the session, with the page's url and title
+    fn notify(&self, event: &Event, page: &msg::GetTextFromComponent);
+}
+
+impl<F> SessionEvents for F
+where
+    F: Fn(&Event, &msg::GetTextFromComponent) + Send + Sync,
+{
+    fn notify(&self, event: &Event, page: &msg::GetTextFromComponent) {
+        self(event, page)
+    }
+}
+
+/// A shared [`SessionEvents`] for the server's state
+#[derive(Clone)]
+pub struct Subscriber(pub Arc<dyn SessionEvents>);
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Subscriber")
+    }
+}
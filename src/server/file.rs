@@ -1,24 +1,35 @@
 use std::{
+    fmt,
+    fs::Metadata,
+    future::Future,
     io::{self},
     path::{Path, PathBuf},
+    str::FromStr,
     time::SystemTime,
 };
 
+use anyhow::bail;
+
 use sha2::{Digest, Sha256};
-use tempdir::TempDir;
 use tokio::{
-    fs::File,
+    fs::{self, File},
     io::{AsyncReadExt, AsyncWriteExt},
+    sync::OwnedSemaphorePermit,
+    time::{sleep, Duration},
 };
 
-use super::msg;
+use super::{dir_pool::DirPool, glob, msg, rules::Rule, Settings};
+use crate::settings::Newline;
 
 #[cfg(feature = "watch_changes")]
 pub use super::watch_changes::watch_edits;
 
 /// A mock that returns an empty stream
 #[cfg(not(feature = "watch_changes"))]
-pub fn watch_edits(_path: impl AsRef<Path>) -> anyhow::Result<impl futures::Stream<Item = ()>> {
+pub fn watch_edits(
+    _path: impl AsRef<Path>,
+    _options: &Settings,
+) -> anyhow::Result<impl futures::Stream<Item = anyhow::Result<()>>> {
     Ok(tokio_stream::empty())
 }
 
@@ -29,44 +40,200 @@ pub fn watch_edits(_path: impl AsRef<Path>) -> anyhow::Result<impl futures::Stre
 pub struct LocalFile {
     path: PathBuf,
     // deletes directory when dropped
-    _tempdir: TempDir,
-    /// Last edit time hash is valid for
-    last_edit: SystemTime,
+    _dir: SessionDir,
+    /// Version of the file that `text` and `hash` are valid for
+    version: Option<FileVersion>,
+    /// Last read or written content, with trailing newline removed
+    text: String,
     /// hash of the local content, with trailing newline removed
     hash: [u8; 32],
+    /// `hash` of the content last sent to or received from the browser
+    synced: Option<[u8; 32]>,
+    /// Largest file that will be read back, in bytes
+    max_len: u64,
+    /// Number of the last previous version saved, if keeping them
+    history: Option<u32>,
+    /// How the text's trailing newline is written and read
+    newline: Newline,
+}
+
+const SESSION_DIR_PREFIX: &str = "ghost-text";
+/// Name of the directory next to the file for the editor's own files
+const SCRATCH_DIR: &str = "scratch";
+
+/// Directory for auxiliary files of the session editing `file`
+///
+/// Removed along with the file when the session ends.
+pub fn scratch_dir(file: &Path) -> PathBuf {
+    file.with_file_name(SCRATCH_DIR)
+}
+
+/// Temporary directory holding a session's file, removed or recycled when dropped
+struct SessionDir {
+    path: PathBuf,
+    pool: DirPool,
+    /// Counts it against `--max-dirs` until it's released
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for SessionDir {
+    fn drop(&mut self) {
+        self.pool.release(std::mem::take(&mut self.path));
+    }
+}
+
+/// Whether `dir` looks like one created by [`LocalFile::create`]
+///
+/// Also accepts ones in the temp dir from servers without `--state-file`.
+fn is_session_dir(dir: &Path, pool: &DirPool) -> bool {
+    let parent = dir.parent();
+    (parent == Some(pool.root().as_path()) || parent == Some(std::env::temp_dir().as_path()))
+        && dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(SESSION_DIR_PREFIX))
+}
+
+/// Identifies the contents of a file on disk without reading it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileVersion {
+    inode: u64,
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileVersion {
+    fn new(metadata: &Metadata) -> io::Result<Self> {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Ok(Self {
+            inode,
+            modified: metadata.modified()?,
+            len: metadata.len(),
+        })
+    }
 }
 
 // public interface
 impl LocalFile {
-    pub async fn create(m: &msg::GetTextFromComponent) -> io::Result<Self> {
-        let tempdir = TempDir::new("ghost-text")?;
-        let mut path = PathBuf::from(tempdir.path());
-        path.set_file_name(get_filename(m));
-
-        let mut s = Self {
-            path,
-            _tempdir: tempdir,
-            last_edit: SystemTime::now(),
-            hash: [0; 32],
+    /// Fails to read the file back once it grows beyond `max_len` bytes
+    ///
+    /// The file's extension is guessed from the page unless one is given, and
+    /// its name starts with the session's label, if any.
+    pub async fn create(
+        m: &msg::GetTextFromComponent,
+        extension: Option<&str>,
+        label: Option<&str>,
+        max_len: usize,
+        newline: Newline,
+        pool: &DirPool,
+    ) -> io::Result<Self> {
+        let permit = pool.permit()?;
+        let dir = SessionDir {
+            path: pool.take(SESSION_DIR_PREFIX)?,
+            pool: pool.clone(),
+            _permit: permit,
+        };
+        let name = get_filename(m, extension);
+        let path = match label {
+            Some(label) => dir.path.join(format!("{label}-{name}")),
+            None => dir.path.join(name),
         };
 
+        fs::create_dir(scratch_dir(&path)).await?;
+        let mut s = Self::new(path, dir, max_len, newline);
+
         debug!("Creating file at: {:?}", s.path);
-        s.write(m).await?;
+        s.write(&m.text).await?;
 
         Ok(s)
     }
 
-    pub async fn get_current_contents(&mut self) -> io::Result<String> {
+    /// Take over a file created by a previous server
+    ///
+    /// Like a created file, it is deleted along with its directory when dropped.
+    pub async fn adopt(
+        path: PathBuf,
+        max_len: usize,
+        newline: Newline,
+        pool: &DirPool,
+    ) -> io::Result<Self> {
+        let dir = path
+            .parent()
+            .filter(|dir| is_session_dir(dir, pool))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{path:?} is not in a session directory"),
+                )
+            })?;
+        let dir = SessionDir {
+            path: dir.to_owned(),
+            pool: pool.clone(),
+            _permit: pool.permit()?,
+        };
+
+        // from a version without it
+        fs::create_dir_all(scratch_dir(&path)).await?;
+        let mut s = Self::new(path, dir, max_len, newline);
+
+        debug!("Adopting file at: {:?}", s.path);
+        s.read().await?;
+
+        Ok(s)
+    }
+
+    fn new(path: PathBuf, dir: SessionDir, max_len: usize, newline: Newline) -> Self {
+        Self {
+            path,
+            _dir: dir,
+            version: None,
+            text: String::new(),
+            hash: [0; 32],
+            synced: None,
+            max_len: max_len as u64,
+            history: None,
+            newline,
+        }
+    }
+
+    /// Save the previous version as `<file>.N` each time an update overwrites it
+    pub fn keep_history(&mut self) {
+        self.history.get_or_insert(0);
+    }
+
+    pub async fn get_current_contents(&mut self) -> io::Result<&str> {
         self.read().await
     }
 
-    pub async fn maybe_update(&mut self, m: &msg::GetTextFromComponent) -> io::Result<bool> {
-        if self.is_equivalent(m).await? {
+    /// Note that the browser has the current contents
+    pub fn mark_synced(&mut self) {
+        self.synced = Some(self.hash);
+    }
+
+    /// Whether the file still has the contents from [`mark_synced`](Self::mark_synced)
+    ///
+    /// Watch events for the server's own writes find the same contents.
+    pub async fn is_synced(&mut self) -> io::Result<bool> {
+        self.read().await?;
+        Ok(self.synced == Some(self.hash))
+    }
+
+    pub async fn maybe_update(&mut self, text: &str) -> io::Result<bool> {
+        if self.is_equivalent(text).await? {
             debug!("Remote copy is equivalent to local, ignoring update");
             return Ok(false);
         }
         debug!("Updating local copy");
-        self.write(m).await?;
+        if self.history.is_some() {
+            if let Err(e) = self.save_previous_version().await {
+                warn!("Unable to save previous version of {:?}: {e}", self.path);
+            }
+        }
+        self.write(text).await?;
 
         Ok(true)
     }
@@ -79,56 +246,270 @@ impl AsRef<Path> for LocalFile {
 }
 
 impl LocalFile {
-    async fn write(&mut self, m: &msg::GetTextFromComponent) -> io::Result<()> {
-        let mut f = File::create(&self).await?;
-        f.write_all(m.text.as_bytes()).await?;
-        f.write_all(&[b'\n']).await?;
+    async fn write(&mut self, text: &str) -> io::Result<()> {
+        let path = &self.path;
+        let append_newline = self.newline != Newline::Preserve;
+        let metadata = with_retries("write", || write_file(path, text, append_newline)).await?;
 
-        self.update_local_md(&mut f, &m.text).await?;
+        self.version = Some(FileVersion::new(&metadata)?);
+        // reuse the existing allocation where possible
+        self.text.clear();
+        self.text.push_str(text);
+        self.hash = calculate_hash(&text);
 
         Ok(())
     }
 
-    async fn read(&mut self) -> io::Result<String> {
-        let mut f = File::open(&self).await?;
-        let mut text = String::new();
-        f.read_to_string(&mut text).await?;
-        if text.ends_with('\n') {
-            text.pop();
+    /// Returns cached contents if the file hasn't changed since the last read or write
+    async fn read(&mut self) -> io::Result<&str> {
+        let (path, cached, max_len) = (&self.path, self.version, self.max_len);
+        let (version, text) = with_retries("read", || read_file(path, cached, max_len)).await?;
+        let Some(mut text) = text else {
+            debug!("File unchanged since last read, using cached contents");
+            return Ok(&self.text);
+        };
+
+        match self.newline {
+            Newline::Append if text.ends_with('\n') => {
+                text.pop();
+            }
+            Newline::Strip => text.truncate(text.trim_end_matches('\n').len()),
+            _ => {}
         }
-        self.update_local_md(&mut f, &text).await?;
-        Ok(text)
-    }
 
-    async fn update_local_md(&mut self, f: &mut File, text: &str) -> io::Result<()> {
-        self.last_edit = get_last_modification(f).await?;
+        self.version = Some(version);
         self.hash = calculate_hash(&text);
+        self.text = text;
+        Ok(&self.text)
+    }
+
+    /// Copy the file to the next unused `<file>.N`
+    async fn save_previous_version(&mut self) -> io::Result<()> {
+        let Some(n) = &mut self.history else {
+            return Ok(());
+        };
+        // an adopted file may already have some
+        let backup = loop {
+            *n += 1;
+            let mut backup = self.path.clone().into_os_string();
+            backup.push(format!(".{n}"));
+            let backup = PathBuf::from(backup);
+            if !fs::try_exists(&backup).await? {
+                break backup;
+            }
+        };
+        let path = &self.path;
+        with_retries("copy", || fs::copy(path, &backup)).await?;
+        debug!("Saved previous version to {backup:?}");
         Ok(())
     }
 
-    async fn is_equivalent(&self, m: &msg::GetTextFromComponent) -> io::Result<bool> {
-        let remote_hash = calculate_hash(&m.text);
-        Ok(
-            self.last_edit == get_last_modification(&mut File::open(&self).await?).await?
-                && remote_hash == self.hash,
-        )
+    async fn is_equivalent(&self, text: &str) -> io::Result<bool> {
+        let remote_hash = calculate_hash(&text);
+        let metadata = with_retries("stat", || fs::metadata(&self.path)).await?;
+        let version = FileVersion::new(&metadata)?;
+        Ok(self.version == Some(version) && remote_hash == self.hash)
     }
 }
 
-fn calculate_hash<T: AsRef<[u8]>>(t: &T) -> [u8; 32] {
-    let mut s = Sha256::new();
-    s.update(t);
-    s.finalize().try_into().expect("Sha256 output is 32 bytes")
+/// Replace the file's contents all at once
+///
+/// Writes to a temporary file next to it and renames that over the original,
+/// so the editor never reads a partial file and a crash leaves the old one.
+async fn write_file(path: &Path, text: &str, append_newline: bool) -> io::Result<Metadata> {
+    let temp = temp_path(path);
+    let mut f = File::create(&temp).await?;
+    f.write_all(text.as_bytes()).await?;
+    if append_newline {
+        f.write_all(b"\n").await?;
+    }
+    // make sure the write has finished before checking metadata
+    f.flush().await?;
+    f.sync_all().await?;
+    // renaming keeps the inode and modification time
+    let metadata = f.metadata().await?;
+    drop(f);
+
+    if let Err(e) = fs::rename(&temp, path).await {
+        let _ = fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    Ok(metadata)
+}
+
+/// Hidden file in the same directory that [`write_file`] writes to first
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Read the file unless it is still at the `cached` version
+///
+/// Stops reading past `max_len` bytes (plus a trailing newline) in case the
+/// file is still growing.
+async fn read_file(
+    path: &Path,
+    cached: Option<FileVersion>,
+    max_len: u64,
+) -> io::Result<(FileVersion, Option<String>)> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, TooLarge { max: max_len });
+
+    let f = File::open(path).await?;
+    let version = FileVersion::new(&f.metadata().await?)?;
+    if cached == Some(version) {
+        return Ok((version, None));
+    }
+    let limit = max_len.saturating_add(1);
+    if version.len > limit {
+        return Err(too_large());
+    }
+
+    let mut text = String::with_capacity(version.len as usize);
+    f.take(limit.saturating_add(1))
+        .read_to_string(&mut text)
+        .await?;
+    if text.len() as u64 > limit || (text.len() as u64 == limit && !text.ends_with('\n')) {
+        return Err(too_large());
+    }
+    Ok((version, Some(text)))
+}
+
+/// Text is larger than the configured limit
+#[derive(Debug)]
+pub struct TooLarge {
+    pub max: u64,
+}
+
+impl TooLarge {
+    /// Whether this is the root cause of `e`, directly or as an IO error
+    pub fn is_cause_of(e: &anyhow::Error) -> bool {
+        e.chain().any(|cause| {
+            cause.is::<Self>()
+                || cause
+                    .downcast_ref::<io::Error>()
+                    .and_then(io::Error::get_ref)
+                    .is_some_and(|inner| inner.is::<Self>())
+        })
+    }
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Text is larger than the limit of {} bytes", self.max)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Attempts made before giving up on a transient error
+const IO_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after
+const IO_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Retry `op` with backoff while it fails with errors that may go away on their own
+///
+/// Antivirus scanners and editors holding exclusive locks can briefly block
+/// access to the file, and some editors replace it while saving.
+async fn with_retries<T, F, Fut>(what: &str, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut backoff = IO_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < IO_ATTEMPTS && is_transient(&e) => {
+                warn!("Unable to {what} file, retrying in {backoff:?}: {e}");
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    if matches!(e.raw_os_error(), Some(32 | 33)) {
+        return true;
+    }
+
+    matches!(
+        e.kind(),
+        NotFound | PermissionDenied | ResourceBusy | WouldBlock | Interrupted | TimedOut
+    )
+}
+
+/// Resolves once the file no longer exists
+pub async fn wait_for_delete(path: &Path) {
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    debug!("Waiting for {path:?} to be deleted");
+    while fs::try_exists(path).await.unwrap_or(true) {
+        sleep(POLL_INTERVAL).await;
+    }
+    debug!("{path:?} was deleted");
+}
+
+/// Resolves once an editor has held the file open and closed it (Windows only)
+///
+/// For editors that keep the file open exclusively rather than making their
+/// launcher wait. Gives up if none opens it within a few seconds.
+#[cfg(windows)]
+pub async fn wait_for_unlock(path: &Path) {
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+    const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    debug!("Waiting for {path:?} to be locked");
+    let start = tokio::time::Instant::now();
+    while !is_locked(path) {
+        if start.elapsed() > OPEN_TIMEOUT {
+            warn!("No editor opened {path:?} within {OPEN_TIMEOUT:?}, ending the session");
+            return;
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    debug!("Waiting for {path:?} to be unlocked");
+    while is_locked(path) {
+        sleep(POLL_INTERVAL).await;
+    }
+    debug!("{path:?} was unlocked");
+}
+
+/// Whether another process has the file open without sharing it
+#[cfg(windows)]
+fn is_locked(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // sharing nothing fails while anyone else has the file open
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(0)
+        .open(path)
+    {
+        Ok(_) => false,
+        // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+        Err(e) => matches!(e.raw_os_error(), Some(32 | 33)),
+    }
 }
 
-async fn get_last_modification(f: &mut File) -> io::Result<SystemTime> {
-    f.metadata().await.and_then(|m| m.modified())
+pub fn calculate_hash<T: AsRef<[u8]>>(t: &T) -> [u8; 32] {
+    let mut s = Sha256::new();
+    s.update(t);
+    s.finalize().into()
 }
 
-fn get_filename(msg: &msg::GetTextFromComponent) -> String {
-    const BAD_CHARS: &[char] = &[' ', '/', '\\', '\r', '\n', '\t'];
+pub fn get_filename(msg: &msg::GetTextFromComponent, extension: Option<&str>) -> String {
+    const BAD_CHARS: &[char] = &[' ', '/', '\\'];
 
-    let extension = determine_file_extension(msg);
+    let extension = extension.unwrap_or_else(|| determine_file_extension(msg));
 
     let mut title = msg.title.as_str();
 
@@ -140,34 +521,85 @@ fn get_filename(msg: &msg::GetTextFromComponent) -> String {
                 title = &title[..i];
             }
         }
-        title.replace(BAD_CHARS, "-")
+        title.replace(|c: char| BAD_CHARS.contains(&c) || c.is_control(), "-")
     } + "."
         + extension;
 
     file_name
 }
 
+/// File extension for pages on domains matching a pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionFor {
+    pub pattern: String,
+    pub extension: String,
+}
+
+impl FromStr for ExtensionFor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, extension)) = s.split_once('=') else {
+            bail!("Expected PATTERN=EXTENSION, got {s:?}");
+        };
+        if pattern.is_empty() {
+            bail!("Missing domain pattern in {s:?}");
+        }
+        check_extension(extension)?;
+
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            extension: extension.to_owned(),
+        })
+    }
+}
+
+/// Fail unless `extension` can go after the `.` of a file name
+pub fn check_extension(extension: &str) -> anyhow::Result<()> {
+    if extension.is_empty() {
+        bail!("Missing file extension");
+    }
+    if extension.starts_with('.')
+        || extension.contains(|c: char| matches!(c, '/' | '\\' | ' ') || c.is_control())
+    {
+        bail!("Invalid file extension {extension:?}, expected something like `md`");
+    }
+    Ok(())
+}
+
+/// The configured file extension for pages on `domain`, if any
+///
+/// From the domain's rule or the first matching `--extension-for`.
+pub fn extension_for<'a>(
+    options: &'a Settings,
+    rule: &'a Rule,
+    domain: Option<&str>,
+) -> Option<&'a str> {
+    if let Some(extension) = &rule.extension {
+        return Some(extension);
+    }
+    let extension_for = options.extension_for.iter().find(|extension_for| {
+        domain.is_some_and(|domain| glob::matches(&extension_for.pattern, domain))
+    })?;
+    debug!(
+        "Using extension {:?} for {domain:?}",
+        extension_for.extension
+    );
+    Some(&extension_for.extension)
+}
+
 fn determine_file_extension(msg: &msg::GetTextFromComponent) -> &str {
-    use url::{ParseError::RelativeUrlWithoutBase, Url};
     const MARKDOWN: &str = "md";
     const PLAINTEXT: &str = "txt";
     const DEFAULT: &str = PLAINTEXT;
 
-    let url = Url::parse(&msg.url);
-
-    let domain = match url {
-        Ok(ref url) => match url.host_str() {
-            Some(domain) => domain,
-            None => return DEFAULT,
-        },
-        // extension only sends the domain without scheme or path
-        // See <https://github.com/fregante/GhostText/issues/212>
-        // and <https://github.com/fregante/GhostText/blob/main/source/ghost-text.js#L160>
-        Err(RelativeUrlWithoutBase) => &msg.url,
-        Err(e) => {
-            warn!("Unable to parse url {:?}: {}", &msg.url, e);
-            return DEFAULT;
-        }
+    if let Some(extension) = syntax_extension(&msg.syntax) {
+        return extension;
+    }
+
+    let domain = match msg.domain() {
+        Some(domain) => domain,
+        None => return DEFAULT,
     };
 
     match &domain.split('.').collect::<Vec<_>>()[..] {
@@ -175,3 +607,415 @@ fn determine_file_extension(msg: &msg::GetTextFromComponent) -> &str {
         _ => DEFAULT,
     }
 }
+
+/// Extension for the page's reported syntax, if it's a known language
+///
+/// Editors in pages name their modes differently, e.g. `python`,
+/// `ace/mode/python`, or `text/x-python`.
+fn syntax_extension(syntax: &str) -> Option<&'static str> {
+    let syntax = syntax.trim().to_ascii_lowercase();
+    let name = syntax.rsplit('/').next().unwrap_or_default();
+    let name = name.strip_prefix("x-").unwrap_or(name);
+
+    let extension = match name {
+        "markdown" | "gfm" | "md" => "md",
+        "python" | "py" => "py",
+        "javascript" | "js" | "jsx" | "ecmascript" => "js",
+        "typescript" | "ts" => "ts",
+        "tsx" => "tsx",
+        "json" => "json",
+        "html" | "htmlmixed" => "html",
+        "xml" => "xml",
+        "css" => "css",
+        "scss" => "scss",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "rust" | "rs" => "rs",
+        "go" | "golang" => "go",
+        "c" | "csrc" => "c",
+        "cpp" | "c++" | "c_cpp" | "c++src" => "cpp",
+        "csharp" | "c#" => "cs",
+        "java" => "java",
+        "kotlin" => "kt",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "sh" | "bash" | "shell" | "shellscript" => "sh",
+        "sql" => "sql",
+        "lua" => "lua",
+        "perl" => "pl",
+        "swift" => "swift",
+        "haskell" => "hs",
+        "latex" | "tex" | "stex" => "tex",
+        "diff" | "patch" => "diff",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn message(text: &str) -> msg::GetTextFromComponent {
+        msg::GetTextFromComponent {
+            selections: vec![],
+            syntax: String::new(),
+            text: text.to_owned(),
+            title: String::from("title"),
+            url: String::from("example.com"),
+            resume_token: None,
+        }
+    }
+
+    /// A session file for `text` with the default options
+    async fn create(text: &str) -> LocalFile {
+        LocalFile::create(
+            &message(text),
+            None,
+            None,
+            usize::MAX,
+            Newline::Append,
+            &DirPool::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_back_written_text() {
+        let mut file = create("hello").await;
+        assert_eq!("hello\n", fs::read_to_string(&file).await.unwrap());
+        assert_eq!("hello", file.get_current_contents().await.unwrap());
+    }
+
+    #[test_case(Newline::Append, "hello\n\n" => ("hello\n".to_owned(), "hello\n".to_owned()) ; "append")]
+    #[test_case(Newline::Preserve, "hello\n\n" => ("hello".to_owned(), "hello\n\n".to_owned()) ; "preserve")]
+    #[test_case(Newline::Strip, "hello\n\n" => ("hello\n".to_owned(), "hello".to_owned()) ; "strip")]
+    #[tokio::test]
+    async fn applies_newline_policy(newline: Newline, saved: &str) -> (String, String) {
+        let mut file = LocalFile::create(
+            &message("hello"),
+            None,
+            None,
+            usize::MAX,
+            newline,
+            &DirPool::default(),
+        )
+        .await
+        .unwrap();
+        let written = fs::read_to_string(&file).await.unwrap();
+
+        fs::write(&file, saved).await.unwrap();
+        let read = file.get_current_contents().await.unwrap().to_owned();
+        (written, read)
+    }
+
+    #[tokio::test]
+    async fn reads_external_changes() {
+        let mut file = create("hello").await;
+        assert_eq!("hello", file.get_current_contents().await.unwrap());
+
+        fs::write(&file, "hello world\n").await.unwrap();
+        assert_eq!("hello world", file.get_current_contents().await.unwrap());
+        assert!(!file.is_equivalent("hello").await.unwrap());
+        assert!(file.is_equivalent("hello world").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn keeps_numbered_history() {
+        let mut file = create("first").await;
+        file.keep_history();
+        let base = file.as_ref().to_owned();
+        let version = |n: u32| {
+            let mut path = base.clone().into_os_string();
+            path.push(format!(".{n}"));
+            std::fs::read_to_string(path).ok()
+        };
+
+        assert!(file.maybe_update("second").await.unwrap());
+        assert!(!file.maybe_update("second").await.unwrap());
+        assert!(file.maybe_update("third").await.unwrap());
+
+        assert_eq!(Some("first\n"), version(1).as_deref());
+        assert_eq!(Some("second\n"), version(2).as_deref());
+        assert_eq!(None, version(3));
+    }
+
+    #[tokio::test]
+    async fn removes_directory_on_drop() {
+        let file = create("hello").await;
+        let dir = file.as_ref().parent().unwrap().to_owned();
+        assert!(is_session_dir(&dir, &DirPool::default()));
+        let scratch = scratch_dir(file.as_ref());
+        assert!(scratch.is_dir());
+        std::fs::write(scratch.join("render.html"), "<p>hello</p>").unwrap();
+
+        drop(file);
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn limits_files_to_max_dirs() {
+        let pool = DirPool::new(0, 1);
+        let message = message("hello");
+        let create = || LocalFile::create(&message, None, None, usize::MAX, Newline::Append, &pool);
+        let file = create().await.unwrap();
+        assert!(create().await.is_err());
+
+        drop(file);
+        assert!(create().await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn replaces_file_on_write() {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut file = create("old").await;
+        let before = fs::metadata(&file).await.unwrap().ino();
+
+        assert!(file.maybe_update("new").await.unwrap());
+
+        assert_ne!(before, fs::metadata(&file).await.unwrap().ino());
+        assert_eq!("new\n", fs::read_to_string(&file).await.unwrap());
+        assert!(!fs::try_exists(temp_path(file.as_ref())).await.unwrap());
+        // the rename isn't mistaken for an external edit
+        assert_eq!("new", file.get_current_contents().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tracks_synced_contents() {
+        let mut file = create("hello").await;
+        assert!(!file.is_synced().await.unwrap());
+
+        file.mark_synced();
+        assert!(file.is_synced().await.unwrap());
+
+        // the server's own write
+        file.maybe_update("hi").await.unwrap();
+        file.mark_synced();
+        assert!(file.is_synced().await.unwrap());
+
+        // an edit that's then undone
+        fs::write(&file, "hi there\n").await.unwrap();
+        assert!(!file.is_synced().await.unwrap());
+        fs::write(&file, "hi\n").await.unwrap();
+        assert!(file.is_synced().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn adopts_session_files() {
+        let file = create("hello").await;
+        let path = file.as_ref().to_owned();
+        let dir = file._dir.path.clone();
+        std::mem::forget(file);
+
+        let mut adopted = LocalFile::adopt(path, usize::MAX, Newline::Append, &DirPool::default())
+            .await
+            .unwrap();
+        assert_eq!("hello", adopted.get_current_contents().await.unwrap());
+        drop(adopted);
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_adopt_other_files() {
+        let path = std::env::temp_dir().join("gtany-not-a-session.txt");
+        assert!(
+            LocalFile::adopt(path, usize::MAX, Newline::Append, &DirPool::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_read_large_files() {
+        let mut file = LocalFile::create(
+            &message("hello"),
+            None,
+            None,
+            5,
+            Newline::Append,
+            &DirPool::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!("hello", file.get_current_contents().await.unwrap());
+
+        fs::write(&file, "hello!").await.unwrap();
+        let e = file.get_current_contents().await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+
+    #[tokio::test]
+    async fn starts_name_with_label() {
+        let file = LocalFile::create(
+            &message("hello"),
+            Some("md"),
+            Some("work"),
+            usize::MAX,
+            Newline::Append,
+            &DirPool::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            Some("work-title.md"),
+            file.as_ref().file_name().and_then(|name| name.to_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_delete() {
+        let file = create("hello").await;
+        let path = file.as_ref().to_owned();
+
+        let waiting = tokio::spawn(async move { wait_for_delete(&path).await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        fs::remove_file(&file).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("noticed deletion")
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn detects_locked_files() {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        let dir = tempdir::TempDir::new("gtany-tests").unwrap();
+        let path = dir.path().join("locked.txt");
+        std::fs::write(&path, "hello").unwrap();
+        assert!(!is_locked(&path));
+
+        let editor = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .share_mode(0)
+            .open(&path)
+            .unwrap();
+        assert!(is_locked(&path));
+
+        drop(editor);
+        assert!(!is_locked(&path));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_errors() {
+        let mut attempts = 0;
+        let result = with_retries("test", || {
+            attempts += 1;
+            let result = match attempts {
+                1 => Err(io::ErrorKind::PermissionDenied.into()),
+                2 => Err(io::ErrorKind::ResourceBusy.into()),
+                _ => Ok(attempts),
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(3, result.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_persistent_errors() {
+        let mut attempts = 0;
+        let result: io::Result<()> = with_retries("test", || {
+            attempts += 1;
+            async { Err(io::ErrorKind::PermissionDenied.into()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(IO_ATTEMPTS, attempts);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_fast_on_other_errors() {
+        let mut attempts = 0;
+        let result: io::Result<()> = with_retries("test", || {
+            attempts += 1;
+            async { Err(io::ErrorKind::InvalidData.into()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(1, attempts);
+    }
+
+    #[test_case("" => "buffer.txt" ; "empty")]
+    #[test_case("a/b\\c d" => "a-b-c-d.txt" ; "path separators")]
+    #[test_case("nul\0bell\x07" => "nul-bell-.txt" ; "control characters")]
+    #[test_case("0123456789abcdefghij" => "0123456789abcdef.txt" ; "long ascii")]
+    #[test_case("ééééééééééééééééé" => "éééééééééééééééé.txt" ; "long multibyte")]
+    fn sanitizes_title(title: &str) -> String {
+        get_filename(
+            &msg::GetTextFromComponent {
+                title: title.to_owned(),
+                ..message("")
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn uses_given_extension() {
+        assert_eq!("title.rst", get_filename(&message(""), Some("rst")));
+    }
+
+    #[test]
+    fn parses_extension_for() {
+        assert_eq!(
+            ExtensionFor {
+                pattern: String::from("wiki.*"),
+                extension: String::from("rst"),
+            },
+            "wiki.*=rst".parse().unwrap()
+        );
+        assert!("rst".parse::<ExtensionFor>().is_err());
+        assert!("=rst".parse::<ExtensionFor>().is_err());
+        assert!("wiki.*=".parse::<ExtensionFor>().is_err());
+        assert!("wiki.*=.rst".parse::<ExtensionFor>().is_err());
+        assert!("wiki.*=../rst".parse::<ExtensionFor>().is_err());
+    }
+
+    #[test_case(Some("wiki.example.com"), None => Some("rst".to_owned()) ; "matching pattern")]
+    #[test_case(Some("db.example.com"), None => Some("sql".to_owned()) ; "later pattern")]
+    #[test_case(Some("example.com"), None => None ; "no match")]
+    #[test_case(None, None => None ; "unknown domain")]
+    #[test_case(Some("wiki.example.com"), Some("adoc") => Some("adoc".to_owned()) ; "rule first")]
+    fn picks_extension_for_domain(domain: Option<&str>, rule: Option<&str>) -> Option<String> {
+        let options: Settings = clap::Parser::parse_from([
+            "gtany",
+            "--editor",
+            "nvim",
+            "--extension-for",
+            "wiki.*=rst",
+            "--extension-for",
+            "*.example.com=sql",
+        ]);
+        let rule = Rule {
+            extension: rule.map(String::from),
+            ..Rule::default()
+        };
+        extension_for(&options, &rule, domain).map(String::from)
+    }
+
+    #[test_case("python", "example.com" => "py" ; "plain name")]
+    #[test_case("ace/mode/javascript", "example.com" => "js" ; "ace mode")]
+    #[test_case("text/x-rustsrc", "example.com" => "txt" ; "unknown mime type")]
+    #[test_case("text/x-python", "example.com" => "py" ; "mime type")]
+    #[test_case("GFM", "example.com" => "md" ; "uppercase")]
+    #[test_case("python", "github.com" => "py" ; "syntax before domain")]
+    #[test_case("", "github.com" => "md" ; "domain without syntax")]
+    #[test_case("plaintext", "github.com" => "md" ; "unknown syntax")]
+    fn picks_extension(syntax: &str, url: &str) -> String {
+        determine_file_extension(&msg::GetTextFromComponent {
+            syntax: syntax.to_owned(),
+            url: url.to_owned(),
+            ..message("")
+        })
+        .to_owned()
+    }
+}
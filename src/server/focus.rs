@@ -0,0 +1,111 @@
+//! Focusing the editor's window with `--focus`, since the browser keeps focus
+//! after GhostText is activated
+
+use std::path::Path;
+
+use tokio::{
+    process::Command,
+    time::{sleep, Duration, Instant},
+};
+
+use super::split_command;
+
+/// How long to keep trying while the editor's window doesn't exist yet
+const FOCUS_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Command for a built-in name, or `command` itself
+///
+/// The built-in commands find the window by the editor's process id.
+fn built_in(command: &str) -> &str {
+    match command {
+        "xdotool" => "xdotool search --sync --limit 1 --pid %p windowactivate",
+        "sway" => "swaymsg [pid=%p] focus",
+        "hyprland" => "hyprctl dispatch focuswindow pid:%p",
+        command => command,
+    }
+}
+
+/// Replace `%p` with the editor's process id and `%f` with the file's path
+fn expand(command: &str, pid: u32, file: &Path) -> anyhow::Result<Vec<String>> {
+    let file_arg = file
+        .to_str()
+        .expect("Internally created file paths should be safe UTF-8");
+    Ok(split_command(built_in(command))?
+        .into_iter()
+        .map(|piece| {
+            piece
+                .replace("%p", &pid.to_string())
+                .replace("%f", file_arg)
+        })
+        .collect())
+}
+
+/// Run `--focus` for the editor, retrying until it succeeds or the window
+/// didn't show up in time
+pub async fn focus(command: &str, pid: u32, file: &Path) {
+    let pieces = match expand(command, pid, file) {
+        Ok(pieces) => pieces,
+        Err(e) => {
+            warn!("Invalid focus command {command:?}: {e}");
+            return;
+        }
+    };
+    let Some((program, args)) = pieces.split_first() else {
+        warn!("Empty focus command");
+        return;
+    };
+
+    let deadline = Instant::now() + FOCUS_TIMEOUT;
+    loop {
+        let status = Command::new(program).args(args).kill_on_drop(true).status();
+        let status = tokio::time::timeout_at(deadline, status).await;
+        match status {
+            Ok(Ok(status)) if status.success() => {
+                debug!("Focused editor {pid} with {pieces:?}");
+                return;
+            }
+            Ok(Ok(status)) => debug!("Focus command {program:?} exited with {status}"),
+            Ok(Err(e)) => {
+                warn!("Unable to run focus command {program:?}: {e}");
+                return;
+            }
+            Err(_) => break,
+        }
+        if Instant::now() + RETRY_INTERVAL >= deadline {
+            break;
+        }
+        sleep(RETRY_INTERVAL).await;
+    }
+    debug!("Gave up focusing editor {pid} after {FOCUS_TIMEOUT:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_built_in_commands() {
+        let file = Path::new("/tmp/title.txt");
+        assert_eq!(
+            vec!["swaymsg", "[pid=42]", "focus"],
+            expand("sway", 42, file).unwrap()
+        );
+        assert_eq!(
+            vec!["wmctrl", "-a", "/tmp/title.txt"],
+            expand("wmctrl -a %f", 42, file).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn retries_until_focused() {
+        let dir = tempdir::TempDir::new("gtany-focus").unwrap();
+        let file = dir.path().join("tries");
+
+        // fails the first time, like before the window exists
+        let command = r#"sh -c 'echo %p >> "$0"; [ $(wc -l < "$0") -ge 2 ]' %f"#;
+        focus(command, 42, &file).await;
+        assert_eq!("42\n42\n", std::fs::read_to_string(&file).unwrap());
+    }
+}
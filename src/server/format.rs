@@ -0,0 +1,153 @@
+//! Run text through a formatter before sending it to the browser
+
+use std::{process::Stdio, str::FromStr};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    time::{timeout, Duration},
+};
+
+use super::{editor::SessionEnv, split_command};
+
+/// Longest a formatter or filter may run before the text is used as is
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command for files with an extension, parsed from `EXT=COMMAND`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Formatter {
+    pub extension: String,
+    pub command: Vec<String>,
+}
+
+impl FromStr for Formatter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((extension, command)) = s.split_once('=') else {
+            bail!("Expected EXT=COMMAND, got {s:?}");
+        };
+        let extension = extension.trim_start_matches('.');
+        if extension.is_empty() {
+            bail!("Missing file extension in {s:?}");
+        }
+        let command = split_command(command)?;
+        if command.is_empty() {
+            bail!("Missing formatter command in {s:?}");
+        }
+
+        Ok(Self {
+            extension: extension.to_owned(),
+            command,
+        })
+    }
+}
+
+impl Formatter {
+    /// Pipe `text` through the command, returning its output
+    pub async fn format(&self, text: &str, env: Option<SessionEnv<'_>>) -> anyhow::Result<String> {
+        pipe("Formatter", &self.command, text, env).await
+    }
+}
+
+/// Pipe `text` through `command`, returning its output without the final newline
+///
+/// `kind` names the command in errors, like `Formatter`. Like the editor, it
+/// gets the session's `env` if there is one.
+pub async fn pipe(
+    kind: &str,
+    command: &[String],
+    text: &str,
+    env: Option<SessionEnv<'_>>,
+) -> anyhow::Result<String> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("{kind} command is empty");
+    };
+    let mut command = Command::new(program);
+    if let Some(env) = env {
+        env.apply(&mut command);
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Unable to run {} {program:?}", kind.to_lowercase()))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = format!("{text}\n");
+    // write concurrently, the command may start writing before it has read everything
+    let write = async move {
+        match stdin.write_all(input.as_bytes()).await {
+            // exited without reading it all, its status tells why
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            Err(e) => Err(e),
+            Ok(()) => stdin.shutdown().await,
+        }
+    };
+    let run = async { tokio::try_join!(write, child.wait_with_output()) };
+    let (_, output) = timeout(FORMAT_TIMEOUT, run)
+        .await
+        .with_context(|| format!("{kind} {program:?} timed out"))?
+        .with_context(|| format!("Unable to run {} {program:?}", kind.to_lowercase()))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "{kind} {program:?} failed ({}): {}",
+            output.status,
+            stderr.trim()
+        );
+    }
+    let mut piped =
+        String::from_utf8(output.stdout).with_context(|| format!("{kind} output is not UTF-8"))?;
+    // like the file's trailing newline
+    if piped.ends_with('\n') {
+        piped.pop();
+    }
+    Ok(piped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_formatters() {
+        assert_eq!(
+            Formatter {
+                extension: String::from("md"),
+                command: vec![
+                    String::from("prettier"),
+                    String::from("--parser"),
+                    String::from("markdown")
+                ],
+            },
+            ".md=prettier --parser markdown".parse().unwrap()
+        );
+        assert!("prettier".parse::<Formatter>().is_err());
+        assert!("=prettier".parse::<Formatter>().is_err());
+        assert!("md=".parse::<Formatter>().is_err());
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn pipes_text_through_command() {
+        let formatter: Formatter = "txt=tr a-z A-Z".parse().unwrap();
+        assert_eq!(
+            "HELLO\nWORLD",
+            formatter.format("hello\nworld", None).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn reports_failures() {
+        let formatter: Formatter = "txt=sh -c 'echo oops >&2; exit 3'".parse().unwrap();
+        let e = formatter.format("hello", None).await.unwrap_err();
+        assert!(format!("{e:#}").contains("oops"), "{e:#}");
+    }
+}
@@ -0,0 +1,50 @@
+//! Simple wildcard patterns for `--allow-origin` and rules
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+///
+/// Everything else is compared literally, so `http://localhost:*` matches
+/// `http://localhost:8000` but not `http://localhost.example.com`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("http://localhost:8000", "http://localhost:8000" => true ; "exact")]
+    #[test_case("http://localhost:8000", "http://localhost:8001" => false ; "different")]
+    #[test_case("http://localhost:8000", "http://localhost:80000" => false ; "longer")]
+    #[test_case("http://localhost:*", "http://localhost:8000" => true ; "any port")]
+    #[test_case("http://localhost:*", "http://localhost.example.com" => false ; "other host")]
+    #[test_case("*", "anything" => true ; "everything")]
+    #[test_case("safari-web-extension://*", "safari-web-extension://ABC-123" => true ; "prefix")]
+    #[test_case("*://example.com", "https://example.com" => true ; "suffix")]
+    #[test_case("*://example.com", "https://example.com.evil" => false ; "suffix mismatch")]
+    #[test_case("http://*.test:*", "http://a.test:1" => true ; "several wildcards")]
+    #[test_case("http://*.test:*", "http://a.b:1" => false ; "several wildcards mismatch")]
+    #[test_case("a*a", "a" => false ; "overlapping prefix and suffix")]
+    fn matches_patterns(pattern: &str, text: &str) -> bool {
+        matches(pattern, text)
+    }
+}
@@ -0,0 +1,315 @@
+//! Persist sessions so a restarted server can finish them
+//!
+//! The websocket doesn't survive a restart, but the editor and its file do.
+//! When the browser reconnects to the same page, the new session picks up the
+//! running editor instead of starting another one.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Context};
+use tokio::time::{sleep, Duration};
+
+use super::session::SessionId;
+
+/// How often to check if an editor process is still running
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What's needed to take over a session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub path: PathBuf,
+    pub url: String,
+    pub title: String,
+    /// Editor process id
+    pub pid: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Saved {
+    sessions: Vec<Record>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Sessions of this server with a running editor
+    active: BTreeMap<SessionId, Record>,
+    /// Sessions of a previous server waiting for the browser to reconnect
+    recovered: Vec<Record>,
+}
+
+/// Registry of sessions written to a state file on every change
+///
+/// Does nothing without a state file.
+#[derive(Debug, Clone, Default)]
+pub struct Handoff {
+    state_file: Option<Arc<Path>>,
+    inner: Arc<Mutex<Inner>>,
+    /// Set when the server stops with sessions left, to keep them in the file
+    handing_off: Arc<AtomicBool>,
+}
+
+/// Directory for the session directories of a server with `state_file`
+///
+/// Outside the temp dir, which may be cleared when the server restarts, e.g.
+/// with systemd's `PrivateTmp`.
+pub fn sessions_dir(state_file: &Path) -> PathBuf {
+    state_file.with_extension("sessions")
+}
+
+impl Handoff {
+    /// Load sessions left by a previous server whose editors are still running
+    ///
+    /// Sessions are kept in the file once `handing_off` is set.
+    pub fn load(state_file: Option<&Path>, handing_off: Arc<AtomicBool>) -> anyhow::Result<Self> {
+        let Some(state_file) = state_file else {
+            return Ok(Self::default());
+        };
+        if cfg!(not(unix)) {
+            bail!("--state-file isn't supported on this platform, it can't tell which editors are still running");
+        }
+
+        let saved: Saved = match fs::read(state_file) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Invalid state file {state_file:?}"))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Unable to read state file {state_file:?}"))
+            }
+        };
+
+        let mut recovered = Vec::new();
+        for record in saved.sessions {
+            if !record.path.exists() {
+                debug!("Dropping session for {:?}, file is gone", record.title);
+            } else if !is_running(record.pid) {
+                info!(
+                    "Editor for {:?} exited while the server was down, its text is at {:?}",
+                    record.title, record.path
+                );
+            } else {
+                info!(
+                    "Waiting for {:?} to reconnect to editor {}",
+                    record.title, record.pid
+                );
+                recovered.push(record);
+            }
+        }
+
+        let handoff = Self {
+            state_file: Some(state_file.into()),
+            inner: Arc::new(Mutex::new(Inner {
+                active: BTreeMap::new(),
+                recovered,
+            })),
+            handing_off,
+        };
+        handoff.save(&handoff.inner.lock().unwrap());
+
+        Ok(handoff)
+    }
+
+    /// Forget recovered sessions once their editors exit
+    pub async fn expire_recovered(self) {
+        loop {
+            sleep(POLL_INTERVAL).await;
+            let mut inner = self.inner.lock().unwrap();
+            let before = inner.recovered.len();
+            inner.recovered.retain(|record| {
+                let running = is_running(record.pid);
+                if !running {
+                    info!(
+                        "Editor for {:?} exited before the browser reconnected, its text is at {:?}",
+                        record.title, record.path
+                    );
+                }
+                running
+            });
+            if inner.recovered.len() != before {
+                self.save(&inner);
+            }
+        }
+    }
+
+    /// Take a recovered session for the same page, if there is one
+    pub fn claim(&self, url: &str, title: &str) -> Option<Record> {
+        let mut inner = self.inner.lock().unwrap();
+        let i = inner
+            .recovered
+            .iter()
+            .position(|r| r.url == url && r.title == title)?;
+        let record = inner.recovered.remove(i);
+        self.save(&inner);
+        Some(record)
+    }
+
+    /// Save a session until the returned guard is dropped
+    pub fn track(&self, id: SessionId, record: Record) -> HandoffGuard {
+        let mut inner = self.inner.lock().unwrap();
+        inner.active.insert(id, record);
+        self.save(&inner);
+
+        HandoffGuard {
+            id,
+            handoff: self.clone(),
+        }
+    }
+
+    fn save(&self, inner: &Inner) {
+        let Some(state_file) = &self.state_file else {
+            return;
+        };
+
+        let saved = Saved {
+            sessions: inner
+                .active
+                .values()
+                .chain(&inner.recovered)
+                .cloned()
+                .collect(),
+        };
+        if let Err(e) = write_atomically(state_file, &saved) {
+            warn!("Unable to write state file {state_file:?}: {e:#}");
+        }
+    }
+}
+
+/// Removes the session from the state file when dropped
+#[derive(Debug)]
+pub struct HandoffGuard {
+    id: SessionId,
+    handoff: Handoff,
+}
+
+impl Drop for HandoffGuard {
+    fn drop(&mut self) {
+        if self.handoff.handing_off.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut inner = self.handoff.inner.lock().unwrap();
+        inner.active.remove(&self.id);
+        self.handoff.save(&inner);
+    }
+}
+
+fn write_atomically(path: &Path, saved: &Saved) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(saved)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Resolves once the process exits
+pub async fn wait_for_exit(pid: u32) {
+    while is_running(pid) {
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to check, assume it exited
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn record(pid: u32) -> Record {
+        Record {
+            // any existing path
+            path: std::env::temp_dir(),
+            url: String::from("example.com"),
+            title: String::from("title"),
+            pid,
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn recovers_running_sessions() {
+        let dir = TempDir::new("gtany-handoff").unwrap();
+        let state_file = dir.path().join("state.json");
+
+        let first = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        let guard = first.track(0, record(std::process::id()));
+        // a crash leaves the state file behind
+        std::mem::forget(guard);
+
+        let second = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        assert_eq!(None, second.claim("example.com", "other title"));
+        assert_eq!(
+            Some(record(std::process::id())),
+            second.claim("example.com", "title")
+        );
+        assert_eq!(None, second.claim("example.com", "title"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skips_exited_editors() {
+        let dir = TempDir::new("gtany-handoff").unwrap();
+        let state_file = dir.path().join("state.json");
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        let first = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        std::mem::forget(first.track(0, record(pid)));
+
+        let second = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        assert_eq!(None, second.claim("example.com", "title"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn keeps_sessions_when_handing_off() {
+        let dir = TempDir::new("gtany-handoff").unwrap();
+        let state_file = dir.path().join("state.json");
+
+        let handing_off = Arc::new(AtomicBool::new(false));
+        let first = Handoff::load(Some(&state_file), handing_off.clone()).unwrap();
+        let guard = first.track(0, record(std::process::id()));
+        handing_off.store(true, Ordering::SeqCst);
+        drop(guard);
+
+        let second = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        assert!(second.claim("example.com", "title").is_some());
+        assert_eq!(dir.path().join("state.sessions"), sessions_dir(&state_file));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn forgets_finished_sessions() {
+        let dir = TempDir::new("gtany-handoff").unwrap();
+        let state_file = dir.path().join("state.json");
+
+        let first = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        drop(first.track(0, record(std::process::id())));
+
+        let second = Handoff::load(Some(&state_file), Arc::default()).unwrap();
+        assert_eq!(None, second.claim("example.com", "title"));
+    }
+}
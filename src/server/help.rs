@@ -0,0 +1,57 @@
+//! Explanations for people, rather than the extension, reaching the server
+
+/// Where to look when the extension isn't connecting
+pub const SETUP_DOCS: &str = "https://github.com/GhostText/GhostText#installation";
+
+/// Whether a request for the index comes from a browser tab
+///
+/// The extension fetches the redirect without asking for html, browsers
+/// navigating to a page always do.
+pub fn wants_html(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|media| media.split(';').next().unwrap_or("").trim() == "text/html")
+    })
+}
+
+/// Page shown when opening the server in a browser tab
+pub fn page(port: u16) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>GhostText-Any</title>
+</head>
+<body>
+<h1>GhostText-Any {version} is running</h1>
+<p>This server opens text fields from your browser in your editor.
+It is used through the GhostText browser extension, not by visiting this page.</p>
+<ol>
+<li>Install the extension: <a href="{SETUP_DOCS}">{SETUP_DOCS}</a></li>
+<li>Check that the extension's server port is set to {port}</li>
+<li>Click in a text field and activate GhostText from the toolbar button or its keyboard shortcut</li>
+</ol>
+<p>Run <code>gtany doctor</code> to check for common setup problems.</p>
+</body>
+</html>
+"#,
+        version = crate::version(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(None => false ; "missing")]
+    #[test_case(Some("*/*") => false ; "fetch")]
+    #[test_case(Some("application/json") => false ; "json")]
+    #[test_case(Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8") => true ; "firefox")]
+    #[test_case(Some("text/html;q=0.9, */*") => true ; "with parameters")]
+    fn detects_browser_tabs(accept: Option<&str>) -> bool {
+        wants_html(accept)
+    }
+}
@@ -0,0 +1,91 @@
+//! Commands run when sessions start and end, from `--on-start` and `--on-end`
+
+use anyhow::{bail, Context};
+use tokio::{
+    process::Command,
+    time::{timeout, Duration},
+};
+
+use super::{editor::SessionEnv, split_command};
+
+/// Longest a hook may run before the session continues without it
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `command` for the session's file, with `%f` replaced by its path
+///
+/// Like the editor, it gets the session's [`SessionEnv`].
+pub async fn run(command: &str, env: SessionEnv<'_>) -> anyhow::Result<()> {
+    let file = env.file;
+    let file_arg = file
+        .to_str()
+        .expect("Internally created file paths should be safe UTF-8");
+    let pieces: Vec<_> = split_command(command)?
+        .into_iter()
+        .map(|piece| piece.replace("%f", file_arg))
+        .collect();
+    let Some((program, args)) = pieces.split_first() else {
+        bail!("Empty hook command");
+    };
+    debug!("Running hook {pieces:?}");
+
+    let mut hook = Command::new(program);
+    env.apply(&mut hook);
+    let status = hook.args(args).kill_on_drop(true).status();
+    let status = timeout(HOOK_TIMEOUT, status)
+        .await
+        .with_context(|| format!("Hook {program:?} timed out"))?
+        .with_context(|| format!("Unable to run hook {program:?}"))?;
+    if !status.success() {
+        bail!("Hook {program:?} exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{msg, rules::Rule};
+
+    fn message() -> msg::GetTextFromComponent {
+        msg::GetTextFromComponent {
+            selections: vec![msg::RangeInText { start: 1, end: 1 }],
+            syntax: String::new(),
+            text: String::from("hi"),
+            title: String::from("title"),
+            url: String::from("example.com"),
+            resume_token: None,
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn runs_with_file_and_page() {
+        let dir = tempdir::TempDir::new("gtany-hooks").unwrap();
+        let file = dir.path().join("example.com.txt");
+        let rule = Rule {
+            label: Some(String::from("work")),
+            lang: Some(String::from("de-DE")),
+            env: [(String::from("TEAM"), String::from("docs"))].into(),
+            ..Rule::default()
+        };
+        let env = SessionEnv {
+            msg: &message(),
+            rule: &rule,
+            file: &file,
+        };
+
+        run(
+            r#"sh -c 'echo "$GHOST_TEXT_LABEL $GHOST_TEXT_LANG $TEAM $GHOST_TEXT_TITLE $GHOST_TEXT_URL $GHOST_TEXT_SELECTIONS" > "$0"' %f"#,
+            env,
+        )
+        .await
+        .unwrap();
+        let written = std::fs::read_to_string(&file).unwrap();
+        assert!(
+            written.starts_with("work de-DE docs title example.com [{"),
+            "{written:?}"
+        );
+
+        assert!(run("false", env).await.is_err());
+    }
+}
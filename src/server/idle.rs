@@ -0,0 +1,157 @@
+//! Idle timeout based on the number of active connections
+
+use std::{
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    sync::Notify,
+    time::{timeout, Duration},
+};
+
+#[derive(Debug, Default)]
+struct Inner {
+    active: AtomicUsize,
+    changed: Notify,
+}
+
+/// Counts connections that are holding an [`ActiveGuard`]
+#[derive(Debug, Default, Clone)]
+pub struct Activity(Arc<Inner>);
+
+impl Activity {
+    /// Mark a connection as active until the returned guard is dropped
+    pub fn start(&self) -> ActiveGuard {
+        self.0.active.fetch_add(1, Ordering::SeqCst);
+        self.0.changed.notify_waiters();
+        ActiveGuard(self.clone())
+    }
+
+    pub fn active(&self) -> usize {
+        self.0.active.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once there are no active connections
+    pub async fn until_idle(&self) {
+        loop {
+            let mut changed = pin!(self.0.changed.notified());
+            changed.as_mut().enable();
+
+            if self.active() == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Resolves once there have been no active connections for `duration`
+    pub async fn idle_timeout(&self, duration: Duration) {
+        loop {
+            // register for changes before checking the count so none are missed
+            let mut changed = pin!(self.0.changed.notified());
+            changed.as_mut().enable();
+
+            if self.active() > 0 {
+                changed.await;
+            } else if timeout(duration, changed).await.is_err() {
+                info!("Stopping after idle timeout of {} secs", duration.as_secs());
+                return;
+            }
+        }
+    }
+}
+
+/// Keeps a connection counted as active while alive
+#[derive(Debug)]
+pub struct ActiveGuard(Activity);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0 .0.active.fetch_sub(1, Ordering::SeqCst);
+        self.0 .0.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        task::{yield_now, JoinHandle},
+        time::{advance, Instant},
+    };
+
+    const IDLE: Duration = Duration::from_secs(10);
+
+    /// Start waiting for the timeout in the background
+    async fn spawn_timeout(activity: &Activity) -> JoinHandle<()> {
+        let activity = activity.clone();
+        let task = tokio::spawn(async move { activity.idle_timeout(IDLE).await });
+        // let the task start waiting
+        yield_now().await;
+        task
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_with_no_connections() {
+        let start = Instant::now();
+        Activity::default().idle_timeout(IDLE).await;
+        assert_eq!(IDLE, start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_guards_to_drop() {
+        let activity = Activity::default();
+        let a = activity.start();
+        let b = activity.start();
+
+        let task = spawn_timeout(&activity).await;
+
+        advance(IDLE * 3).await;
+        drop(a);
+        advance(IDLE * 3).await;
+        assert!(!task.is_finished());
+
+        drop(b);
+        assert_eq!(0, activity.active());
+        let start = Instant::now();
+        task.await.unwrap();
+        assert_eq!(IDLE, start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn waits_until_idle() {
+        let activity = Activity::default();
+        activity.until_idle().await;
+
+        let guard = activity.start();
+        let waiting = {
+            let activity = activity.clone();
+            tokio::spawn(async move { activity.until_idle().await })
+        };
+        yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(guard);
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn activity_resets_timer() {
+        let activity = Activity::default();
+        let task = spawn_timeout(&activity).await;
+
+        advance(IDLE / 2).await;
+        drop(activity.start());
+        yield_now().await;
+        advance(IDLE / 2).await;
+        assert!(!task.is_finished());
+
+        let start = Instant::now();
+        task.await.unwrap();
+        assert_eq!(IDLE / 2, start.elapsed());
+    }
+}
@@ -0,0 +1,152 @@
+//! Sessions for front ends other than the GhostText websocket, like `gtany native-host`
+//!
+//! Texts are edited with the same files, rules, and editors as the server's
+//! sessions, without a network listener. Saved texts go back through a
+//! [`Transport`].
+
+use futures::{future, pin_mut, FutureExt, StreamExt};
+use tokio::{sync::Notify, time::Duration};
+
+use super::{
+    check_text_size, editor,
+    file::{self, watch_edits, LocalFile},
+    msg,
+    rules::Rules,
+    DirPool,
+};
+use crate::debounce::MyStreamExt;
+use crate::settings::Settings;
+
+/// Wait after a file change for more before sending it
+const EDIT_DELAY: Duration = Duration::from_millis(200);
+
+/// Where a local session's text goes when the editor saves it
+///
+/// Implemented for closures, e.g. to collect the texts:
+///
+/// ```
+/// use gtany::server::Transport;
+///
+/// let mut texts = Vec::new();
+/// let mut transport = |text: &str| {
+///     texts.push(text.to_owned());
+///     anyhow::Ok(())
+/// };
+/// transport.send_text("hello").unwrap();
+/// assert_eq!(vec!["hello"], texts);
+/// ```
+pub trait Transport: Send {
+    /// Pass on the file's `text`, ending the session if it fails
+    fn send_text(&mut self, text: &str) -> anyhow::Result<()>;
+}
+
+impl<F> Transport for F
+where
+    F: FnMut(&str) -> anyhow::Result<()> + Send,
+{
+    fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+        self(text)
+    }
+}
+
+/// Opens texts in the editor, sharing rules and session directories between them
+///
+/// Each text gets its own editor, like with `--multi`.
+pub struct LocalSessions {
+    options: Settings,
+    rules: Rules,
+    dirs: DirPool,
+}
+
+impl LocalSessions {
+    pub fn new(options: &Settings) -> anyhow::Result<Self> {
+        Ok(Self {
+            options: options.clone(),
+            rules: Rules::load(options.rules.as_deref(), &options.domain_rule)?,
+            dirs: DirPool::new(options.dir_pool, options.max_dirs),
+        })
+    }
+
+    /// Edit `start`'s text until the editor exits, sending it whenever it's saved
+    ///
+    /// The final text is sent once the editor exits, if it changed since.
+    pub async fn edit(
+        &self,
+        start: &msg::GetTextFromComponent,
+        transport: &mut impl Transport,
+    ) -> anyhow::Result<()> {
+        let options = &self.options;
+        check_text_size(&start.text, options.max_text_size)?;
+        let domain = start.domain();
+        let rule = self.rules.resolve(domain.as_deref());
+        let mut file = LocalFile::create(
+            start,
+            file::extension_for(options, &rule, domain.as_deref()),
+            rule.label.as_deref(),
+            options.max_text_size,
+            rule.newline.unwrap_or(options.newline),
+            &self.dirs,
+        )
+        .await?;
+        file.mark_synced();
+        let path = file.as_ref().to_owned();
+
+        // never notified, the session ends with the editor
+        let close = Notify::new();
+        let editor =
+            editor::spawn_editor(options, &rule, &path, None, start, |_| (), &close).fuse();
+        let watched = if rule.read_only {
+            Ok(None)
+        } else {
+            watch_edits(&path, options).map(Some)
+        };
+        let edits = match watched {
+            Ok(Some(edits)) => edits.left_stream(),
+            Ok(None) => futures::stream::pending().right_stream(),
+            Err(e) => {
+                warn!("Unable to watch file, changes will be sent when the editor exits: {e:#}");
+                futures::stream::pending().right_stream()
+            }
+        };
+        let edits = edits
+            .scan((), |(), edit| {
+                if let Err(e) = &edit {
+                    warn!(
+                        "File watching failed, changes will be sent when the editor exits: {e:#}"
+                    );
+                }
+                future::ready(edit.ok())
+            })
+            .debounce(EDIT_DELAY)
+            .fuse();
+        pin_mut!(editor, edits);
+
+        loop {
+            futures::select! {
+                exit = editor => {
+                    if let Err(e) = exit {
+                        error!("Error creating editor process: {e:#}");
+                    }
+                    break;
+                },
+                () = edits.select_next_some() => send_changes(&mut file, transport).await?,
+            }
+        }
+
+        if !rule.read_only {
+            send_changes(&mut file, transport).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Send the file's text if it changed since it was last sent
+async fn send_changes(file: &mut LocalFile, transport: &mut impl Transport) -> anyhow::Result<()> {
+    if file.is_synced().await? {
+        return Ok(());
+    }
+    let text = file.get_current_contents().await?;
+    transport.send_text(text)?;
+    file.mark_synced();
+    Ok(())
+}
@@ -2,6 +2,15 @@
 //!
 //! See <https://github.com/fregante/GhostText/blob/d5273b134f88a96dd3a20bfeb09049bdbc5f8b70/PROTOCOL.md>
 
+use std::borrow::Cow;
+
+use serde::{Deserialize, Deserializer};
+
+use super::text::Selection;
+
+/// Version of the GhostText protocol implemented here
+pub const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 #[allow(non_snake_case)]
 pub struct RedirectToWebSocket {
@@ -9,10 +18,13 @@ pub struct RedirectToWebSocket {
     pub ProtocolVersion: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub struct SetTextInComponent<'a> {
     pub text: &'a str,
-    pub selections: Vec<RangeInText>,
+    pub selections: &'a [RangeInText],
+    /// Extension: pass back to continue the session from a new websocket
+    #[serde(rename = "resumeToken", skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<&'a str>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
@@ -21,11 +33,90 @@ pub struct RangeInText {
     pub end: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetTextFromComponent {
+    #[serde(default, deserialize_with = "nullable_selections")]
     pub selections: Vec<RangeInText>,
     pub syntax: String,
     pub text: String,
     pub title: String,
     pub url: String,
+    /// Extension: token of a session to continue instead of starting a new one
+    #[serde(
+        default,
+        rename = "resumeToken",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resume_token: Option<String>,
+}
+
+/// The parts of a [`GetTextFromComponent`] needed after the initial message
+///
+/// Borrows the text from the websocket message unless it contains escapes.
+#[derive(Debug, Deserialize)]
+pub struct UpdateTextFromComponent<'a> {
+    #[serde(default, deserialize_with = "nullable_selections")]
+    pub selections: Vec<RangeInText>,
+    #[serde(borrow)]
+    pub text: Cow<'a, str>,
+    /// Changes if the extension moves on to a field of another page
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// Some extension forks leave out the selections or send `null`, also in the list
+///
+/// Either puts the cursor at the start. Sessions warn once if the first
+/// message has none, instead of for every update.
+fn nullable_selections<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<RangeInText>, D::Error> {
+    let selections = Option::<Vec<Option<RangeInText>>>::deserialize(deserializer)?;
+    Ok(selections.into_iter().flatten().flatten().collect())
+}
+
+impl GetTextFromComponent {
+    /// Domain of the page the text is from, if it can be determined
+    pub fn domain(&self) -> Option<String> {
+        use url::{ParseError::RelativeUrlWithoutBase, Url};
+
+        match Url::parse(&self.url) {
+            Ok(url) => url.host_str().map(str::to_owned),
+            // extension only sends the domain without scheme or path
+            // See <https://github.com/fregante/GhostText/issues/212>
+            // and <https://github.com/fregante/GhostText/blob/main/source/ghost-text.js#L160>
+            Err(RelativeUrlWithoutBase) => Some(self.url.clone()),
+            Err(e) => {
+                warn!("Unable to parse url {:?}: {}", &self.url, e);
+                None
+            }
+        }
+    }
+    /// The selections in both the browser's and editors' coordinates
+    pub fn selections(&self) -> Vec<Selection> {
+        self.selections
+            .iter()
+            .map(|s| Selection::new(s.start, s.end, &self.text))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(r#""selections": [{ "start": 1, "end": 2 }],"# => 1 ; "present")]
+    #[test_case("" => 0 ; "missing")]
+    #[test_case(r#""selections": null,"# => 0 ; "null")]
+    #[test_case(r#""selections": [null, { "start": 1, "end": 2 }],"# => 1 ; "null selection")]
+    fn parses_selections(selections: &str) -> usize {
+        let json = format!(
+            r#"{{ {selections} "syntax": "", "text": "hi", "title": "", "url": "example.com" }}"#
+        );
+        let message: GetTextFromComponent = serde_json::from_str(&json).unwrap();
+        let update: UpdateTextFromComponent = serde_json::from_str(&json).unwrap();
+        assert_eq!(message.selections.len(), update.selections.len());
+        message.selections.len()
+    }
 }
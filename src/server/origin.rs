@@ -0,0 +1,124 @@
+//! Which pages and programs may open websockets
+//!
+//! If a Websocket request is sent by a browser, the origin will be set to:
+//! - `null`
+//! - the url of the initiating webpage
+//! - some form of `*-extension://*` if initiated by an extension
+//!
+//! Restricting it to extensions prevents random websites from trying to exfiltrate or exploit.
+//! See: <https://christian-schneider.net/CrossSiteWebSocketHijacking.html>.
+
+use url::Url;
+
+use super::glob;
+use crate::settings::Settings;
+
+/// Decides whether to accept a websocket based on its `Origin` header
+///
+/// Implemented for closures, e.g. to accept a development server on top of
+/// the default policy:
+///
+/// ```
+/// use gtany::server::{DefaultOriginPolicy, OriginPolicy};
+/// # use clap::Parser;
+/// # let options = gtany::settings::Settings::parse_from(["gtany", "--editor", "vim"]);
+///
+/// let default = DefaultOriginPolicy::new(&options);
+/// let policy = move |origin: Option<&str>| match origin {
+///     Some("http://localhost:3000") => Ok(()),
+///     _ => default.check(origin),
+/// };
+/// assert!(policy.check(Some("http://localhost:3000")).is_ok());
+/// assert!(policy.check(Some("https://example.com")).is_err());
+/// ```
+pub trait OriginPolicy: Send + Sync {
+    /// Accept or reject a websocket from `origin`, with the reason it was rejected
+    ///
+    /// `origin` is `None` if the request has no `Origin` header.
+    fn check(&self, origin: Option<&str>) -> Result<(), String>;
+}
+
+impl<F> OriginPolicy for F
+where
+    F: Fn(Option<&str>) -> Result<(), String> + Send + Sync,
+{
+    fn check(&self, origin: Option<&str>) -> Result<(), String> {
+        self(origin)
+    }
+}
+
+/// Accepts browser extensions, and origins allowed with `--allow-origin` and
+/// `--allow-null-origin`
+#[derive(Debug, Clone)]
+pub struct DefaultOriginPolicy {
+    allowed: Vec<String>,
+    allow_null: bool,
+}
+
+impl DefaultOriginPolicy {
+    pub fn new(options: &Settings) -> Self {
+        Self {
+            allowed: options.allow_origin.clone(),
+            allow_null: options.allow_null_origin,
+        }
+    }
+}
+
+impl OriginPolicy for DefaultOriginPolicy {
+    fn check(&self, origin: Option<&str>) -> Result<(), String> {
+        // Verify websocket is from extension context
+        let Some(origin) = origin else {
+            if self.allow_null {
+                return Ok(());
+            }
+            return Err(String::from("without an origin"));
+        };
+
+        if self.allow_null && origin == "null" {
+            return Ok(());
+        }
+        if let Some(pattern) = self.allowed.iter().find(|p| glob::matches(p, origin)) {
+            debug!("Allowing origin {origin:?} matching --allow-origin {pattern:?}");
+            return Ok(());
+        }
+
+        let origin =
+            Url::parse(origin).map_err(|e| format!("from unparseable origin: {origin:?}: {e}"))?;
+
+        if !origin.scheme().ends_with("extension") {
+            return Err(format!("from non-extension origin: {origin}"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn policy(allowed: &[&str], allow_null: bool) -> DefaultOriginPolicy {
+        DefaultOriginPolicy {
+            allowed: allowed.iter().map(|p| p.to_string()).collect(),
+            allow_null,
+        }
+    }
+
+    #[test_case(Some("moz-extension://1234") => true ; "firefox extension")]
+    #[test_case(Some("chrome-extension://abcd") => true ; "chrome extension")]
+    #[test_case(Some("https://example.com") => false ; "web page")]
+    #[test_case(Some("null") => false ; "null")]
+    #[test_case(None => false ; "missing")]
+    fn accepts_extensions(origin: Option<&str>) -> bool {
+        policy(&[], false).check(origin).is_ok()
+    }
+
+    #[test_case(Some("http://localhost:3000") => true ; "allowed")]
+    #[test_case(Some("https://localhost:3000") => false ; "other scheme")]
+    #[test_case(Some("null") => true ; "null")]
+    #[test_case(None => true ; "missing")]
+    fn accepts_allowed_origins(origin: Option<&str>) -> bool {
+        policy(&["http://localhost:*"], true).check(origin).is_ok()
+    }
+}
@@ -0,0 +1,250 @@
+//! Rendered Markdown previews of session files
+//!
+//! With `--preview`, each session's file is served as HTML at a URL with a
+//! random token until the session ends. The page polls for a new rendering, so
+//! it follows the file as it is saved.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd};
+use tokio::fs;
+use warp::{
+    http::StatusCode,
+    reply::{self, Reply, Response},
+    Filter, Rejection,
+};
+
+use super::resume::new_token;
+
+/// Files of running sessions by preview token
+#[derive(Debug, Clone)]
+pub struct Previews {
+    /// Where the server is reachable, e.g. `http://localhost:4001`
+    base: Arc<str>,
+    files: Arc<Mutex<BTreeMap<String, PathBuf>>>,
+}
+
+impl Previews {
+    pub fn new(base: String) -> Self {
+        Self {
+            base: base.into(),
+            files: Default::default(),
+        }
+    }
+
+    /// Serve a preview of `path` until the returned guard is dropped
+    pub fn register(&self, path: PathBuf) -> PreviewGuard {
+        let mut files = self.files.lock().unwrap();
+        let token = loop {
+            let token = new_token();
+            if !files.contains_key(&token) {
+                break token;
+            }
+        };
+        files.insert(token.clone(), path);
+
+        PreviewGuard {
+            token,
+            previews: self.clone(),
+        }
+    }
+
+    fn path(&self, token: &str) -> Option<PathBuf> {
+        self.files.lock().unwrap().get(token).cloned()
+    }
+
+    /// `/preview/<token>` and the rendered fragment it polls
+    pub fn routes(
+        self,
+        max_len: usize,
+    ) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        let previews = self;
+        let page = warp::path!("preview" / String)
+            .and(super::with_state(previews.clone()))
+            .and_then(|token: String, previews: Previews| async move {
+                match previews.path(&token) {
+                    Some(_) => Ok(reply::html(page(&token)).into_response()),
+                    None => Err(warp::reject::not_found()),
+                }
+            });
+        let body = warp::path!("preview" / String / "body")
+            .and(super::with_state(previews))
+            .and_then(move |token: String, previews: Previews| async move {
+                let Some(path) = previews.path(&token) else {
+                    return Err(warp::reject::not_found());
+                };
+                Ok(render_file(path, max_len).await)
+            });
+
+        warp::get().and(page.or(body).unify())
+    }
+}
+
+/// Stops serving the preview when dropped
+#[derive(Debug)]
+pub struct PreviewGuard {
+    token: String,
+    previews: Previews,
+}
+
+impl PreviewGuard {
+    pub fn url(&self) -> String {
+        format!("{}/preview/{}", self.previews.base, self.token)
+    }
+}
+
+impl Drop for PreviewGuard {
+    fn drop(&mut self) {
+        self.previews.files.lock().unwrap().remove(&self.token);
+    }
+}
+
+async fn render_file(path: PathBuf, max_len: usize) -> Response {
+    match fs::metadata(&path).await {
+        Ok(metadata) if metadata.len() > max_len as u64 + 1 => {
+            return reply::with_status(
+                format!("Text is larger than {max_len} bytes"),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            )
+            .into_response()
+        }
+        _ => {}
+    }
+    match fs::read_to_string(&path).await {
+        Ok(text) => reply::html(render(&text)).into_response(),
+        Err(e) => {
+            debug!("Unable to read {path:?} for preview: {e}");
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+/// Render Markdown to HTML, showing any raw HTML in it as text
+///
+/// The text comes from a web page, so it shouldn't run scripts on this origin.
+pub fn render(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let events = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Start(Tag::HtmlBlock) => Event::Start(Tag::CodeBlock(CodeBlockKind::Indented)),
+        Event::End(TagEnd::HtmlBlock) => Event::End(TagEnd::CodeBlock),
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        event => event,
+    });
+
+    let mut rendered = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut rendered, events);
+    rendered
+}
+
+/// Drop urls that run code when followed
+fn safe_url(url: CowStr) -> CowStr {
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme.trim());
+    match scheme {
+        Some(scheme)
+            if ["javascript", "vbscript", "data"]
+                .iter()
+                .any(|unsafe_scheme| scheme.eq_ignore_ascii_case(unsafe_scheme)) =>
+        {
+            CowStr::Borrowed("")
+        }
+        _ => url,
+    }
+}
+
+fn page(token: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>GhostText-Any Preview</title>
+<style>
+body {{ max-width: 50em; margin: 2em auto; padding: 0 1em; font-family: sans-serif; line-height: 1.5; }}
+pre {{ overflow-x: auto; }}
+table {{ border-collapse: collapse; }}
+td, th {{ border: 1px solid #999; padding: 0.2em 0.5em; }}
+</style>
+</head>
+<body>
+<main id="preview"></main>
+<script>
+const preview = document.getElementById("preview");
+let last = null;
+async function refresh() {{
+    const response = await fetch("/preview/{token}/body");
+    if (response.status === 404) {{
+        document.title += " (session ended)";
+        return;
+    }}
+    const rendered = await response.text();
+    if (rendered !== last) {{
+        preview.innerHTML = rendered;
+        last = rendered;
+    }}
+    setTimeout(refresh, 500);
+}}
+refresh().catch(() => {{ document.title += " (server stopped)"; }});
+</script>
+</body>
+</html>
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("# Title" => "<h1>Title</h1>\n" ; "heading")]
+    #[test_case("~~old~~ new" => "<p><del>old</del> new</p>\n" ; "strikethrough")]
+    #[test_case("<script>alert(1)</script>\n" => "<pre><code>&lt;script&gt;alert(1)&lt;/script&gt;\n</code></pre>\n" ; "html block")]
+    #[test_case("a <b>b</b>" => "<p>a &lt;b&gt;b&lt;/b&gt;</p>\n" ; "inline html")]
+    #[test_case("[x](javascript:alert(1))" => "<p><a href=\"\">x</a></p>\n" ; "script link")]
+    #[test_case("[x](https://example.com)" => "<p><a href=\"https://example.com\">x</a></p>\n" ; "web link")]
+    fn renders(markdown: &str) -> String {
+        render(markdown)
+    }
+
+    #[test]
+    fn forgets_dropped_previews() {
+        let previews = Previews::new(String::from("http://localhost:4001"));
+        let guard = previews.register(PathBuf::from("file.md"));
+        let token = guard.url().rsplit('/').next().unwrap().to_owned();
+
+        assert!(guard.url().starts_with("http://localhost:4001/preview/"));
+        assert_eq!(Some(PathBuf::from("file.md")), previews.path(&token));
+        drop(guard);
+        assert_eq!(None, previews.path(&token));
+    }
+}
@@ -0,0 +1,203 @@
+//! First-come, first-served access to the editor
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    sync::{AcquireError, OwnedSemaphorePermit, Semaphore},
+    time::{interval, Duration},
+};
+
+/// How often waiting sessions report their place in line
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct EditorQueue {
+    semaphore: Arc<Semaphore>,
+    next_ticket: AtomicU64,
+    /// Tickets of sessions waiting for a permit
+    waiting: Mutex<BTreeSet<u64>>,
+}
+
+impl EditorQueue {
+    pub fn new() -> Self {
+        Self::with_size(1)
+    }
+
+    /// Let up to `size` sessions use the editor at once
+    pub fn with_size(size: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(size)),
+            next_ticket: AtomicU64::new(0),
+            waiting: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Wait for a turn in order of arrival, periodically logging the place in line
+    pub async fn acquire(&self, label: &str) -> Result<OwnedSemaphorePermit, AcquireError> {
+        let ticket = Ticket::new(self);
+
+        let mut acquire = pin!(self.semaphore.clone().acquire_owned());
+        let mut report = interval(REPORT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                biased;
+                permit = &mut acquire => return permit,
+                _ = report.tick() => {
+                    info!("{label:?} is waiting behind {} other edit(s)", self.position(ticket.0));
+                }
+            }
+        }
+    }
+
+    /// Take a turn if the editor is free and no one is waiting
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if !self.waiting.lock().unwrap().is_empty() {
+            return None;
+        }
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+
+    /// Number of sessions ahead of the ticket, including the one in the editor
+    fn position(&self, ticket: u64) -> usize {
+        self.waiting.lock().unwrap().range(..ticket).count() + 1
+    }
+}
+
+/// Queues of named concurrency groups, created on first use
+#[derive(Debug, Default)]
+pub struct EditorGroups {
+    /// Sessions allowed at once per group, 1 if not listed, unlimited if 0
+    sizes: BTreeMap<String, usize>,
+    queues: Mutex<BTreeMap<String, Arc<EditorQueue>>>,
+}
+
+impl EditorGroups {
+    pub fn new(sizes: impl IntoIterator<Item = (String, usize)>) -> Self {
+        Self {
+            sizes: sizes.into_iter().collect(),
+            queues: Mutex::default(),
+        }
+    }
+
+    /// The queue shared by sessions in `group`, unless it's unlimited
+    pub fn queue(&self, group: &str) -> Option<Arc<EditorQueue>> {
+        let size = self.sizes.get(group).copied().unwrap_or(1);
+        if size == 0 {
+            return None;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues
+            .entry(group.to_owned())
+            .or_insert_with(|| Arc::new(EditorQueue::with_size(size)));
+        Some(queue.clone())
+    }
+}
+
+/// Place in line, given up when dropped
+struct Ticket<'a>(u64, &'a EditorQueue);
+
+impl<'a> Ticket<'a> {
+    fn new(queue: &'a EditorQueue) -> Self {
+        let ticket = queue.next_ticket.fetch_add(1, Ordering::Relaxed);
+        queue.waiting.lock().unwrap().insert(ticket);
+        Self(ticket, queue)
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        self.1.waiting.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use tokio::task::yield_now;
+
+    #[tokio::test]
+    async fn acquires_in_order() {
+        let queue = Arc::new(EditorQueue::new());
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let permit = queue.acquire("first").await.unwrap();
+
+        let mut tasks = Vec::new();
+        for i in 0..3 {
+            let (queue, order) = (queue.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = queue.acquire("waiting").await.unwrap();
+                order.lock().unwrap().push(i);
+            }));
+            yield_now().await;
+        }
+
+        drop(permit);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(vec![0, 1, 2], *order.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_leave_line() {
+        let queue = Arc::new(EditorQueue::new());
+        let _permit = queue.acquire("first").await.unwrap();
+
+        let spawn_waiter = || {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire("waiting").await.map(drop) })
+        };
+        let a = spawn_waiter();
+        yield_now().await;
+        let _b = spawn_waiter();
+        yield_now().await;
+
+        let last = queue.next_ticket.load(Ordering::Relaxed);
+        assert_eq!(3, queue.position(last));
+
+        a.abort();
+        let _ = a.await;
+        assert_eq!(2, queue.position(last));
+    }
+
+    #[tokio::test]
+    async fn tries_to_acquire() {
+        let queue = EditorQueue::new();
+
+        let permit = queue.try_acquire().unwrap();
+        assert!(queue.try_acquire().is_none());
+
+        drop(permit);
+        assert!(queue.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn shares_queues_within_groups() {
+        let groups = EditorGroups::new([(String::from("pair"), 2), (String::from("free"), 0)]);
+
+        let code = groups.queue("code").unwrap();
+        let _permit = code.acquire("first").await.unwrap();
+        assert_eq!(
+            0,
+            groups.queue("code").unwrap().semaphore.available_permits()
+        );
+
+        let pair = groups.queue("pair").unwrap();
+        let _first = pair.acquire("first").await.unwrap();
+        assert_eq!(1, pair.semaphore.available_permits());
+
+        assert!(groups.queue("free").is_none());
+    }
+}
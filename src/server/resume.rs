@@ -0,0 +1,144 @@
+//! Reattach a new websocket to a running session
+//!
+//! Each session gets a token that is sent to the browser with its updates. If
+//! the connection drops, e.g. when a laptop sleeps, the session keeps the editor
+//! open for a while, and a new websocket presenting the token takes its place.
+
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{stream::FusedStream, Stream};
+use tokio::sync::mpsc;
+
+/// Sessions waiting for, or able to accept, a new connection of type `C`
+#[derive(Debug)]
+pub struct Resumable<C>(Arc<Mutex<BTreeMap<String, mpsc::Sender<C>>>>);
+
+// derive requires `C: Clone`
+impl<C> Clone for Resumable<C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C> Default for Resumable<C> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<C> Resumable<C> {
+    /// Accept connections for a new token until the returned guard is dropped
+    pub fn register(&self) -> ResumeGuard<C> {
+        let (tx, rx) = mpsc::channel(1);
+        let mut sessions = self.0.lock().unwrap();
+        let token = loop {
+            let token = new_token();
+            if !sessions.contains_key(&token) {
+                break token;
+            }
+        };
+        sessions.insert(token.clone(), tx);
+
+        ResumeGuard {
+            token,
+            connections: rx,
+            resumable: self.clone(),
+        }
+    }
+
+    /// Hand a connection to the session with `token`
+    ///
+    /// Returns the connection if there is no such session, or it already has
+    /// another connection waiting.
+    pub fn resume(&self, token: &str, connection: C) -> Result<(), C> {
+        let sessions = self.0.lock().unwrap();
+        let Some(session) = sessions.get(token) else {
+            return Err(connection);
+        };
+        session.try_send(connection).map_err(|e| e.into_inner())
+    }
+}
+
+/// Receives connections for a session, unregistering its token when dropped
+#[derive(Debug)]
+pub struct ResumeGuard<C> {
+    token: String,
+    connections: mpsc::Receiver<C>,
+    resumable: Resumable<C>,
+}
+
+impl<C> ResumeGuard<C> {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+/// New connections with this session's token
+impl<C> Stream for ResumeGuard<C> {
+    type Item = C;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<C>> {
+        self.connections.poll_recv(cx)
+    }
+}
+
+impl<C> FusedStream for ResumeGuard<C> {
+    fn is_terminated(&self) -> bool {
+        // the sender lives in the registry until this guard is dropped
+        false
+    }
+}
+
+impl<C> Drop for ResumeGuard<C> {
+    fn drop(&mut self) {
+        self.resumable.0.lock().unwrap().remove(&self.token);
+    }
+}
+
+/// 128 random bits from the OS as hex
+pub fn new_token() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("The OS should provide random bytes");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn hands_over_connections() {
+        let resumable = Resumable::default();
+        let mut session = resumable.register();
+
+        assert_eq!(Ok(()), resumable.resume(session.token(), 1));
+        assert_eq!(Some(1), session.next().await);
+        assert_eq!(Err(2), resumable.resume("unknown", 2));
+    }
+
+    #[test]
+    fn forgets_dropped_sessions() {
+        let resumable = Resumable::default();
+        let session = resumable.register();
+        let token = session.token().to_owned();
+        drop(session);
+
+        assert_eq!(Err(1), resumable.resume(&token, 1));
+    }
+
+    #[test]
+    fn generates_distinct_tokens() {
+        let resumable = Resumable::<()>::default();
+        let first = resumable.register();
+        let second = resumable.register();
+
+        assert_eq!(32, first.token().len());
+        assert_ne!(first.token(), second.token());
+    }
+}
@@ -0,0 +1,340 @@
+//! Per-domain session options
+//!
+//! Rules are read from the JSON file passed with `--rules`, a list of objects
+//! with a `domain` pattern and the options for pages on matching domains:
+//!
+//! ```json
+//! [
+//!     { "domain": "*.wikipedia.org", "read_only": true }
+//! ]
+//! ```
+//!
+//! The first matching rule applies, so put catch-all patterns like `*` last.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+
+use super::{file::check_extension, glob};
+use crate::settings::Newline;
+
+/// Options for sessions from matching domains
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Domain pattern, where `*` matches any characters
+    pub domain: String,
+    /// Editor command instead of `--editor`
+    pub editor: Option<String>,
+    /// File extension instead of `--extension-for` or one guessed from the page
+    pub extension: Option<String>,
+    /// Open the editor in read-only mode where known, and never send the text back
+    #[serde(default)]
+    pub read_only: bool,
+    /// Extra environment variables for the editor, hooks and filters
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Directories added to the front of the editor's PATH, relative to the rules file
+    #[serde(default)]
+    pub path: Vec<PathBuf>,
+    /// File to start from when the page's text is empty, relative to the rules file
+    pub template: Option<PathBuf>,
+    /// Lines starting with this are removed from text started from the template
+    pub template_marker: Option<String>,
+    /// Name of a concurrency group, whose sessions wait for each other instead
+    /// of for sessions outside it
+    pub group: Option<String>,
+    /// What to do with the newline at the end of the text, instead of `--newline`
+    pub newline: Option<Newline>,
+    /// Name to organize sessions by, like `work`, shown in logs, `/status`,
+    /// and the file name
+    pub label: Option<String>,
+    /// Language of the text, like `de-DE`, for spell checking
+    pub lang: Option<String>,
+    /// Only send the text when the editor exits, instead of `--no-watch`
+    pub no_watch: Option<bool>,
+    /// Properties for the `.editorconfig` next to the file, e.g. `max_line_length`
+    #[serde(default)]
+    pub editorconfig: BTreeMap<String, serde_json::Value>,
+}
+
+impl Rule {
+    /// Editorconfig properties as written in the file
+    pub fn editorconfig_properties(&self) -> BTreeMap<String, String> {
+        self.editorconfig
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                (key.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Check the options that go in commands and file names
+    fn check(&self) -> anyhow::Result<()> {
+        if let Some(extension) = &self.extension {
+            check_extension(extension)?;
+        }
+        if let Some(lang) = &self.lang {
+            check_word("language", lang, "de-DE")?;
+        }
+        if let Some(label) = &self.label {
+            check_word("label", label, "work")?;
+        }
+        Ok(())
+    }
+}
+
+/// Languages are passed to editor commands and labels go in file names, so
+/// only allow letters, digits, `-`, and `_`
+fn check_word(what: &str, word: &str, example: &str) -> anyhow::Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_');
+    if word.is_empty() || !word.chars().all(valid) {
+        anyhow::bail!("Invalid {what} {word:?}, expected something like `{example}`");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rules(Arc<[Rule]>);
+
+/// The rules in the file at `path`, with paths relative to it
+fn read(path: &Path) -> anyhow::Result<Vec<Rule>> {
+    let bytes = fs::read(path).with_context(|| format!("Unable to read rules {path:?}"))?;
+    let mut rules: Vec<Rule> =
+        serde_json::from_slice(&bytes).with_context(|| format!("Invalid rules {path:?}"))?;
+    for rule in &rules {
+        rule.check()
+            .with_context(|| format!("Invalid rule for {:?} in {path:?}", rule.domain))?;
+    }
+    if let Some(dir) = path.parent() {
+        for rule in &mut rules {
+            let paths = rule.template.iter_mut().chain(&mut rule.path);
+            for path in paths {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+    debug!("Loaded {} rules from {path:?}", rules.len());
+    Ok(rules)
+}
+
+impl Rules {
+    /// Read the rules file, if there is one, followed by the `configured` JSON
+    /// rules from `--domain-rule`
+    pub fn load(path: Option<&Path>, configured: &[String]) -> anyhow::Result<Self> {
+        let mut rules = match path {
+            Some(path) => read(path)?,
+            None => Vec::new(),
+        };
+        for json in configured {
+            let rule: Rule = serde_json::from_str(json).context("Invalid rule in config")?;
+            rule.check()
+                .with_context(|| format!("Invalid rule for {:?} in config", rule.domain))?;
+            rules.push(rule);
+        }
+
+        Ok(Self(rules.into()))
+    }
+
+    /// The first rule matching `domain`, or the defaults
+    pub fn resolve(&self, domain: Option<&str>) -> Rule {
+        let Some(domain) = domain else {
+            return Rule::default();
+        };
+
+        match self
+            .0
+            .iter()
+            .find(|rule| glob::matches(&rule.domain, domain))
+        {
+            Some(rule) => {
+                debug!("Applying rule for {:?} to {domain:?}", rule.domain);
+                rule.clone()
+            }
+            None => Rule::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use test_case::test_case;
+
+    fn rules(json: &str) -> anyhow::Result<Rules> {
+        let dir = TempDir::new("gtany-rules").unwrap();
+        let path = dir.path().join("rules.json");
+        fs::write(&path, json).unwrap();
+        Rules::load(Some(&path), &[])
+    }
+
+    #[test]
+    fn uses_first_matching_rule() {
+        let rules = rules(
+            r#"[
+                { "domain": "*.wikipedia.org", "read_only": true },
+                { "domain": "*" }
+            ]"#,
+        )
+        .unwrap();
+
+        assert!(rules.resolve(Some("en.wikipedia.org")).read_only);
+        assert_eq!("*", rules.resolve(Some("github.com")).domain);
+        assert_eq!(Rule::default(), rules.resolve(None));
+    }
+
+    #[test]
+    fn applies_configured_rules_after_file() {
+        let dir = TempDir::new("gtany-rules").unwrap();
+        let path = dir.path().join("rules.json");
+        fs::write(&path, r#"[{ "domain": "github.com", "read_only": true }]"#).unwrap();
+        let configured = [
+            String::from(r#"{ "domain": "github.com", "editor": "code --wait" }"#),
+            String::from(r#"{ "domain": "*.wikipedia.org", "label": "wiki" }"#),
+        ];
+        let rules = Rules::load(Some(&path), &configured).unwrap();
+
+        let github = rules.resolve(Some("github.com"));
+        assert!(github.read_only);
+        assert_eq!(None, github.editor);
+        let wiki = rules.resolve(Some("en.wikipedia.org"));
+        assert_eq!(Some("wiki"), wiki.label.as_deref());
+
+        let invalid = [String::from(r#"{ "domain": "*", "label": "../wiki" }"#)];
+        assert!(Rules::load(None, &invalid).is_err());
+    }
+
+    #[test]
+    fn reads_editor() {
+        let rules = rules(r#"[{ "domain": "github.com", "editor": "code --wait" }]"#).unwrap();
+        assert_eq!(
+            Some("code --wait"),
+            rules.resolve(Some("github.com")).editor.as_deref()
+        );
+    }
+
+    #[test]
+    fn reads_env() {
+        let rules = rules(r#"[{ "domain": "*", "env": { "LANG": "de_DE.UTF-8" } }]"#).unwrap();
+        let rule = rules.resolve(Some("example.com"));
+        assert_eq!(
+            Some("de_DE.UTF-8"),
+            rule.env.get("LANG").map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn resolves_templates_next_to_rules() {
+        let dir = TempDir::new("gtany-rules").unwrap();
+        let path = dir.path().join("rules.json");
+        fs::write(
+            &path,
+            r#"[{ "domain": "*", "template": "issue.md" }, { "domain": "", "template": "/abs.md" }]"#,
+        )
+        .unwrap();
+        let rules = Rules::load(Some(&path), &[]).unwrap();
+
+        assert_eq!(
+            Some(dir.path().join("issue.md")),
+            rules.resolve(Some("github.com")).template
+        );
+        assert_eq!(Some(PathBuf::from("/abs.md")), rules.0[1].template);
+    }
+
+    #[test]
+    fn resolves_path_next_to_rules() {
+        let dir = TempDir::new("gtany-rules").unwrap();
+        let path = dir.path().join("rules.json");
+        fs::write(
+            &path,
+            r#"[{ "domain": "*", "path": ["bin", "/opt/node/bin"] }]"#,
+        )
+        .unwrap();
+        let rules = Rules::load(Some(&path), &[]).unwrap();
+
+        assert_eq!(
+            vec![dir.path().join("bin"), PathBuf::from("/opt/node/bin")],
+            rules.resolve(Some("github.com")).path
+        );
+    }
+
+    #[test]
+    fn reads_editorconfig_properties() {
+        let rules = rules(
+            r#"[{ "domain": "*", "editorconfig": { "max_line_length": 72, "indent_style": "tab" } }]"#,
+        )
+        .unwrap();
+        let properties = rules
+            .resolve(Some("lists.example.com"))
+            .editorconfig_properties();
+        assert_eq!(
+            Some("72"),
+            properties.get("max_line_length").map(String::as_str)
+        );
+        assert_eq!(
+            Some("tab"),
+            properties.get("indent_style").map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn reads_newline_policy() {
+        let rules = rules(r#"[{ "domain": "github.com", "newline": "preserve" }]"#).unwrap();
+        assert_eq!(
+            Some(Newline::Preserve),
+            rules.resolve(Some("github.com")).newline
+        );
+        assert_eq!(None, rules.resolve(Some("example.com")).newline);
+    }
+
+    #[test_case("label", |rule| rule.label, "work", "../work" ; "label")]
+    #[test_case("lang", |rule| rule.lang, "de-DE", "de' | !rm" ; "lang")]
+    fn reads_words(option: &str, get: fn(Rule) -> Option<String>, word: &str, invalid: &str) {
+        let with = |value: &str| rules(&format!(r#"[{{ "domain": "*", "{option}": "{value}" }}]"#));
+        let read = get(with(word).unwrap().resolve(Some("example.com")));
+        assert_eq!(Some(word), read.as_deref());
+        assert!(with(invalid).is_err());
+    }
+
+    #[test]
+    fn reads_no_watch() {
+        let rules = rules(r#"[{ "domain": "*.example.com", "no_watch": true }]"#).unwrap();
+        assert_eq!(Some(true), rules.resolve(Some("nfs.example.com")).no_watch);
+        assert_eq!(None, rules.resolve(Some("github.com")).no_watch);
+    }
+
+    #[test]
+    fn reads_extension() {
+        let rules = rules(r#"[{ "domain": "wiki.example.com", "extension": "rst" }]"#).unwrap();
+        assert_eq!(
+            Some("rst"),
+            rules.resolve(Some("wiki.example.com")).extension.as_deref()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_extension() {
+        assert!(rules(r#"[{ "domain": "*", "extension": "../rst" }]"#).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(rules(r#"[{ "domain": "*", "readonly": true }]"#).is_err());
+    }
+
+    #[test]
+    fn defaults_without_rules() {
+        let rules = Rules::load(None, &[]).unwrap();
+        assert_eq!(Rule::default(), rules.resolve(Some("github.com")));
+    }
+}
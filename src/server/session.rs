@@ -0,0 +1,403 @@
+//! Registry of active editing sessions
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use tokio::{
+    sync::{watch, Notify},
+    time::Duration,
+};
+
+use super::msg;
+use crate::debounce::Wait;
+
+pub type SessionId = u64;
+
+/// Publicly visible details of an active session
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub title: String,
+    pub url: String,
+    /// From the domain's rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Seconds since the unix epoch
+    pub started: u64,
+    /// Once it's created
+    pub file: Option<PathBuf>,
+    /// Of the latest editor started for the session
+    pub editor_pid: Option<u32>,
+    /// Problems that limit syncing without ending the session
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: SessionInfo,
+    kill: Arc<Notify>,
+    /// Token to resume the session while the browser is disconnected
+    detached: Option<String>,
+    /// Debounce of browser updates set with `gtany ctl set-delay`
+    delay: watch::Sender<Option<Duration>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Sessions {
+    next_id: Arc<AtomicU64>,
+    active: Arc<Mutex<BTreeMap<SessionId, Entry>>>,
+}
+
+impl Sessions {
+    /// Add a session to the registry until the returned guard is dropped
+    pub fn register(&self, msg: &msg::GetTextFromComponent) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let kill = Arc::new(Notify::new());
+        let (delay, delay_rx) = watch::channel(None);
+
+        let info = SessionInfo {
+            id,
+            title: msg.title.clone(),
+            url: msg.url.clone(),
+            label: None,
+            started: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            file: None,
+            editor_pid: None,
+            warnings: Vec::new(),
+        };
+
+        self.active.lock().unwrap().insert(
+            id,
+            Entry {
+                info,
+                kill: kill.clone(),
+                detached: None,
+                delay,
+            },
+        );
+
+        SessionGuard {
+            id,
+            kill,
+            delay: delay_rx,
+            sessions: self.clone(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|e| e.info.clone())
+            .collect()
+    }
+
+    /// Report the process id of the session's editor in [`Sessions::list`]
+    pub fn set_editor_pid(&self, id: SessionId, pid: u32) {
+        if let Some(entry) = self.active.lock().unwrap().get_mut(&id) {
+            entry.info.editor_pid = Some(pid);
+        }
+    }
+
+    /// A session for the same page that is waiting for the browser to resume it
+    ///
+    /// Returns its id and resume token.
+    pub fn find_detached(&self, url: &str, title: &str) -> Option<(SessionId, String)> {
+        self.active.lock().unwrap().values().find_map(|entry| {
+            let token = entry.detached.as_ref()?;
+            (entry.info.url == url && entry.info.title == title)
+                .then(|| (entry.info.id, token.clone()))
+        })
+    }
+
+    /// Change how long sessions wait for browser updates to stop before writing the file
+    ///
+    /// Applies to session `id`, or all active sessions. Returns the ids of the
+    /// changed sessions.
+    pub fn set_delay(&self, id: Option<SessionId>, delay: Duration) -> Vec<SessionId> {
+        let active = self.active.lock().unwrap();
+        active
+            .iter()
+            .filter(|(entry_id, _)| id.is_none_or(|id| id == **entry_id))
+            .map(|(entry_id, entry)| {
+                entry.delay.send_replace(Some(delay));
+                *entry_id
+            })
+            .collect()
+    }
+
+    /// Ask every session to close its editor and send its text
+    ///
+    /// Returns the number of active sessions.
+    pub fn finish_all(&self) -> usize {
+        let active = self.active.lock().unwrap();
+        for entry in active.values() {
+            entry.kill.notify_one();
+        }
+        active.len()
+    }
+
+    /// Ask a session to stop syncing and close its editor
+    ///
+    /// Returns false if no session with that id is active.
+    pub fn kill(&self, id: SessionId) -> bool {
+        match self.active.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Removes the session from the registry when dropped
+#[derive(Debug)]
+pub struct SessionGuard {
+    id: SessionId,
+    kill: Arc<Notify>,
+    delay: watch::Receiver<Option<Duration>>,
+    sessions: Sessions,
+}
+
+impl SessionGuard {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Report the session's file in [`Sessions::list`]
+    pub fn set_file(&self, path: &Path) {
+        if let Some(entry) = self.sessions.active.lock().unwrap().get_mut(&self.id) {
+            entry.info.file = Some(path.to_owned());
+        }
+    }
+
+    /// Report the session's label in [`Sessions::list`]
+    pub fn set_label(&self, label: &str) {
+        if let Some(entry) = self.sessions.active.lock().unwrap().get_mut(&self.id) {
+            entry.info.label = Some(label.to_owned());
+        }
+    }
+
+    /// Log a problem with the session and report it in [`Sessions::list`]
+    pub fn warn(&self, warning: String) {
+        warn!("Session {}: {}", self.id, warning);
+        if let Some(entry) = self.sessions.active.lock().unwrap().get_mut(&self.id) {
+            entry.info.warnings.push(warning);
+        }
+    }
+
+    /// Mark the session as waiting for the browser to resume it with `token`
+    pub fn detach(&self, token: &str) {
+        self.set_detached(Some(token.to_owned()));
+    }
+
+    /// The browser resumed the session
+    pub fn attach(&self) {
+        self.set_detached(None);
+    }
+
+    fn set_detached(&self, detached: Option<String>) {
+        if let Some(entry) = self.sessions.active.lock().unwrap().get_mut(&self.id) {
+            entry.detached = detached;
+        }
+    }
+
+    /// Debounce of browser updates, `default` until changed with [`Sessions::set_delay`]
+    ///
+    /// Without a default, it's [`adaptive_delay`] for the latest update's size.
+    pub fn delay(&self, default: Option<Duration>) -> Delay {
+        Delay {
+            default,
+            set: self.delay.clone(),
+            size: Arc::default(),
+        }
+    }
+
+    /// Resolves once [`Sessions::kill`] is called for this session
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+}
+
+/// A session's current debounce of browser updates
+#[derive(Debug, Clone)]
+pub struct Delay {
+    default: Option<Duration>,
+    set: watch::Receiver<Option<Duration>>,
+    /// Bytes of the latest update, shared by clones
+    size: Arc<AtomicUsize>,
+}
+
+impl Delay {
+    /// Adapt to an update of `size` bytes, if there's no fixed delay
+    pub fn observe(&self, size: usize) {
+        self.size.store(size, Ordering::Relaxed);
+    }
+}
+
+impl Wait for Delay {
+    fn wait(&self) -> Duration {
+        let fixed = *self.set.borrow();
+        fixed
+            .or(self.default)
+            .unwrap_or_else(|| adaptive_delay(self.size.load(Ordering::Relaxed)))
+    }
+}
+
+/// Debounce for updates of `size` bytes without `--delay`: 50ms plus 10ms per
+/// KB, up to 2s
+///
+/// Writing the file and reloading it in the editor takes longer the larger
+/// the text is, so batch more of those updates.
+pub fn adaptive_delay(size: usize) -> Duration {
+    const MIN: u64 = 50;
+    const MAX: u64 = 2000;
+    let per_kb = (size / 100).try_into().unwrap_or(u64::MAX);
+    Duration::from_millis(MIN.saturating_add(per_kb).min(MAX))
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> msg::GetTextFromComponent {
+        msg::GetTextFromComponent {
+            selections: vec![],
+            syntax: String::new(),
+            text: String::new(),
+            title: String::from("title"),
+            url: String::from("example.com"),
+            resume_token: None,
+        }
+    }
+
+    #[test]
+    fn guard_unregisters_on_drop() {
+        let sessions = Sessions::default();
+        let a = sessions.register(&message());
+        let b = sessions.register(&message());
+        assert_ne!(a.id(), b.id());
+        assert_eq!(2, sessions.list().len());
+
+        drop(a);
+        let active = sessions.list();
+        assert_eq!(1, active.len());
+        assert_eq!(b.id(), active[0].id);
+    }
+
+    #[test]
+    fn lists_warnings() {
+        let sessions = Sessions::default();
+        let guard = sessions.register(&message());
+        guard.warn(String::from("uh oh"));
+        assert_eq!(vec!["uh oh"], sessions.list()[0].warnings);
+    }
+
+    #[test]
+    fn lists_file_and_editor() {
+        let sessions = Sessions::default();
+        let guard = sessions.register(&message());
+        assert_eq!(None, sessions.list()[0].file);
+        assert!(sessions.list()[0].started > 0);
+
+        guard.set_file(Path::new("/tmp/title.txt"));
+        sessions.set_editor_pid(guard.id(), 42);
+        sessions.set_editor_pid(7, 43);
+        let info = &sessions.list()[0];
+        assert_eq!(Some(Path::new("/tmp/title.txt")), info.file.as_deref());
+        assert_eq!(Some(42), info.editor_pid);
+    }
+
+    #[test]
+    fn changes_delay() {
+        let sessions = Sessions::default();
+        let a = sessions.register(&message());
+        let b = sessions.register(&message());
+        let default = Duration::from_millis(500);
+        assert_eq!(default, a.delay(Some(default)).wait());
+
+        let changed = sessions.set_delay(Some(a.id()), Duration::from_secs(2));
+        assert_eq!(vec![a.id()], changed);
+        assert_eq!(Duration::from_secs(2), a.delay(Some(default)).wait());
+        assert_eq!(default, b.delay(Some(default)).wait());
+
+        let changed = sessions.set_delay(None, Duration::ZERO);
+        assert_eq!(vec![a.id(), b.id()], changed);
+        assert_eq!(Duration::ZERO, b.delay(Some(default)).wait());
+        assert!(sessions.set_delay(Some(42), Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn adapts_delay_to_size() {
+        assert_eq!(Duration::from_millis(50), adaptive_delay(0));
+        assert_eq!(Duration::from_millis(150), adaptive_delay(10_000));
+        assert_eq!(Duration::from_secs(2), adaptive_delay(usize::MAX));
+
+        let sessions = Sessions::default();
+        let a = sessions.register(&message());
+        let delay = a.delay(None);
+        delay.clone().observe(100_000);
+        assert_eq!(Duration::from_millis(1050), delay.wait());
+
+        sessions.set_delay(Some(a.id()), Duration::ZERO);
+        assert_eq!(Duration::ZERO, delay.wait());
+    }
+
+    #[test]
+    fn finds_detached_sessions() {
+        let sessions = Sessions::default();
+        let guard = sessions.register(&message());
+        assert_eq!(None, sessions.find_detached("example.com", "title"));
+
+        guard.detach("token");
+        assert_eq!(
+            Some((guard.id(), String::from("token"))),
+            sessions.find_detached("example.com", "title")
+        );
+        assert_eq!(None, sessions.find_detached("example.com", "other"));
+
+        guard.attach();
+        assert_eq!(None, sessions.find_detached("example.com", "title"));
+    }
+
+    #[tokio::test]
+    async fn kill_wakes_session() {
+        let sessions = Sessions::default();
+        let guard = sessions.register(&message());
+
+        assert!(sessions.kill(guard.id()));
+        guard.killed().await;
+
+        drop(guard);
+        assert!(!sessions.kill(0));
+    }
+
+    #[tokio::test]
+    async fn finishes_all_sessions() {
+        let sessions = Sessions::default();
+        let a = sessions.register(&message());
+        let b = sessions.register(&message());
+
+        assert_eq!(2, sessions.finish_all());
+        a.killed().await;
+        b.killed().await;
+    }
+}
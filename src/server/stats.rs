@@ -0,0 +1,223 @@
+//! Usage statistics aggregated per domain
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Key used for sessions whose domain can't be determined
+const UNKNOWN_DOMAIN: &str = "unknown";
+/// Key used for rejected requests without an `Origin` header
+const MISSING_ORIGIN: &str = "none";
+/// Key used for rejected origins once [`MAX_REJECTED_ORIGINS`] are tracked
+const OTHER_ORIGINS: &str = "other";
+/// Distinct rejected origins to count separately, so junk requests can't grow the map forever
+const MAX_REJECTED_ORIGINS: usize = 64;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DomainStats {
+    /// Number of completed sessions
+    pub sessions: u64,
+    /// Total time spent in completed sessions
+    pub seconds: f64,
+    /// Bytes written to the local file from the browser
+    pub bytes_received: u64,
+    /// Bytes sent back to the browser
+    pub bytes_sent: u64,
+    /// From receiving an update to writing it to the file, including the debounce
+    pub browser_to_file: Latency,
+    /// From the file changing to sending it to the browser, including the debounce
+    pub file_to_browser: Latency,
+}
+
+/// How long a stage of syncing took
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Latency {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Latency {
+    fn add(&mut self, duration: Duration) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        self.count += 1;
+        self.mean_ms += (ms - self.mean_ms) / self.count as f64;
+        self.max_ms = self.max_ms.max(ms);
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.0}ms mean, {:.0}ms max", self.mean_ms, self.max_ms)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Stats(Arc<Mutex<BTreeMap<String, DomainStats>>>);
+
+impl Stats {
+    pub fn add_session(&self, domain: Option<&str>, duration: Duration) {
+        self.update(domain, |s| {
+            s.sessions += 1;
+            s.seconds += duration.as_secs_f64();
+        });
+    }
+
+    pub fn add_received(&self, domain: Option<&str>, bytes: usize) {
+        self.update(domain, |s| s.bytes_received += bytes as u64);
+    }
+
+    pub fn add_sent(&self, domain: Option<&str>, bytes: usize) {
+        self.update(domain, |s| s.bytes_sent += bytes as u64);
+    }
+
+    pub fn add_browser_to_file(&self, domain: Option<&str>, duration: Duration) {
+        self.update(domain, |s| s.browser_to_file.add(duration));
+    }
+
+    pub fn add_file_to_browser(&self, domain: Option<&str>, duration: Duration) {
+        self.update(domain, |s| s.file_to_browser.add(duration));
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, DomainStats> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Log totals for each domain
+    pub fn log_summary(&self) {
+        let stats = self.snapshot();
+        if stats.is_empty() {
+            return;
+        }
+
+        info!("Usage summary:");
+        for (domain, s) in stats {
+            info!(
+                "  {domain}: {} sessions, {:.0} secs, {} bytes received, {} bytes sent",
+                s.sessions, s.seconds, s.bytes_received, s.bytes_sent
+            );
+            if s.browser_to_file.count > 0 {
+                info!("    browser to file: {}", s.browser_to_file);
+            }
+            if s.file_to_browser.count > 0 {
+                info!("    file to browser: {}", s.file_to_browser);
+            }
+        }
+    }
+
+    fn update(&self, domain: Option<&str>, f: impl FnOnce(&mut DomainStats)) {
+        let domain = domain.unwrap_or(UNKNOWN_DOMAIN);
+        f(self.0.lock().unwrap().entry(domain.to_owned()).or_default());
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RejectedOrigin {
+    /// Number of rejected requests
+    pub requests: u64,
+    /// Why the last one was rejected
+    pub reason: String,
+}
+
+/// Requests refused by the origin check, by origin
+#[derive(Debug, Default, Clone)]
+pub struct Rejections(Arc<Mutex<BTreeMap<String, RejectedOrigin>>>);
+
+impl Rejections {
+    /// Count a rejected request, returning how many there have been from its origin
+    pub fn add(&self, origin: Option<&str>, reason: &str) -> u64 {
+        let mut rejected = self.0.lock().unwrap();
+        let mut origin = origin.unwrap_or(MISSING_ORIGIN);
+        if !rejected.contains_key(origin) && rejected.len() >= MAX_REJECTED_ORIGINS {
+            origin = OTHER_ORIGINS;
+        }
+        let entry = rejected.entry(origin.to_owned()).or_default();
+        entry.requests += 1;
+        reason.clone_into(&mut entry.reason);
+        entry.requests
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, RejectedOrigin> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Log the rejected origins, if any
+    pub fn log_summary(&self) {
+        let rejected = self.snapshot();
+        if rejected.is_empty() {
+            return;
+        }
+
+        info!("Rejected origins:");
+        for (origin, r) in rejected {
+            info!("  {origin}: {} requests, last {}", r.requests, r.reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_rejected_origins() {
+        let rejections = Rejections::default();
+        assert_eq!(
+            1,
+            rejections.add(Some("https://example.com"), "from non-extension origin")
+        );
+        assert_eq!(2, rejections.add(Some("https://example.com"), "again"));
+        rejections.add(None, "without an origin");
+
+        let snapshot = rejections.snapshot();
+        assert_eq!(2, snapshot["https://example.com"].requests);
+        assert_eq!("again", snapshot["https://example.com"].reason);
+        assert_eq!(1, snapshot[MISSING_ORIGIN].requests);
+    }
+
+    #[test]
+    fn limits_rejected_origins() {
+        let rejections = Rejections::default();
+        for i in 0..MAX_REJECTED_ORIGINS + 3 {
+            rejections.add(Some(&format!("https://{i}.example.com")), "nope");
+        }
+
+        let snapshot = rejections.snapshot();
+        assert_eq!(MAX_REJECTED_ORIGINS + 1, snapshot.len());
+        assert_eq!(3, snapshot[OTHER_ORIGINS].requests);
+    }
+
+    #[test]
+    fn aggregates_per_domain() {
+        let stats = Stats::default();
+        stats.add_session(Some("github.com"), Duration::from_secs(2));
+        stats.add_session(Some("github.com"), Duration::from_secs(3));
+        stats.add_received(Some("github.com"), 10);
+        stats.add_sent(None, 4);
+
+        let snapshot = stats.snapshot();
+        let github = &snapshot["github.com"];
+        assert_eq!(2, github.sessions);
+        assert_eq!(5.0, github.seconds);
+        assert_eq!(10, github.bytes_received);
+        assert_eq!(4, snapshot[UNKNOWN_DOMAIN].bytes_sent);
+    }
+
+    #[test]
+    fn aggregates_latency() {
+        let stats = Stats::default();
+        stats.add_browser_to_file(Some("github.com"), Duration::from_millis(100));
+        stats.add_browser_to_file(Some("github.com"), Duration::from_millis(300));
+
+        let snapshot = stats.snapshot();
+        let latency = &snapshot["github.com"].browser_to_file;
+        assert_eq!(2, latency.count);
+        assert!((latency.mean_ms - 200.0).abs() < 1e-9);
+        assert!((latency.max_ms - 300.0).abs() < 1e-9);
+        assert_eq!(0, snapshot["github.com"].file_to_browser.count);
+        assert_eq!("200ms mean, 300ms max", latency.to_string());
+    }
+}
@@ -1,5 +1,23 @@
+use std::borrow::Cow;
+
 /// Convert the browser's 0-based UTF-16 offset to 1-based UTF-8 line/col cursor coordinates
 pub fn utf16_offset_to_utf8_line_col(offset: usize, text: &str) -> (usize, usize) {
+    utf16_offset_to_line_col(offset, text, char::len_utf8)
+}
+
+/// Convert the browser's 0-based UTF-16 offset to 1-based line and display column
+///
+/// For editors that place the cursor by screen cell, where wide characters
+/// like `汉` take two. Tabs count as one.
+pub fn utf16_offset_to_display_line_col(offset: usize, text: &str) -> (usize, usize) {
+    utf16_offset_to_line_col(offset, text, display_width)
+}
+
+fn utf16_offset_to_line_col(
+    offset: usize,
+    text: &str,
+    width: impl Fn(char) -> usize,
+) -> (usize, usize) {
     // - the ascii range (`0x00` - `0x7F`) counts the same (1:1)
     // - `0x0000` - `0xD7FF` is 1 code unit in UTF-16, but can be 1-3 in UTF-8
     // - `0x10000` - `0x10FFFF` is 2 code units in UTF-16, but 4 in UTF-8
@@ -7,7 +25,7 @@ pub fn utf16_offset_to_utf8_line_col(offset: usize, text: &str) -> (usize, usize
     // TODO: don't land in middle of graphemes?
 
     let mut line = 1;
-    let mut utf8_col = 1;
+    let mut col = 1;
     let mut utf16_offset = 0;
 
     for c in text.chars() {
@@ -19,18 +37,196 @@ pub fn utf16_offset_to_utf8_line_col(offset: usize, text: &str) -> (usize, usize
 
         if c == '\n' { // TODO: any unicode line break?
             line += 1;
-            utf8_col = 1;
+            col = 1;
         } else {
-            utf8_col += c.len_utf8();
+            col += width(c);
+        }
+    }
+
+    (line, col)
+}
+
+/// Terminal cells taken by a character
+///
+/// Approximates Unicode's East Asian Width: wide and fullwidth characters,
+/// like CJK ideographs, Hangul, and most emoji, take two cells, and
+/// combining marks and zero-width characters none.
+fn display_width(c: char) -> usize {
+    match u32::from(c) {
+        0x0300..=0x036F | 0x200B..=0x200F | 0x20D0..=0x20FF | 0xFE00..=0xFE0F | 0xFE20..=0xFE2F => {
+            0
+        }
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Convert the browser's 0-based UTF-16 offset to a 0-based UTF-8 byte offset
+///
+/// Offsets in the middle of a surrogate pair round down, offsets past the end
+/// are clamped to the text's length.
+pub fn utf16_offset_to_utf8_offset(offset: usize, text: &str) -> usize {
+    let mut utf16_offset = 0;
+    for (i, c) in text.char_indices() {
+        utf16_offset += c.len_utf16();
+        if utf16_offset > offset {
+            return i;
+        }
+    }
+    text.len()
+}
+
+/// A selection in both the browser's and editors' coordinates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Selection {
+    /// 0-based UTF-16 offsets, as sent by the browser
+    pub start: usize,
+    pub end: usize,
+    /// 0-based UTF-8 byte offsets
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// 1-based line and UTF-8 column
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Selection {
+    /// Convert the browser's UTF-16 `start` and `end` offsets in `text`
+    pub fn new(start: usize, end: usize, text: &str) -> Self {
+        let (start_line, start_column) = utf16_offset_to_utf8_line_col(start, text);
+        let (end_line, end_column) = utf16_offset_to_utf8_line_col(end, text);
+        Self {
+            start,
+            end,
+            start_byte: utf16_offset_to_utf8_offset(start, text),
+            end_byte: utf16_offset_to_utf8_offset(end, text),
+            start_line,
+            start_column,
+            end_line,
+            end_column,
         }
     }
+}
 
-    (line, utf8_col)
+/// The part of a text that changed, in the browser's UTF-16 offsets
+///
+/// Found by trimming the common start and end, so a single edit is exact
+/// and several are treated as one spanning them all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextChange {
+    /// Offset of the first changed character
+    start: usize,
+    /// End of the changed part in the old text
+    old_end: usize,
+    /// End of the changed part in the new text
+    new_end: usize,
+}
+
+impl TextChange {
+    pub fn new(old: &str, new: &str) -> Self {
+        let prefix: usize = old
+            .chars()
+            .zip(new.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        let (old_rest, new_rest) = (&old[prefix..], &new[prefix..]);
+        let suffix: usize = old_rest
+            .chars()
+            .rev()
+            .zip(new_rest.chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+
+        let utf16_len = |s: &str| s.chars().map(char::len_utf16).sum::<usize>();
+        let start = utf16_len(&old[..prefix]);
+        Self {
+            start,
+            old_end: start + utf16_len(&old_rest[..old_rest.len() - suffix]),
+            new_end: start + utf16_len(&new_rest[..new_rest.len() - suffix]),
+        }
+    }
+
+    /// Move an `offset` in the old text to the same place in the new one
+    ///
+    /// Offsets before the change stay, those after it move with the text,
+    /// and those in it, or where text was inserted, go to the end of the
+    /// new part.
+    pub fn remap(&self, offset: usize) -> usize {
+        if offset < self.start {
+            offset
+        } else if offset >= self.old_end {
+            offset - self.old_end + self.new_end
+        } else {
+            self.new_end
+        }
+    }
+}
+
+/// Remove lines starting with `marker`, like the instructions in a template
+pub fn strip_marked_lines<'a>(text: &'a str, marker: &str) -> Cow<'a, str> {
+    if marker.is_empty()
+        || !text
+            .split_inclusive('\n')
+            .any(|line| line.starts_with(marker))
+    {
+        return Cow::Borrowed(text);
+    }
+
+    text.split_inclusive('\n')
+        .filter(|line| !line.starts_with(marker))
+        .collect()
+}
+
+/// Whether `c` is an invisible formatting character that pages inject
+///
+/// Zero-width spaces and joiners used to break words, soft hyphens, byte order
+/// marks, and bidi controls. Zero-width (non-)joiners are kept, they shape
+/// emoji and some scripts.
+pub fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}' // soft hyphen
+            | '\u{061C}' // arabic letter mark
+            | '\u{200B}' // zero-width space
+            | '\u{200E}'..='\u{200F}' // left-to-right and right-to-left marks
+            | '\u{202A}'..='\u{202E}' // bidi embeddings and overrides
+            | '\u{2060}' // word joiner
+            | '\u{2066}'..='\u{2069}' // bidi isolates
+            | '\u{FEFF}' // zero-width no-break space
+    )
+}
+
+/// Remove [invisible](is_invisible) characters, returning how many there were
+pub fn strip_invisible(text: Cow<str>) -> (Cow<str>, usize) {
+    let removed = text.chars().filter(|&c| is_invisible(c)).count();
+    if removed == 0 {
+        return (text, 0);
+    }
+    let stripped = text.chars().filter(|&c| !is_invisible(c)).collect();
+    (Cow::Owned(stripped), removed)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use test_case::test_case;
 
     #[test_case("asdf hjkl", 0 => (1, 1)               ; "at text beginning")]
@@ -64,4 +260,93 @@ mod tests {
     fn offset_conversions(text: &str, offset: usize) -> (usize, usize) {
         utf16_offset_to_utf8_line_col(offset, text)
     }
+
+    #[test_case("asdf hjkl", 4 => (1, 5)   ; "ascii")]
+    #[test_case("汉字 hjkl", 2 => (1, 5)    ; "after wide characters")]
+    #[test_case("ｘ\n汉字", 3 => (2, 3)     ; "on another line")]
+    #[test_case("e\u{301}x", 2 => (1, 2)   ; "after combining mark")]
+    #[test_case("🙂x", 2 => (1, 3)         ; "after emoji")]
+    fn display_offset_conversions(text: &str, offset: usize) -> (usize, usize) {
+        utf16_offset_to_display_line_col(offset, text)
+    }
+
+    #[test_case("asdf", 2 => 2                     ; "ascii")]
+    #[test_case("àsdf", 1 => 2                     ; "after 2-byte UTF-8 sequence")]
+    #[test_case("a𐘗b", 2 => 1                      ; "in middle of UTF-16 surrogate pair")]
+    #[test_case("a𐘗b", 3 => 5                      ; "after UTF-16 surrogate pair")]
+    #[test_case("asdf", 10 => 4                    ; "past the end")]
+    fn byte_offset_conversions(text: &str, offset: usize) -> usize {
+        utf16_offset_to_utf8_offset(offset, text)
+    }
+
+    #[test_case("kept\n", "#" => "kept\n" ; "no markers")]
+    #[test_case("# hint\nkept\n# hint\n", "#" => "kept\n" ; "marked lines")]
+    #[test_case("kept\n# hint", "#" => "kept\n" ; "last line without newline")]
+    #[test_case("not # a hint\n", "#" => "not # a hint\n" ; "marker inside line")]
+    #[test_case("# kept\n", "" => "# kept\n" ; "empty marker")]
+    fn strips_marked_lines(text: &str, marker: &str) -> String {
+        strip_marked_lines(text, marker).into_owned()
+    }
+
+    #[test_case("plain text" => ("plain text".to_owned(), 0) ; "nothing to strip")]
+    #[test_case("zero\u{200B}width" => ("zerowidth".to_owned(), 1) ; "zero-width space")]
+    #[test_case("soft\u{00AD}hy\u{00AD}phen" => ("softhyphen".to_owned(), 2) ; "soft hyphens")]
+    #[test_case("\u{FEFF}\u{202E}bidi\u{202C}\u{2067}" => ("bidi".to_owned(), 4) ; "bom and bidi controls")]
+    #[test_case("👨\u{200D}👩\u{200D}👧" => ("👨\u{200D}👩\u{200D}👧".to_owned(), 0) ; "emoji joiners")]
+    fn strips_invisible(text: &str) -> (String, usize) {
+        let (text, removed) = strip_invisible(Cow::Borrowed(text));
+        (text.into_owned(), removed)
+    }
+
+    #[test_case("hello world", "hello world", 5 => 5 ; "unchanged")]
+    #[test_case("hello world", "hello there world", 8 => 14 ; "after insertion")]
+    #[test_case("hello world", "hello there world", 2 => 2 ; "before insertion")]
+    #[test_case("hello", "hello world", 5 => 11 ; "at insertion")]
+    #[test_case("hello cruel world", "hello world", 8 => 6 ; "in deletion")]
+    #[test_case("hello cruel world", "hello world", 15 => 9 ; "after deletion")]
+    #[test_case("a: 1", "b: 1", 4 => 4 ; "after replacement")]
+    #[test_case("😀 hi", "😀😀 hi", 5 => 7 ; "surrogate pairs")]
+    #[test_case("", "new text", 0 => 8 ; "from empty")]
+    fn remaps_offsets(old: &str, new: &str, offset: usize) -> usize {
+        TextChange::new(old, new).remap(offset)
+    }
+
+    proptest! {
+        #[test]
+        fn offset_conversion_is_monotonic(text in any::<String>(), a in 0..64usize, b in 0..64usize) {
+            let (a, b) = (a.min(b), a.max(b));
+            prop_assert!(
+                utf16_offset_to_utf8_line_col(a, &text) <= utf16_offset_to_utf8_line_col(b, &text)
+            );
+        }
+
+        #[test]
+        fn byte_offsets_agree_with_line_col(text in any::<String>(), offset in 0..64usize) {
+            let byte = utf16_offset_to_utf8_offset(offset, &text);
+            prop_assert!(text.is_char_boundary(byte), "offset {} splits a char", byte);
+
+            let before = &text[..byte];
+            let line = before.matches('\n').count() + 1;
+            let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            prop_assert_eq!((line, col), utf16_offset_to_utf8_line_col(offset, &text));
+        }
+
+        #[test]
+        fn remapped_offsets_stay_in_bounds(old in any::<String>(), new in any::<String>(), offset in 0..64usize) {
+            let offset = offset.min(old.encode_utf16().count());
+            let remapped = TextChange::new(&old, &new).remap(offset);
+            prop_assert!(remapped <= new.encode_utf16().count(), "offset {} out of bounds", remapped);
+        }
+
+        #[test]
+        fn offset_conversion_stays_in_bounds(text in any::<String>(), offset in any::<usize>()) {
+            let (line, col) = utf16_offset_to_utf8_line_col(offset, &text);
+
+            let line_text = text.split('\n').nth(line - 1);
+            prop_assert!(line_text.is_some(), "line {} out of bounds", line);
+            let line_text = line_text.unwrap();
+            prop_assert!(col >= 1 && col <= line_text.len() + 1, "col {} out of bounds", col);
+            prop_assert!(line_text.is_char_boundary(col - 1), "col {} splits a char", col);
+        }
+    }
 }
@@ -0,0 +1,128 @@
+//! System tray icon showing active sessions
+
+use std::sync::Arc;
+
+use ksni::{menu::StandardItem, MenuItem, ToolTip, TrayMethods};
+use tokio::{
+    sync::Notify,
+    time::{interval, Duration},
+};
+
+use super::session::{SessionInfo, Sessions};
+
+/// How often the icon is refreshed from the session registry
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Tray {
+    sessions: Sessions,
+    shutdown: Arc<Notify>,
+    active: Vec<SessionInfo>,
+}
+
+impl ksni::Tray for Tray {
+    fn id(&self) -> String {
+        env!("CARGO_PKG_NAME").into()
+    }
+
+    fn title(&self) -> String {
+        format!("GhostText-Any: {} active", self.active.len())
+    }
+
+    fn icon_name(&self) -> String {
+        if self.active.is_empty() {
+            "accessories-text-editor"
+        } else {
+            "document-edit"
+        }
+        .into()
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        ToolTip {
+            title: self.title(),
+            description: self
+                .active
+                .iter()
+                .map(|s| s.title.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ..Default::default()
+        }
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        let mut items: Vec<MenuItem<Self>> = self
+            .active
+            .iter()
+            .map(|s| {
+                let id = s.id;
+                StandardItem {
+                    label: format!("Kill \"{}\" ({})", s.title, s.url),
+                    icon_name: "process-stop".into(),
+                    activate: Box::new(move |this: &mut Self| {
+                        info!("Killing session {id} from tray");
+                        this.sessions.kill(id);
+                    }),
+                    ..Default::default()
+                }
+                .into()
+            })
+            .collect();
+
+        if !items.is_empty() {
+            items.push(MenuItem::Separator);
+        }
+
+        items.push(
+            StandardItem {
+                label: "Stop server".into(),
+                icon_name: "application-exit".into(),
+                activate: Box::new(|this: &mut Self| this.shutdown.notify_one()),
+                ..Default::default()
+            }
+            .into(),
+        );
+
+        items
+    }
+}
+
+/// Show the tray icon in the background, logging if it can't be registered
+pub fn spawn(sessions: Sessions, shutdown: Arc<Notify>) {
+    tokio::spawn(async move {
+        let tray = Tray {
+            sessions: sessions.clone(),
+            shutdown,
+            active: Vec::new(),
+        };
+
+        let handle = match tray.spawn().await {
+            Ok(handle) => handle,
+            Err(e) => {
+                error!("Unable to show tray icon: {e}");
+                return;
+            }
+        };
+
+        let mut refresh = interval(REFRESH_INTERVAL);
+        loop {
+            refresh.tick().await;
+            let active = sessions.list();
+            let changed = handle
+                .update(|tray| {
+                    let ids = |v: &[SessionInfo]| v.iter().map(|s| s.id).collect::<Vec<_>>();
+                    let changed = ids(&tray.active) != ids(&active);
+                    if changed {
+                        tray.active = active;
+                    }
+                    changed
+                })
+                .await;
+
+            if changed.is_none() {
+                debug!("Tray service stopped");
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,88 @@
+//! Listening on a Unix socket at a path, e.g. behind a reverse proxy
+
+use std::{
+    io,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+
+/// Removes the socket file when dropped
+#[derive(Debug)]
+pub struct SocketFile(PathBuf);
+
+impl SocketFile {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Unable to remove socket {:?}: {e}", self.0);
+        }
+    }
+}
+
+/// Listen on a new socket at `path`, replacing one left behind by a previous server
+pub fn bind(path: &Path) -> anyhow::Result<(UnixListenerStream, SocketFile)> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            bail!("{path:?} already exists and is not a socket");
+        }
+        Ok(_) => match UnixStream::connect(path) {
+            Ok(_) => bail!("{path:?} is in use by another server"),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("Removing stale socket {path:?}");
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Unable to remove stale socket {path:?}"))?;
+            }
+            Err(e) => debug!("Unable to check socket {path:?}: {e}"),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => debug!("Unable to check {path:?}: {e}"),
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Unable to listen on {path:?}"))?;
+    Ok((
+        UnixListenerStream::new(listener),
+        SocketFile(path.to_owned()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replaces_stale_sockets() {
+        let dir = tempdir::TempDir::new("gtany-socket").unwrap();
+        let path = dir.path().join("gtany.sock");
+
+        let (listener, socket) = bind(&path).unwrap();
+        assert!(bind(&path).is_err(), "bound a socket in use");
+
+        // left behind as if the server crashed
+        drop(listener);
+        std::mem::forget(socket);
+        let (_listener, socket) = bind(&path).unwrap();
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_other_files() {
+        let dir = tempdir::TempDir::new("gtany-socket").unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "important").unwrap();
+
+        assert!(bind(&path).is_err());
+        assert!(path.exists());
+    }
+}
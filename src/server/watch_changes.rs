@@ -1,39 +1,137 @@
-use std::path::Path;
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
+use anyhow::Context;
 use futures::{Stream, StreamExt};
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::settings::Settings;
 
 /// Returns a stream of update events for the provided file
-pub fn watch_edits(path: impl AsRef<Path>) -> anyhow::Result<impl Stream<Item = ()>> {
+///
+/// Watches the file's directory, so it keeps working after editors like vim
+/// save by writing a new file and renaming it over the old one. The file is
+/// also watched itself, and watched again once it's replaced, for backends
+/// that only report changes to the contents of watched files.
+///
+/// Ends with an error if watching fails later, e.g. when notify can't read
+/// its events, after which no more changes are reported.
+pub fn watch_edits(
+    path: impl AsRef<Path>,
+    options: &Settings,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<()>>> {
     let path = path.as_ref();
     use notify::Watcher;
 
-    let (mut watcher, rx) = async_watcher()?;
+    let name = path
+        .file_name()
+        .with_context(|| format!("{path:?} is not a file"))?
+        .to_owned();
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let shared = Arc::new(Shared::default());
+    let (mut watcher, rx) = async_watcher(name, options.watch_buffer.get(), shared.clone())?;
 
-    watcher.watch(path.as_ref(), notify::RecursiveMode::NonRecursive)?;
+    watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
 
     let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
 
-    Ok(NotifyWatcherStream {
-        _watcher: watcher,
+    let mut stream = NotifyWatcherStream {
+        watcher,
+        path: path.to_owned(),
         stream,
-    })
+        shared,
+        failed: false,
+    };
+    stream.watch_file();
+    Ok(stream)
+}
+
+/// State set from notify's thread
+#[derive(Debug, Default)]
+struct Shared {
+    /// Events not sent because the channel was full
+    dropped: AtomicU64,
+    /// Set when the file was created or renamed onto
+    replaced: AtomicBool,
+    /// The first error notify reported
+    error: Mutex<Option<notify::Error>>,
 }
 
 /// Wrapper to keep watcher alive with event stream handle
 struct NotifyWatcherStream {
-    _watcher: notify::RecommendedWatcher,
+    watcher: notify::RecommendedWatcher,
+    path: PathBuf,
     stream: tokio_stream::wrappers::ReceiverStream<()>,
+    shared: Arc<Shared>,
+    /// Set once the error was returned, ending the stream
+    failed: bool,
+}
+
+impl NotifyWatcherStream {
+    /// Watch the file itself, as well as its directory
+    ///
+    /// The directory's events are enough with most backends, so failing is fine.
+    fn watch_file(&mut self) {
+        use notify::Watcher;
+
+        // the watch on a replaced file may still be registered
+        let _ = self.watcher.unwatch(&self.path);
+        if let Err(e) = self
+            .watcher
+            .watch(&self.path, notify::RecursiveMode::NonRecursive)
+        {
+            debug!("Unable to watch {:?}: {e}", self.path);
+        }
+    }
+}
+
+impl Drop for NotifyWatcherStream {
+    fn drop(&mut self) {
+        match self.shared.dropped.load(Ordering::Relaxed) {
+            0 => debug!("No notify events dropped"),
+            dropped => info!(
+                "Dropped {dropped} file change events while --watch-buffer was full, \
+                raise it if saves were missed"
+            ),
+        }
+    }
 }
 
 impl Stream for NotifyWatcherStream {
-    type Item = ();
+    type Item = anyhow::Result<()>;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.stream.poll_next_unpin(cx)
+        use std::task::Poll;
+
+        if self.failed {
+            return Poll::Ready(None);
+        }
+        let event = self.stream.poll_next_unpin(cx);
+        // woken by an event sent with the error
+        let error = self.shared.error.lock().expect("not poisoned").take();
+        if let Some(e) = error {
+            self.failed = true;
+            let e = anyhow::Error::new(e).context(format!("Stopped watching {:?}", self.path));
+            return Poll::Ready(Some(Err(e)));
+        }
+        if event.is_ready() && self.shared.replaced.swap(false, Ordering::Relaxed) {
+            trace!("{:?} was replaced, watching it again", self.path);
+            self.watch_file();
+        }
+        event.map(|event| event.map(Ok))
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -41,27 +139,120 @@ impl Stream for NotifyWatcherStream {
     }
 }
 
-fn async_watcher() -> notify::Result<(notify::RecommendedWatcher, mpsc::Receiver<()>)> {
-    use notify::{Event, EventKind};
+/// File names are case-insensitive on Windows, where editors may save with a
+/// different case than the server created the file with
+fn is_same_name(a: &OsStr, b: &OsStr) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+/// Forward events for the file `name` from notify's thread without blocking it
+fn async_watcher(
+    name: OsString,
+    capacity: usize,
+    shared: Arc<Shared>,
+) -> notify::Result<(notify::RecommendedWatcher, mpsc::Receiver<()>)> {
+    let (tx, rx) = mpsc::channel(capacity);
+    let watcher = notify::recommended_watcher(move |res| forward(res, &name, &tx, &shared))?;
+    Ok((watcher, rx))
+}
 
-    let (tx, rx) = mpsc::channel(1);
-    let handle = tokio::runtime::Handle::current();
+/// Send an event to `tx` if `res` changed the file `name`
+///
+/// Events are interchangeable, so if the channel is full one is already
+/// pending and new ones can be dropped. `replaced` is set for events that
+/// put a new file at the path. Errors are kept in `shared` for the stream,
+/// with an event to wake it.
+fn forward(
+    res: notify::Result<notify::Event>,
+    name: &OsStr,
+    tx: &mpsc::Sender<()>,
+    shared: &Shared,
+) {
+    use notify::event::{ModifyKind, RenameMode};
+    use notify::EventKind;
 
-    let watcher = notify::recommended_watcher(move |res| match res {
-        Err(e) => debug!("Notify error: {e}"),
+    let (changed, new_file) = match res {
+        Err(e) => {
+            debug!("Notify error: {e}");
+            let mut error = shared.error.lock().expect("not poisoned");
+            error.get_or_insert(e);
+            (true, false)
+        }
         Ok(event) => {
             trace!("New notify event: {event:?}");
-            if let Event {
-                kind: EventKind::Modify(_),
-                ..
-            } = event
-            {
-                handle.block_on(async {
-                    tx.send(()).await.unwrap();
-                })
+            // other files in the directory include our own temporary one
+            let is_file = |path: &PathBuf| path.file_name().is_some_and(|n| is_same_name(n, name));
+            match event.kind {
+                // moved away, e.g. to a backup, before the new file is saved
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) => (false, false),
+                // from the old path to the new one
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                    let to = event.paths.last().is_some_and(is_file);
+                    (to, to)
+                }
+                EventKind::Modify(ModifyKind::Name(_)) | EventKind::Create(_) => {
+                    let any = event.paths.iter().any(is_file);
+                    (any, any)
+                }
+                EventKind::Modify(_) => (event.paths.iter().any(is_file), false),
+                _ => (false, false),
+            }
+        }
+    };
+    if new_file {
+        shared.replaced.store(true, Ordering::Relaxed);
+    }
+    if changed {
+        match tx.try_send(()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(())) => {
+                trace!("Dropping notify event, channel is full");
+                shared.dropped.fetch_add(1, Ordering::Relaxed);
             }
+            Err(TrySendError::Closed(())) => trace!("Notify event stream closed"),
         }
-    })?;
+    }
+}
 
-    Ok((watcher, rx))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{EventKind, ModifyKind};
+
+    #[tokio::test]
+    async fn ends_with_notify_error() {
+        let dir = tempdir::TempDir::new("gtany-watch").unwrap();
+        let path = dir.path().join("example.com.txt");
+        let name = path.file_name().unwrap().to_owned();
+        let shared = Arc::new(Shared::default());
+        let (tx, rx) = mpsc::channel(1);
+        let mut edits = NotifyWatcherStream {
+            watcher: notify::recommended_watcher(|_| {}).unwrap(),
+            path: path.clone(),
+            stream: tokio_stream::wrappers::ReceiverStream::new(rx),
+            shared: shared.clone(),
+            failed: false,
+        };
+
+        let modified = notify::Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path);
+        forward(Ok(modified.clone()), &name, &tx, &shared);
+        assert!(edits.next().await.unwrap().is_ok());
+
+        // reported even with the channel full
+        forward(Ok(modified), &name, &tx, &shared);
+        forward(
+            Err(notify::Error::generic("watch limit reached")),
+            &name,
+            &tx,
+            &shared,
+        );
+        forward(Err(notify::Error::generic("later")), &name, &tx, &shared);
+        let e = edits.next().await.unwrap().unwrap_err();
+        assert!(format!("{e:#}").contains("watch limit reached"), "{e:#}");
+        assert!(edits.next().await.is_none());
+    }
 }
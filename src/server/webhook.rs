@@ -0,0 +1,180 @@
+//! Notifications of session lifecycle events to a user-provided url
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Context};
+use hyper::{client::HttpConnector, header, Body, Client, Method, Request, Uri};
+use tokio::time::{timeout, Duration};
+use url::Url;
+
+use super::{events::Event, msg, text::Selection};
+
+/// Give up on delivering an event after this long
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: Client<HttpConnector>,
+    uri: Uri,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    url: &'a str,
+    title: &'a str,
+    selections: Vec<Selection>,
+    timestamp: u64,
+}
+
+impl Webhook {
+    pub fn new(url: Url) -> anyhow::Result<Self> {
+        if url.scheme() != "http" {
+            bail!("Only http:// webhook urls are supported: {url}");
+        }
+
+        let uri = url
+            .as_str()
+            .parse()
+            .with_context(|| format!("Invalid webhook url: {url}"))?;
+
+        Ok(Self {
+            client: Client::new(),
+            uri,
+        })
+    }
+
+    /// Send an event in the background, logging any failures
+    pub fn notify(&self, event: Event, msg: &msg::GetTextFromComponent) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let body = match serde_json::to_vec(&Payload {
+            event: &event,
+            url: &msg.url,
+            title: &msg.title,
+            selections: msg.selections(),
+            timestamp,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Unable to serialize webhook event: {e}");
+                return;
+            }
+        };
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Unable to build webhook request: {e}");
+                return;
+            }
+        };
+
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, client.request(request)).await {
+                Ok(Ok(response)) if response.status().is_success() => {
+                    debug!("Sent webhook event: {event:?}");
+                }
+                Ok(Ok(response)) => {
+                    warn!("Webhook returned status {}", response.status());
+                }
+                Ok(Err(e)) => warn!("Unable to send webhook event: {e}"),
+                Err(_) => warn!("Timed out sending webhook event"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    fn message() -> msg::GetTextFromComponent {
+        msg::GetTextFromComponent {
+            selections: vec![msg::RangeInText { start: 1, end: 2 }],
+            syntax: String::new(),
+            text: String::from("one"),
+            title: String::from("title"),
+            url: String::from("example.com"),
+            resume_token: None,
+        }
+    }
+
+    #[test]
+    fn serializes_event_with_page() {
+        let msg = message();
+        let payload = Payload {
+            event: &Event::Error {
+                error: String::from("boom"),
+            },
+            url: &msg.url,
+            title: &msg.title,
+            selections: msg.selections(),
+            timestamp: 1700000000,
+        };
+
+        assert_eq!(
+            serde_json::json!({
+                "event": "error",
+                "error": "boom",
+                "url": "example.com",
+                "title": "title",
+                "selections": [{
+                    "start": 1,
+                    "end": 2,
+                    "start_byte": 1,
+                    "end_byte": 2,
+                    "start_line": 1,
+                    "start_column": 2,
+                    "end_line": 1,
+                    "end_column": 3,
+                }],
+                "timestamp": 1700000000,
+            }),
+            serde_json::to_value(&payload).unwrap()
+        );
+    }
+
+    #[test_case(Event::Start => "start" ; "start")]
+    #[test_case(Event::End => "end" ; "end")]
+    #[test_case(Event::Detached => "detached" ; "detached")]
+    #[test_case(Event::Error { error: String::from("boom") } => "error" ; "error")]
+    #[tokio::test]
+    async fn posts_events(event: Event) -> String {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::header::exact("content-type", "application/json"))
+            .and(warp::body::json())
+            .map(move |payload: serde_json::Value| {
+                tx.send(payload).unwrap();
+                warp::reply()
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let webhook = Webhook::new(format!("http://{addr}/events").parse().unwrap()).unwrap();
+        webhook.notify(event, &message());
+
+        let payload = timeout(REQUEST_TIMEOUT, rx.recv()).await.unwrap().unwrap();
+        assert_eq!("example.com", payload["url"]);
+        payload["event"].as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(Webhook::new("https://example.com/events".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Running Windows editors from a server inside WSL
+//!
+//! The browser runs on Windows and connects to the server in WSL, which can
+//! start Windows programs through interop. They need the session file's
+//! Windows path, e.g. `\\wsl.localhost\Ubuntu\tmp\...`, and environment
+//! variables only reach them when listed in `WSLENV`.
+
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{bail, Context};
+use tokio::process::Command;
+
+/// Variables set for the editor that Windows programs should see too
+const FORWARDED_ENV: &[&str] = &[
+    "GHOST_TEXT_URL",
+    "GHOST_TEXT_TITLE",
+    "GHOST_TEXT_SELECTIONS",
+    "GHOST_TEXT_LANG",
+    "GHOST_TEXT_LABEL",
+    // translated to a Windows path
+    "GHOST_TEXT_SCRATCH/p",
+];
+
+/// Whether this process runs inside WSL
+pub fn is_wsl() -> bool {
+    std::env::var_os("WSL_DISTRO_NAME").is_some()
+        || fs::read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|release| release.to_ascii_lowercase().contains("microsoft"))
+}
+
+/// Whether `program` is a Windows executable, like `code.exe`
+pub fn is_windows_program(program: &str) -> bool {
+    Path::new(program)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("exe"))
+}
+
+/// Translate a path for Windows programs with `wslpath`
+pub async fn windows_path(path: &Path) -> anyhow::Result<String> {
+    let output = Command::new("wslpath")
+        .arg("-w")
+        .arg(path)
+        .output()
+        .await
+        .context("Unable to run wslpath")?;
+    if !output.status.success() {
+        bail!(
+            "wslpath failed for {path:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let translated = String::from_utf8(output.stdout).context("wslpath output is not UTF-8")?;
+    Ok(translated.trim_end().to_owned())
+}
+
+/// `WSLENV` with the editor's variables and the rule's `env` added to any already shared
+///
+/// `PATH` is left out, interop already translates it.
+pub fn forward_env<'a>(
+    existing: Option<OsString>,
+    rule_env: impl IntoIterator<Item = &'a str>,
+) -> OsString {
+    let mut wslenv = existing.unwrap_or_default();
+    let rule_env = rule_env.into_iter().filter(|name| *name != "PATH");
+    for name in FORWARDED_ENV.iter().copied().chain(rule_env) {
+        if !wslenv.is_empty() {
+            wslenv.push(":");
+        }
+        wslenv.push(name);
+    }
+    wslenv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("code.exe" => true)]
+    #[test_case("/mnt/c/Program Files/Notepad++/NOTEPAD++.EXE" => true ; "full path")]
+    #[test_case("code" => false)]
+    #[test_case("/usr/bin/vim" => false)]
+    fn detects_windows_programs(program: &str) -> bool {
+        is_windows_program(program)
+    }
+
+    #[test]
+    fn forwards_editor_env() {
+        assert_eq!(
+            "GHOST_TEXT_URL:GHOST_TEXT_TITLE:GHOST_TEXT_SELECTIONS:GHOST_TEXT_LANG:GHOST_TEXT_LABEL:GHOST_TEXT_SCRATCH/p",
+            forward_env(None, [])
+        );
+        assert_eq!(
+            "USERPROFILE/p:GHOST_TEXT_URL:GHOST_TEXT_TITLE:GHOST_TEXT_SELECTIONS:GHOST_TEXT_LANG:GHOST_TEXT_LABEL:GHOST_TEXT_SCRATCH/p",
+            forward_env(Some(OsString::from("USERPROFILE/p")), [])
+        );
+    }
+
+    #[test]
+    fn forwards_rule_env() {
+        assert!(forward_env(None, ["PATH", "NVIM_APPNAME"])
+            .to_str()
+            .unwrap()
+            .ends_with("GHOST_TEXT_SCRATCH/p:NVIM_APPNAME"));
+    }
+}
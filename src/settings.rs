@@ -1,15 +1,45 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use url::Url;
+
+use crate::{
+    fake_editor::Step,
+    server::{EditorFor, ExtensionFor, Formatter},
+};
 
 #[derive(Parser, Clone, Debug)]
 #[clap(author, about)]
 #[clap(version = crate::version())]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Settings {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
     /// Port to listen on
     #[clap(short, long, default_value = "4001")]
     pub port: u16,
+    /// Try up to <N> successive ports if `--port` is already in use
+    ///
+    /// The port that is actually bound is sent to the extension in the
+    /// websocket redirect, so multiple instances can share a host.
+    #[clap(long, name = "N", default_value = "0")]
+    pub port_fallback: u16,
     /// Host to bind to
+    ///
+    /// Either an IP address, with or without brackets for IPv6 (`::1` or
+    /// `[::1]`), or a hostname. If a hostname resolves to several addresses,
+    /// the first is used and the choice is logged.
     #[clap(long, default_value = "127.0.0.1")]
     pub host: String,
+    /// Listen on a Unix socket at <PATH> instead of `--host` and `--port`
+    ///
+    /// E.g. behind a reverse proxy, which must forward websocket upgrades.
+    /// `--port` must match the port the extension connects to, so the
+    /// websocket redirect points at it. A socket left behind by a previous
+    /// server is replaced, and the socket is removed when the server stops.
+    #[cfg(unix)]
+    #[clap(long, value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
     /// Command to run with the received file
     ///
     /// Defaults to the value of $EDITOR.
@@ -20,19 +50,470 @@ pub struct Settings {
     /// If %f, %l, or %c are present in the command, they will be replaced with
     /// the filename, cursor line, and cursor column, respectively. If none are
     /// present, the filename will be appended to the command.
-    #[clap(short, long, env)]
-    pub editor: String,
+    ///
+    /// The editor runs with GHOST_TEXT_URL and GHOST_TEXT_TITLE set to the
+    /// page's url and title, and GHOST_TEXT_SELECTIONS set to a JSON list of
+    /// selections with UTF-16 `start`/`end` offsets, as sent by the browser,
+    /// UTF-8 `start_byte`/`end_byte` offsets, and 1-based
+    /// `start_line`/`start_column`/`end_line`/`end_column`.
+    /// GHOST_TEXT_SCRATCH is a directory next to the file for the editor's
+    /// own files, like renders, removed when the session ends.
+    /// GHOST_TEXT_LANG and GHOST_TEXT_LABEL are the `lang` and `label` rule
+    /// options, if set.
+    ///
+    /// On Windows, quote paths with spaces with double quotes; backslashes
+    /// are kept as is.
+    ///
+    /// Only required when running the server.
+    #[clap(short, long, env, required = true)]
+    pub editor: Option<String>,
+    /// Use <COMMAND> as the editor for pages on domains matching <PATTERN>
+    ///
+    /// E.g. `github.com=code --wait`, with `--editor nvim` for everything
+    /// else. `*` in the pattern matches any characters. Can be repeated; the
+    /// first matching one applies. The `editor` rule option takes precedence.
+    #[clap(long, value_name = "PATTERN=COMMAND")]
+    pub editor_for: Vec<EditorFor>,
+    /// Editors whose cursor column is in screen cells, for `%c` and known editors
+    ///
+    /// Wide characters like 汉字 take two cells, and combining marks none.
+    /// Other editors get the column in UTF-8 bytes. Matched against the
+    /// lowercase program names in the editor command, without directories or
+    /// extensions.
+    #[clap(
+        long,
+        value_name = "PROGRAMS",
+        value_delimiter = ',',
+        default_value = "vi,vim,nvim,gvim"
+    )]
+    pub display_columns: Vec<String>,
+    /// Run terminal editors in a new window of <COMMAND> when the server has no terminal
+    ///
+    /// E.g. `alacritty -e` or `xterm -e`, followed by the editor command. The
+    /// terminal must stay open until the editor exits, so for
+    /// `gnome-terminal`, pass `gnome-terminal --wait --`. Without it,
+    /// sessions with terminal editors like `vim` fail when the server runs
+    /// as a service.
+    #[clap(long, value_name = "COMMAND")]
+    pub terminal: Option<String>,
+    /// Focus the editor's window with <COMMAND> after starting it
+    ///
+    /// `%p` is replaced with the editor's process id and `%f` with the file's
+    /// path. It's retried for up to 5 seconds until it succeeds, while the
+    /// window opens. `xdotool`, `sway`, and `hyprland` are built in, finding
+    /// the window by process id, e.g. `--focus sway`. Otherwise e.g.
+    /// `wmctrl -a %f` for editors that show the path in their title.
+    #[clap(long, value_name = "COMMAND")]
+    pub focus: Option<String>,
+    /// Run <COMMAND> when a session starts, before the editor opens the file
+    ///
+    /// `%f` is replaced with the file's path, and the GHOST_TEXT_* variables
+    /// and the rule's `env` are set like for the editor.
+    /// E.g. for notifications or focusing the editor's window. Sessions wait
+    /// for it for up to 10 seconds.
+    #[clap(long, value_name = "COMMAND")]
+    pub on_start: Option<String>,
+    /// Run <COMMAND> when a session ends, before the file is sent back
+    ///
+    /// Like `--on-start`, and it may change the file, e.g. to format it or
+    /// back it up.
+    #[clap(long, value_name = "COMMAND")]
+    pub on_end: Option<String>,
+    /// Editor command to try if the previous one's program isn't installed
+    ///
+    /// Can be repeated, and applies after `--editor`, `--editor-for`, and the
+    /// `editor` rule option, e.g. `--editor code --editor-fallback nvim
+    /// --editor-fallback vi`. Pass `--editor-fallback "$VISUAL"` to fall back
+    /// to your usual editor.
+    #[clap(long, value_name = "COMMAND")]
+    pub editor_fallback: Vec<String>,
+    /// Name files for pages on domains matching <PATTERN> with <EXTENSION>
+    ///
+    /// E.g. `wiki.example.com=rst` or `db.*=sql`, so the editor picks the
+    /// right filetype. `*` in the pattern matches any characters. Can be
+    /// repeated; the first matching one applies. The `extension` rule option
+    /// takes precedence. Otherwise the extension is guessed from the page's
+    /// syntax and domain.
+    #[clap(long, value_name = "PATTERN=EXTENSION")]
+    pub extension_for: Vec<ExtensionFor>,
+    /// Pass the editor's arguments exactly as written in --editor (Windows only)
+    ///
+    /// By default each argument is quoted separately, which some programs
+    /// with their own command line parsing don't understand. With this flag,
+    /// %f, %l, and %c are replaced without quoting, so write `"%f"` if the
+    /// path may contain spaces. Without %f, the quoted path is appended.
+    #[cfg(windows)]
+    #[clap(long)]
+    pub raw_args: bool,
+    /// Start Windows editors like `code.exe` from inside WSL (linux only)
+    ///
+    /// For a server in WSL used from a browser on Windows. Editors ending in
+    /// `.exe` get the file's Windows path from `wslpath`, wait flags for
+    /// `code.exe` and `notepad++.exe`, and the GHOST_TEXT_* variables through
+    /// `WSLENV`.
+    #[cfg(target_os = "linux")]
+    #[clap(long)]
+    pub wsl: bool,
+    /// Keep syncing until the file is deleted if the editor exits right away
+    ///
+    /// For editors that hand the file off to an already running instance and
+    /// exit, like `gedit` or `code` without `--wait`. Changes are sent to the
+    /// browser whenever the file is saved; delete it to end the session.
+    #[clap(long)]
+    pub wait_for_delete: bool,
+    /// Keep syncing until the editor closes the file if it exits right away (Windows only)
+    ///
+    /// For editors that hand the file off to an already running instance,
+    /// which holds it open exclusively while editing it. The session ends once
+    /// no other program has the file open, or if none opens it within 10
+    /// seconds.
+    #[cfg(windows)]
+    #[clap(long, conflicts_with = "wait_for_delete")]
+    pub wait_for_unlock: bool,
+    /// Wait up to <MILLIS> for an empty field to be filled before opening the editor
+    ///
+    /// For pages that start a session with an empty field and fill it in
+    /// afterwards. If no text arrives in time, the editor opens with an empty
+    /// file.
+    #[clap(long, value_name = "MILLIS")]
+    pub wait_for_text: Option<u64>,
+    /// Finish sessions after <SECONDS> without file changes or browser messages
+    ///
+    /// Sends the current text and closes the connection as if the editor had
+    /// exited, for editors that never do, e.g. with `--wait-for-delete`. An
+    /// editor that is still running is closed. Updates with unchanged text
+    /// count as messages, but `--ping-interval`'s pings don't.
+    #[clap(long, value_name = "SECONDS")]
+    pub finalize_after: Option<u64>,
+    /// Give the editor <SECONDS> to exit when its session ends first
+    ///
+    /// E.g. when the session is killed, finalized, or not resumed in time, or
+    /// the page detaches. The editor is asked to close with `--close-command`,
+    /// or SIGINT, then SIGTERM, waiting up to <SECONDS> after each, before it
+    /// is killed. Whatever it saves in that time is sent to the page if still
+    /// connected. Set to 0 to kill it right away.
+    #[clap(long, value_name = "SECONDS", default_value = "0")]
+    pub close_grace: u64,
+    /// Ask the editor to close by running <COMMAND>, see `--close-grace`
+    ///
+    /// %f is replaced with the file and %p with the editor's process id, e.g.
+    /// `nvim --server %f.sock --remote-send '<C-\><C-N>:wqa<CR>'` for
+    /// `--editor 'nvim --listen %f.sock %f'`.
+    #[clap(long, value_name = "COMMAND")]
+    pub close_command: Option<String>,
+    /// Title editor and terminal windows with the page's title and domain
+    ///
+    /// Supported for vim, nvim, gvim, emacs, and the alacritty, foot, kitty,
+    /// rxvt, st, urxvt, xfce4-terminal, and xterm terminals.
+    #[clap(long)]
+    pub window_title: bool,
+    /// Write an `.editorconfig` next to the file for the page's syntax
+    ///
+    /// Sets the charset and line endings, keeps trailing whitespace in
+    /// Markdown, and sets the indentation for some syntaxes. Editors that
+    /// support editorconfig pick it up. Also written for domains whose rule
+    /// has `editorconfig` properties.
+    #[clap(long)]
+    pub editorconfig: bool,
+    /// Add <DIR> to the front of the editor's PATH
+    ///
+    /// E.g. for a directory of formatters or a specific node version, without
+    /// changing the server's own environment. Can be repeated; earlier ones
+    /// are searched first.
+    #[clap(long, value_name = "DIR")]
+    pub editor_path: Vec<PathBuf>,
+    /// Keep each version the page overwrites as `<file>.1`, `<file>.2`, ...
+    ///
+    /// Saved next to the file, to recover text the page replaced unexpectedly.
+    /// They are removed with it when the session ends.
+    #[clap(long)]
+    pub history: bool,
+    /// Pipe text of files with extension <EXT> through <COMMAND> before sending it
+    ///
+    /// E.g. `md=prettier --parser markdown`. Can be repeated for different
+    /// extensions. The command reads the text on stdin and writes the
+    /// formatted text to stdout; the editor's file is left as is. It gets the
+    /// same environment as the editor. If it fails, the error is logged and
+    /// the text is sent unformatted.
+    #[clap(long = "formatter", value_name = "EXT=COMMAND")]
+    pub formatters: Vec<Formatter>,
+    /// Pipe text from the page through <COMMAND> before writing it to the file
+    ///
+    /// E.g. `pandoc -f html -t markdown` to edit rich text fields as
+    /// Markdown, with `--filter-out` converting it back. The command reads
+    /// the text on stdin and writes it to stdout, with the same environment
+    /// as the editor. If it fails, the error is logged and the text is
+    /// written as is.
+    #[clap(long, value_name = "COMMAND")]
+    pub filter_in: Option<String>,
+    /// Pipe the file's text through <COMMAND> before sending it to the page
+    ///
+    /// Like `--filter-in`, after any `--formatter`, e.g. `pandoc -f markdown
+    /// -t html`.
+    #[clap(long, value_name = "COMMAND")]
+    pub filter_out: Option<String>,
+    /// Remove invisible characters from text sent back to the page
+    ///
+    /// Zero-width spaces, soft hyphens, byte order marks, and bidi controls,
+    /// which some sites inject. Zero-width joiners are kept for emoji.
+    #[clap(long)]
+    pub strip_invisible: bool,
+    /// Serve a rendered Markdown preview of each session's file
+    ///
+    /// The preview's url is logged when the session starts. It updates as the
+    /// file is saved.
+    #[cfg(feature = "preview")]
+    #[clap(long)]
+    pub preview: bool,
+    /// Copy the text to the clipboard before sending it back when a session ends
+    ///
+    /// A backup for pages that lose the update, e.g. by re-rendering the field.
+    #[cfg(feature = "clipboard")]
+    #[clap(long)]
+    pub clipboard: bool,
     /// Allow multiple concurrent instances of editing command
     #[clap(short, long)]
     pub multi: bool,
+    /// Editor command for sessions that start while the editor is open
+    ///
+    /// Instead of waiting for the editor to exit, the file is opened in the
+    /// running editor with this command, e.g. `emacsclient`, `code
+    /// --reuse-window --wait`, or `gvim --remote-tab-wait`. It must wait for
+    /// the file to be closed. Sessions with their own editor from a
+    /// rule or `--editor-for` still wait. Has no effect with `--multi`.
+    #[clap(long, value_name = "COMMAND")]
+    pub editor_remote: Option<String>,
+    /// Allow <N> sessions of concurrency group <NAME> at once
+    ///
+    /// Rules put sessions in groups with their `group` option. Groups default
+    /// to one session at a time; set 0 to let a group run freely. Can be
+    /// repeated.
+    #[clap(long, value_name = "NAME=N", value_parser = parse_group_size)]
+    pub group_size: Vec<(String, usize)>,
     /// Shutdown after <SECONDS> with no connections
     #[clap(short, long, name = "SECONDS")]
     pub idle_timeout: Option<u64>,
+    /// Give active sessions up to <SECONDS> to finish when the server stops
+    ///
+    /// E.g. after `--idle-timeout` when a session starts just as it fires. On
+    /// Ctrl-C or SIGTERM, sessions close their editors and send their text.
+    /// Sessions still active afterwards end without sending their text back.
+    #[clap(long, value_name = "SECONDS", default_value = "10")]
+    pub shutdown_grace: u64,
     /// Wait until <MILLIS> have passed with no new changes before updating the local file.
     ///
-    /// May conflict with $EDITOR's internal debouncing. Set to 0 to disable.
-    #[clap(long, name = "MILLIS", default_value = "500")]
-    pub delay: u64,
+    /// By default, the wait grows with the size of the text: 50ms plus 10ms
+    /// per KB, up to 2s, so small fields sync almost instantly and large
+    /// documents aren't rewritten on every keystroke. May conflict with
+    /// $EDITOR's internal debouncing. Set to 0 to disable.
+    #[clap(long, name = "MILLIS")]
+    pub delay: Option<u64>,
+    /// Assume the browser disconnected if sending to it takes longer than <SECONDS>
+    #[clap(long, value_name = "SECONDS", default_value = "10")]
+    pub send_timeout: u64,
+    /// Keep the editor open for <SECONDS> after the browser disconnects
+    ///
+    /// Updates sent to the browser include a `resumeToken`. A new websocket
+    /// that passes it back, as a `resume` query parameter or a `resumeToken`
+    /// field in its first message, continues the session and is sent the
+    /// current text. Set to 0 to end sessions when the browser disconnects.
+    #[clap(long, value_name = "SECONDS", default_value = "600")]
+    pub resume_timeout: u64,
+    /// Ping the browser every <SECONDS> to notice connections that dropped silently
+    ///
+    /// E.g. after a network change, which doesn't close the websocket. If the
+    /// browser hasn't answered by the next ping, the session is treated as
+    /// disconnected and kept for `--resume-timeout`, so a reloaded page can
+    /// continue it. Set to 0 to disable.
+    #[clap(long, value_name = "SECONDS", default_value = "30")]
+    pub ping_interval: u64,
+    /// Keep the editor open when the page detaches from the field
+    ///
+    /// The extension detaches when it is stopped for the field, closing the
+    /// websocket without a status code, or moves on to another page's field.
+    /// Syncing stops either way. By default the editor is closed; with this
+    /// flag it stays open until it exits, and its changes are not sent.
+    #[clap(long)]
+    pub keep_detached: bool,
+    /// What to do with the newline at the end of the text
+    ///
+    /// Most editors end files with a newline, which fields in pages rarely
+    /// want. Can be set per domain with the `newline` rule option.
+    #[clap(long, value_enum, default_value = "append")]
+    pub newline: Newline,
+    /// What to do when a page reconnects while its old session waits to be resumed
+    ///
+    /// E.g. after reloading the page, which starts a new session for the same
+    /// url and title. Sessions that are still connected are left alone, since
+    /// a page can have several fields.
+    #[clap(long, value_enum, default_value = "adopt")]
+    pub duplicates: Duplicates,
+    /// End sessions when the text grows larger than <BYTES>
+    ///
+    /// Applies to text from both the browser and the editor. Defaults to 16 MiB.
+    #[clap(long, value_name = "BYTES", default_value = "16777216")]
+    pub max_text_size: usize,
+    /// Keep up to <N> emptied session directories to reuse for new sessions
+    ///
+    /// Saves creating and removing a temporary directory for every session,
+    /// which adds up with `--multi` and many short sessions. Directories are
+    /// emptied in the background and removed when the server stops.
+    #[clap(long, value_name = "N", default_value = "0")]
+    pub dir_pool: usize,
+    /// Allow at most <N> session directories at once, 0 for any number
+    ///
+    /// Sessions beyond it are refused until others end, bounding the
+    /// temporary files and file watches with `--multi` and many sessions.
+    #[clap(long, value_name = "N", default_value = "0")]
+    pub max_dirs: usize,
+    /// Save texts that can't be sent back to the page in <DIR>
+    ///
+    /// E.g. when the browser disconnected or the page detached from the field
+    /// before the editor exited, or the server stopped during a session.
+    /// Drafts are named `<TIMESTAMP>-<SESSION>-<FILE>`, with the page's url and
+    /// title in a `.json` file of the same name.
+    #[clap(long, value_name = "DIR")]
+    pub drafts_dir: Option<PathBuf>,
+    /// Open the editor in diff mode against the page's latest draft
+    ///
+    /// When `--drafts-dir` has a draft with the same url and title, e.g. from
+    /// a session the browser left, the editor shows it next to the page's
+    /// text to reconcile them. Only the page's text is sent back. Supported
+    /// for vim, nvim, gvim, and VS Code.
+    #[clap(long, requires = "drafts_dir")]
+    pub diff_draft: bool,
+    /// Don't watch the file, and only send the text when the editor exits
+    ///
+    /// For editors or network filesystems that flood the watcher with
+    /// events. Can be set per domain with the `no_watch` rule option.
+    #[clap(long)]
+    pub no_watch: bool,
+    /// Queue up to <N> file change events before dropping new ones
+    ///
+    /// Any queued event causes the whole file to be sent, so a larger queue
+    /// only helps editors that save in several steps in quick succession.
+    /// Dropped events are logged when the session ends.
+    #[clap(long, value_name = "N", default_value = "1")]
+    #[cfg(feature = "watch_changes")]
+    pub watch_buffer: std::num::NonZeroUsize,
+    /// Reject websocket messages from the browser larger than <BYTES>
+    ///
+    /// Defaults to twice `--max-text-size`, to allow for escaping in the
+    /// JSON message.
+    #[clap(long, value_name = "BYTES")]
+    pub max_message_size: Option<usize>,
+    /// Reject websocket frames from the browser larger than <BYTES>
+    ///
+    /// Defaults to 16 MiB. Messages may be split over multiple frames.
+    #[clap(long, value_name = "BYTES")]
+    pub max_frame_size: Option<usize>,
+    /// Buffer at most <BYTES> of unsent websocket data per session
+    ///
+    /// Sending fails once the buffer is full, e.g. if the browser is not
+    /// keeping up. Unlimited by default; must be greater than 128 KiB.
+    #[clap(long, value_name = "BYTES")]
+    pub max_write_buffer_size: Option<usize>,
+    /// Also accept websockets from origins matching <PATTERN>
+    ///
+    /// `*` matches any characters, e.g. `http://localhost:*`. Can be repeated.
+    /// Meant for development: any matching page can open your editor and
+    /// read back what you write in it.
+    #[clap(long, value_name = "PATTERN")]
+    pub allow_origin: Vec<String>,
+    /// Also accept websockets without an origin or with a `null` origin
+    ///
+    /// Command line clients like websocat send no origin, sandboxed pages and
+    /// local files send `null`.
+    #[clap(long)]
+    pub allow_null_origin: bool,
+    /// Accept `gtany ctl` requests sent with <TOKEN>
+    ///
+    /// Without it, `gtany ctl` only works over `--unix-socket`, where the
+    /// socket's permissions decide who may change sessions. Give `gtany ctl`
+    /// the same token, e.g. from the config file to keep it out of process
+    /// lists. With it, `/status` also answers requests for hosts other than
+    /// `localhost`.
+    #[clap(long, value_name = "TOKEN")]
+    pub ctl_token: Option<String>,
+    /// POST session start, end, detached, and error events to <URL>
+    ///
+    /// Each event is a JSON object with `event`, `url`, `title`, `selections`
+    /// (like GHOST_TEXT_SELECTIONS for the editor), and `timestamp` (seconds
+    /// since the unix epoch) fields. Error events also
+    /// include an `error` message. Only `http://` urls are supported.
+    #[clap(long, name = "URL")]
+    pub webhook: Option<Url>,
+    /// Save active sessions to <PATH> to pick them up again after a restart
+    ///
+    /// If the server stops while editors are still open, e.g. after a crash
+    /// with systemd socket activation, the next server continues a session
+    /// when the browser reconnects to the same page. Editors must outlive the
+    /// server process, e.g. with `KillMode=process`. Session files are kept
+    /// in `<PATH>.sessions` instead of the temp dir, and left there for the
+    /// next server if it stops with sessions still active. Only supported on
+    /// Unix.
+    #[clap(long, value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
+    /// Read per-domain session options from the JSON file at <PATH>
+    ///
+    /// A list of rules like `[{"domain": "*.wikipedia.org", "read_only": true}]`.
+    /// The first rule whose `domain` pattern matches the page applies, where
+    /// `*` matches any characters.
+    ///
+    /// Options:
+    /// `read_only` opens the editor in read-only mode where known and never
+    /// sends the text back to the page.
+    /// `editor` replaces `--editor` and `--editor-for` for the domain.
+    /// `extension` replaces `--extension-for` for the domain, e.g. `"rst"`.
+    /// `env` is an object of extra environment variables for the editor.
+    /// `path` is a list of directories, relative to the rules, to search for
+    /// programs before those of `--editor-path`.
+    /// `template` is a file, relative to the rules, to start from when the
+    /// page's text is empty. Lines of it starting with `template_marker` are
+    /// removed before the text is sent back.
+    /// `group` puts sessions in a concurrency group, see `--group-size`.
+    /// `newline` replaces `--newline` for the domain.
+    /// `no_watch` replaces `--no-watch` for the domain.
+    /// `lang` is the text's language, like `de-DE`, to spell check in with
+    /// vim, nvim, and emacs, and is set for the editor as `GHOST_TEXT_LANG`.
+    /// `label` is a name to organize sessions by, like `work`, shown in logs
+    /// and `/status`, at the start of the file name, and set for the editor
+    /// as `GHOST_TEXT_LABEL`.
+    /// `editorconfig` is an object of properties for an `.editorconfig` next
+    /// to the file, e.g. `{"max_line_length": 72}`.
+    #[clap(long, value_name = "PATH")]
+    pub rules: Option<PathBuf>,
+    /// A rule as JSON, from a `[domain."PATTERN"]` section of the config file
+    ///
+    /// Applies after those of `--rules`.
+    #[clap(long, value_name = "JSON", hide = true)]
+    pub domain_rule: Vec<String>,
+    /// Read options from the TOML file at <PATH>
+    ///
+    /// Keys are the long options, e.g. `editor = "nvim"`, `multi = true`, or
+    /// `editor_for = ["github.com=code --wait"]`. Sections like
+    /// `[domain."*.wikipedia.org"]` hold `--rules` options for the domain
+    /// pattern, and apply after the rules file. Options on the command line
+    /// or in the environment take precedence. Defaults to
+    /// `$XDG_CONFIG_HOME/gtany/config.toml` (`%APPDATA%\gtany\config.toml` on
+    /// Windows) if it exists. Profiles only read the file given in them.
+    #[clap(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Also serve another configuration, given as command line arguments
+    ///
+    /// E.g. `--profile '--port 4002 --editor emacs'` next to `--port 4001
+    /// --editor nvim`. Each profile starts from the defaults, not the options
+    /// outside it, and has its own listener and sessions. Can be repeated.
+    /// `--idle-timeout` applies to the whole process, which stops once all
+    /// profiles are idle.
+    #[clap(long, value_name = "ARGS", allow_hyphen_values = true)]
+    pub profile: Vec<String>,
+    /// Show a system tray icon with the number of active sessions
+    ///
+    /// The tray menu can stop the server or kill a stuck session.
+    #[clap(long)]
+    #[cfg(all(feature = "tray", target_os = "linux"))]
+    pub tray: bool,
     /// Serve on a listening socket passed by systemd
     ///
     /// If the socket cannot be found or used a failure will be returned.
@@ -46,3 +527,149 @@ pub struct Settings {
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     pub from_systemd: bool,
 }
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Newline {
+    /// Add a newline to the end of the file, and remove one from the editor's text
+    #[default]
+    Append,
+    /// Keep the text exactly as it is in the page and the file, e.g. for code
+    Preserve,
+    /// Add a newline to the end of the file, and remove all of them from the
+    /// editor's text, e.g. for prose
+    Strip,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Continue the old session and its editor on the new connection
+    Adopt,
+    /// Close the old session's editor and start a new session
+    Replace,
+    /// Start a new session next to the old one
+    Allow,
+}
+
+fn parse_group_size(s: &str) -> Result<(String, usize), String> {
+    let (name, size) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=N, got {s:?}"))?;
+    let size = size
+        .parse()
+        .map_err(|e| format!("invalid size {size:?}: {e}"))?;
+    Ok((name.to_owned(), size))
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Check common setup problems and suggest fixes
+    ///
+    /// Uses the same options as the server, e.g. `gtany --port 4002 doctor`.
+    Doctor,
+    /// Load test a running server with concurrent fake sessions
+    ///
+    /// Each session connects like the browser extension, sends updates as if
+    /// typing, and waits for the server to close it. Run the server with
+    /// `--multi` and an editor that exits on its own, e.g.
+    /// `--editor 'gtany fake-editor %f sleep=2000 append=saved save'`.
+    Bench(BenchOptions),
+    /// Change the sessions of a running server
+    ///
+    /// Sent to the server at `--unix-socket`, or at `--host` and `--port` with
+    /// its `--ctl-token`, e.g. `gtany --port 4002 --ctl-token <TOKEN> ctl
+    /// set-delay 2000`.
+    #[clap(subcommand)]
+    Ctl(CtlCommand),
+    /// List and search the drafts in `--drafts-dir`
+    ///
+    /// Prints each draft's path, title, and url, separated by tabs, e.g.
+    /// `gtany --drafts-dir ~/drafts history search 'ticket 123'`.
+    #[clap(subcommand)]
+    History(HistoryCommand),
+    /// Run as a native messaging host for extensions like Textern
+    ///
+    /// Started by the browser through a host manifest, with each text from
+    /// the extension opened in its own editor like a GhostText session,
+    /// without listening on the network. Messages over `--max-text-size` are
+    /// refused. As the manifest can't pass options, point it at a script like
+    /// `exec gtany --editor 'code --wait' native-host "$@"`.
+    NativeHost {
+        /// Arguments added by the browser, ignored
+        #[clap(hide = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        browser_args: Vec<String>,
+    },
+    /// Pretend to be an editor by following a script of steps
+    ///
+    /// Used for tests and demos, e.g.
+    /// `--editor 'gtany fake-editor %f append=hi save sleep=500 exit=1'`.
+    #[clap(hide = true)]
+    FakeEditor(FakeEditorOptions),
+    /// Print a JSON schema of the config file's options
+    ///
+    /// Its keys are the long options, with their types, defaults, and help,
+    /// e.g. to generate a configuration UI.
+    ConfigSchema,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum CtlCommand {
+    /// Wait until <MILLIS> have passed with no new changes before updating the local file
+    ///
+    /// Like `--delay`, for sessions that are already running, e.g. when a page
+    /// floods updates. Applies to <SESSION>, with ids as listed in `/status`,
+    /// or all active sessions.
+    SetDelay {
+        #[clap(value_name = "MILLIS")]
+        millis: u64,
+        session: Option<u64>,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum HistoryCommand {
+    /// List saved drafts, oldest first
+    List {
+        /// Print each draft's text after its details
+        #[clap(long)]
+        print: bool,
+    },
+    /// List drafts whose text, title, or url contain <QUERY>, ignoring case
+    Search {
+        query: String,
+        /// Print each draft's text after its details
+        #[clap(long)]
+        print: bool,
+    },
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct BenchOptions {
+    /// Number of concurrent sessions
+    #[clap(long, default_value = "10")]
+    pub sessions: usize,
+    /// Updates to send per session
+    #[clap(long, default_value = "20")]
+    pub updates: usize,
+    /// Wait <MILLIS> between updates
+    #[clap(long, name = "MILLIS", default_value = "50")]
+    pub interval: u64,
+    /// Initial text size in bytes
+    #[clap(long, name = "BYTES", default_value = "1000")]
+    pub size: usize,
+    /// Fail sessions that take longer than <SECONDS>
+    #[clap(long, name = "SECONDS", default_value = "60")]
+    pub timeout: u64,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct FakeEditorOptions {
+    /// File to edit
+    pub file: PathBuf,
+    /// Steps to run in order
+    ///
+    /// One of `append=TEXT`, `set=TEXT`, `reload`, `save`, `save-rename`,
+    /// `sleep=MILLIS`, `exit=CODE`, or `abort`. The buffer starts with the file contents.
+    #[clap(name = "STEP")]
+    pub steps: Vec<Step>,
+}
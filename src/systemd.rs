@@ -1,4 +1,4 @@
-use std::{env, os::unix};
+use std::{env, os::unix, process, time::Duration};
 
 use log::{LevelFilter, Log, Metadata, Record};
 use systemd_journal_logger::{connected_to_journal, JournalLog};
@@ -45,6 +45,42 @@ pub fn try_get_socket() -> anyhow::Result<UnixListenerStream> {
     Ok(listener_stream)
 }
 
+/// Send a state like `READY=1` to the service manager, see sd_notify(3)
+///
+/// Does nothing unless running as a `Type=notify` service.
+pub fn notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let bytes = path.as_encoded_bytes();
+    let result = (|| -> std::io::Result<usize> {
+        // leading '@' is an abstract socket
+        let addr = match bytes.strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)
+    })();
+    match result {
+        Ok(_) => debug!("Notified systemd of {state:?}"),
+        Err(e) => warn!("Unable to notify systemd of {state:?}: {e}"),
+    }
+}
+
+/// How often to send `WATCHDOG=1`, half of the unit's `WatchdogSec=`, see sd_watchdog_enabled(3)
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
 struct SystemdEnvLogger {
     filter: env_logger::filter::Filter,
     inner: JournalLog<&'static str, &'static str>,
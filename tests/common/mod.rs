@@ -0,0 +1,212 @@
+//! Helpers for running the real server against a fake browser extension
+
+use std::process::Stdio;
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use hyper::{header::ORIGIN, http::HeaderValue};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    process::{Child, Command},
+    sync::mpsc,
+    time::{timeout, Duration},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, protocol::CloseFrame, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use gtany::server::msg;
+
+pub const ORIGIN_VALUE: &str = "moz-extension://gtany-tests";
+
+/// Maximum time to wait for the server to respond
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Editor command running `gtany fake-editor` with the given steps
+pub fn fake_editor(steps: &str) -> String {
+    format!("{:?} fake-editor %f {steps}", env!("CARGO_BIN_EXE_gtany"))
+}
+
+/// A `gtany` process listening on an ephemeral port
+///
+/// The process is killed when dropped.
+pub struct Server {
+    pub port: u16,
+    /// Ports of any other listeners, like those of `--profile`s
+    ports: mpsc::UnboundedReceiver<u16>,
+    _child: Child,
+}
+
+impl Server {
+    /// Start the server with `editor`, forwarding its logs to stderr
+    pub async fn start(editor: &str, args: &[&str]) -> anyhow::Result<Self> {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_gtany"))
+            .args(["--port", "0", "--delay", "0", "--editor", editor])
+            .args(args)
+            .env("RUST_LOG", "debug")
+            // don't pick up the user's config file
+            .env("XDG_CONFIG_HOME", "/nonexistent")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Unable to start server")?;
+
+        let stderr = child.stderr.take().unwrap();
+        let (port_tx, mut ports) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                eprintln!("server: {line}");
+                if let Some((_, addr)) = line.split_once("Listening on http://") {
+                    if let Some((_, port)) = addr.rsplit_once(':') {
+                        let _ = port_tx.send(port.parse().unwrap());
+                    }
+                }
+            }
+        });
+
+        let port = next_port(&mut ports).await?;
+
+        Ok(Self {
+            port,
+            ports,
+            _child: child,
+        })
+    }
+
+    /// Wait for another listener of the same process
+    pub async fn next_port(&mut self) -> anyhow::Result<u16> {
+        next_port(&mut self.ports).await
+    }
+
+    /// Open a websocket like the extension does, without sending anything
+    pub async fn connect(&self) -> anyhow::Result<Session> {
+        self.connect_from(ORIGIN_VALUE).await
+    }
+
+    /// Open a websocket with the given `Origin` header
+    pub async fn connect_from(&self, origin: &str) -> anyhow::Result<Session> {
+        self.connect_to(&format!("ws://127.0.0.1:{}/", self.port), origin)
+            .await
+    }
+
+    /// Continue a session on a new websocket
+    pub async fn resume(&self, token: &str) -> anyhow::Result<Session> {
+        let url = format!("ws://127.0.0.1:{}/?resume={token}", self.port);
+        self.connect_to(&url, ORIGIN_VALUE).await
+    }
+
+    async fn connect_to(&self, url: &str, origin: &str) -> anyhow::Result<Session> {
+        let mut request = url.into_client_request()?;
+        request
+            .headers_mut()
+            .insert(ORIGIN, HeaderValue::from_str(origin)?);
+
+        let (ws, _response) = timeout(TIMEOUT, connect_async(request))
+            .await
+            .context("Timed out connecting")?
+            .context("Unable to connect")?;
+
+        Ok(Session {
+            ws,
+            resume_token: None,
+        })
+    }
+
+    /// Connect and send the initial message for an edit of `text`
+    pub async fn edit(&self, text: &str) -> anyhow::Result<Session> {
+        let mut session = self.connect().await?;
+        session.send_text(text).await?;
+        Ok(session)
+    }
+}
+
+async fn next_port(ports: &mut mpsc::UnboundedReceiver<u16>) -> anyhow::Result<u16> {
+    timeout(TIMEOUT, ports.recv())
+        .await
+        .context("Timed out waiting for server to listen")?
+        .context("Server exited before listening")
+}
+
+/// An open websocket to the server
+pub struct Session {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    /// Last token sent by the server to resume the session
+    pub resume_token: Option<String>,
+}
+
+impl Session {
+    /// Send the text as if typed in the browser
+    pub async fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+        let message = msg::GetTextFromComponent {
+            selections: vec![msg::RangeInText {
+                start: text.len(),
+                end: text.len(),
+            }],
+            syntax: String::new(),
+            text: text.to_owned(),
+            title: String::from("gtany tests"),
+            url: String::from("gtany-tests.invalid"),
+            resume_token: None,
+        };
+        self.send(Message::Text(serde_json::to_string(&message)?))
+            .await
+    }
+
+    pub async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        self.ws.send(message).await.context("Unable to send")
+    }
+
+    /// Wait for the next text sent by the server, or `None` if it closed
+    pub async fn next_text(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            match self.next_message().await? {
+                Some(Message::Text(text)) => {
+                    let value: serde_json::Value = serde_json::from_str(&text)?;
+                    let Some(text) = value["text"].as_str() else {
+                        bail!("Server sent unexpected message: {value}");
+                    };
+                    if let Some(token) = value["resumeToken"].as_str() {
+                        self.resume_token = Some(token.to_owned());
+                    }
+                    return Ok(Some(text.to_owned()));
+                }
+                Some(Message::Close(_)) | None => return Ok(None),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Collect all texts sent by the server until it closes the connection
+    pub async fn texts_until_close(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut texts = Vec::new();
+        while let Some(text) = self.next_text().await? {
+            texts.push(text);
+        }
+        Ok(texts)
+    }
+
+    /// Wait for the server to close the connection, ignoring any other messages
+    pub async fn close_frame(&mut self) -> anyhow::Result<Option<CloseFrame<'static>>> {
+        loop {
+            match self.next_message().await? {
+                Some(Message::Close(frame)) => return Ok(frame),
+                None => bail!("Connection ended without a close frame"),
+                Some(_) => {}
+            }
+        }
+    }
+
+    async fn next_message(&mut self) -> anyhow::Result<Option<Message>> {
+        match timeout(TIMEOUT, self.ws.next()).await {
+            Err(_) => bail!("Timed out waiting for server"),
+            Ok(None) => Ok(None),
+            Ok(Some(message)) => Ok(Some(message.context("Websocket error")?)),
+        }
+    }
+}
@@ -0,0 +1,978 @@
+//! Full round trips between a fake extension, the server, and a scripted editor
+
+mod common;
+
+use common::{fake_editor, Server, TIMEOUT};
+use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+
+#[tokio::test]
+async fn redirects_to_websocket() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let response = hyper::Client::new()
+        .get(format!("http://127.0.0.1:{}/", server.port).parse()?)
+        .await?;
+    assert_eq!("application/json", response.headers()["content-type"]);
+    assert_eq!("no-cache", response.headers()["cache-control"]);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let redirect: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(server.port, redirect["WebSocketPort"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn answers_head_requests() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let request = hyper::Request::head(format!("http://127.0.0.1:{}/", server.port))
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    assert_eq!(hyper::StatusCode::OK, response.status());
+    assert_eq!("application/json", response.headers()["content-type"]);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(body.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_other_methods() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let request = hyper::Request::post(format!("http://127.0.0.1:{}/", server.port))
+        .body(hyper::Body::from("{}"))?;
+    let response = hyper::Client::new().request(request).await?;
+    assert_eq!(hyper::StatusCode::METHOD_NOT_ALLOWED, response.status());
+    assert_eq!("GET, HEAD", response.headers()["allow"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn explains_itself_to_browser_tabs() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let request = hyper::Request::get(format!("http://127.0.0.1:{}/", server.port))
+        .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    assert!(response.headers()["content-type"]
+        .to_str()?
+        .starts_with("text/html"));
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(String::from_utf8_lossy(&body).contains("GhostText"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reports_build_info() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let response = hyper::Client::new()
+        .get(format!("http://127.0.0.1:{}/version", server.port).parse()?)
+        .await?;
+    assert_eq!("application/json", response.headers()["content-type"]);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let info: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(gtany::version(), info["version"]);
+    // `YYYY-MM-DD` from build.rs
+    let date = info["build_date"].as_str().unwrap_or_default();
+    assert!(
+        date.len() == 10 && date.split('-').all(|part| part.parse::<u32>().is_ok()),
+        "{info}"
+    );
+    assert!(info["features"].is_array(), "{info}");
+    assert_eq!(gtany::server::PROTOCOL_VERSION, info["protocol_version"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn forbids_websockets_from_pages() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let request = hyper::Request::get(format!("http://127.0.0.1:{}/", server.port))
+        .header("Connection", "upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .header("Origin", "https://example.com")
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(String::from_utf8_lossy(&body).contains("https://example.com"));
+
+    let response = hyper::Client::new()
+        .get(format!("http://127.0.0.1:{}/status", server.port).parse()?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let status: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(
+        1,
+        status["rejected_origins"]["https://example.com"]["requests"]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn hides_status_from_other_hosts() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let request = hyper::Request::get(format!("http://127.0.0.1:{}/status", server.port))
+        .header("Host", format!("rebound.example.com:{}", server.port))
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+
+    let request = hyper::Request::get(format!("http://127.0.0.1:{}/status", server.port))
+        .header("Host", format!("localhost:{}", server.port))
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    assert_eq!(hyper::StatusCode::OK, response.status());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn accepts_allowed_origins() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("set=edited save"),
+        &["--allow-origin", "http://localhost:*"],
+    )
+    .await?;
+
+    let mut session = server.connect_from("http://localhost:8000").await?;
+    session.send_text("original").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("edited"), texts.last().map(String::as_str));
+
+    let rejected = server.connect_from("http://localhost.example.com").await;
+    assert!(rejected.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sends_saved_text_when_editor_exits() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("set=hello=world save"), &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("hello=world"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn notifies_webhook_of_session_start_and_end() -> anyhow::Result<()> {
+    use tokio::{sync::mpsc, time::timeout};
+    use warp::Filter;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let route = warp::body::json().map(move |payload: serde_json::Value| {
+        tx.send(payload).unwrap();
+        warp::reply()
+    });
+    let (addr, webhook) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(webhook);
+
+    let url = format!("http://{addr}/");
+    let server = Server::start(&fake_editor("save"), &["--webhook", &url]).await?;
+    let mut session = server.edit("hello").await?;
+    session.texts_until_close().await?;
+
+    for event in ["start", "end"] {
+        let payload = timeout(TIMEOUT, rx.recv()).await?.unwrap();
+        assert_eq!(event, payload["event"]);
+        assert_eq!("gtany tests", payload["title"]);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn writes_browser_updates_to_file() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("sleep=1000 reload append=again save"), &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    session.send_text("hello there").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("hello there\nagain"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "watch_changes")]
+async fn sends_saves_while_editor_is_open() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("set=one save sleep=1000 set=two save sleep=1000"),
+        &[],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    // sent before the editor exits
+    assert_eq!(Some("one".to_owned()), session.next_text().await?);
+    assert_eq!(Some("two".to_owned()), session.next_text().await?);
+
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("two"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn opens_sessions_in_running_editor() -> anyhow::Result<()> {
+    use tokio::time::{sleep, timeout, Duration};
+
+    let remote = fake_editor("set=remote save");
+    let server = Server::start(&fake_editor("sleep=5000"), &["--editor-remote", &remote]).await?;
+
+    let _first = server.edit("first").await?;
+    sleep(Duration::from_millis(500)).await;
+    let mut second = server.edit("second").await?;
+    // without waiting for the first editor to exit
+    let texts = timeout(Duration::from_secs(4), second.texts_until_close()).await??;
+    assert_eq!(Some("remote"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn falls_back_to_installed_editor() -> anyhow::Result<()> {
+    let fallback = fake_editor("set=fallback save");
+    let server = Server::start(
+        "gtany-missing-editor %f",
+        &[
+            "--editor-fallback",
+            "gtany-missing-too",
+            "--editor-fallback",
+            &fallback,
+        ],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("fallback"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn skips_fallback_editor_without_terminal() -> anyhow::Result<()> {
+    let fallback = fake_editor("set=fallback save");
+    let server = Server::start(
+        "gtany-missing-editor %f",
+        &["--editor-fallback", "vim", "--editor-fallback", &fallback],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("fallback"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn watches_file_replaced_by_rename() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor(
+            "set=one save-rename sleep=1000 set=two save-rename sleep=1000 set=three save",
+        ),
+        &[],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    // sent while the editor was running, after the file was replaced once
+    assert!(texts.iter().any(|text| text == "two"), "sent {texts:?}");
+    assert_eq!(Some("three"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sends_only_final_text_without_watching() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("set=one save sleep=1000 set=two save"),
+        &["--no-watch"],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    assert_eq!(vec!["two".to_owned()], session.texts_until_close().await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_saved_text_when_editor_fails() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("set=saved save exit=3"), &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("saved"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_saved_text_when_editor_crashes() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("set=saved save append=unsaved abort"), &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("saved"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn warns_once_about_missing_selections() -> anyhow::Result<()> {
+    use tokio::time::Duration;
+
+    let server = Server::start(&fake_editor("sleep=2000"), &[]).await?;
+    let mut session = server.connect().await?;
+    for text in ["hello", "hello there"] {
+        let message = serde_json::json!({
+            "syntax": "",
+            "text": text,
+            "title": "gtany tests",
+            "url": "gtany-tests.invalid",
+        });
+        session.send(Message::Text(message.to_string())).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let response = hyper::Client::new()
+        .get(format!("http://127.0.0.1:{}/status", server.port).parse()?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let status: serde_json::Value = serde_json::from_slice(&body)?;
+    let warnings = &status["sessions"][0]["warnings"];
+    assert_eq!(1, warnings.as_array().map_or(0, Vec::len), "{status}");
+    assert!(warnings[0].as_str().unwrap().contains("no selections"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_invalid_initial_message() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let mut session = server.connect().await?;
+    session
+        .send(Message::Text(String::from("not json")))
+        .await?;
+    let frame = session.close_frame().await?.expect("close reason");
+    assert_eq!(CloseCode::Protocol, frame.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn reconnects_to_editor_after_restart() -> anyhow::Result<()> {
+    use tokio::time::Duration;
+
+    let dir = tempdir::TempDir::new("gtany-tests")?;
+    let state_file = dir.path().join("state.json");
+    let state_file = state_file.to_str().unwrap();
+    let editor = fake_editor("sleep=2000 append=! save");
+
+    let first = Server::start(&editor, &["--state-file", state_file]).await?;
+    let _abandoned = first.edit("hello").await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // the editor keeps running in its own process group
+    drop(first);
+
+    let second = Server::start(&editor, &["--state-file", state_file]).await?;
+    let mut session = second.edit("stale text from the page").await?;
+    assert_eq!(Some("hello".to_owned()), session.next_text().await?);
+
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("hello\n!"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "watch_changes")]
+async fn resumes_session_on_new_websocket() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("set=one save sleep=2000 set=two save sleep=1000"),
+        &[],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    assert_eq!(Some("one".to_owned()), session.next_text().await?);
+    let token = session.resume_token.clone().expect("No resume token");
+    drop(session);
+
+    let mut resumed = server.resume(&token).await?;
+    let texts = resumed.texts_until_close().await?;
+    assert_eq!(Some("two"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn continues_session_for_reopened_page() -> anyhow::Result<()> {
+    use tokio::time::Duration;
+
+    let server = Server::start(
+        &fake_editor("append=! save sleep=2000 append=? save sleep=500"),
+        &[],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    session.next_text().await?;
+    drop(session);
+    // let the server notice the old connection closed
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut reopened = server.edit("reloaded").await?;
+    let texts = reopened.texts_until_close().await?;
+    let last = texts.last().expect("No text");
+    assert!(last.starts_with("hello"), "{last:?}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn continues_session_after_silent_drop() -> anyhow::Result<()> {
+    use tokio::time::Duration;
+
+    let server = Server::start(
+        &fake_editor("sleep=3000 append=! save sleep=500"),
+        &["--ping-interval", "1"],
+    )
+    .await?;
+
+    // never read from, so pings go unanswered as if the network dropped
+    let _stale = server.edit("hello").await?;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let mut reopened = server.edit("reloaded").await?;
+    let texts = reopened.texts_until_close().await?;
+    assert_eq!(Some("hello\n!"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn changes_delay_of_running_sessions() -> anyhow::Result<()> {
+    use tokio::time::Duration;
+
+    let server = Server::start(&fake_editor("sleep=2000"), &["--ctl-token", "secret"]).await?;
+    let _session = server.edit("hello").await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let ctl = |token: &str, args: &[&str]| {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_gtany"))
+            .args([
+                "--port",
+                &server.port.to_string(),
+                "--ctl-token",
+                token,
+                "ctl",
+            ])
+            .args(args)
+            .output()
+    };
+    let output = ctl("wrong", &["set-delay", "2000"]).await?;
+    assert!(!output.status.success(), "{output:?}");
+    let output = ctl("secret", &["set-delay", "2000"]).await?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        "Set delay of session(s) 0 to 2000ms\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let output = ctl("secret", &["set-delay", "2000", "7"]).await?;
+    assert!(!output.status.success(), "{output:?}");
+
+    // pages can't change sessions without the token
+    let request = hyper::Request::post(format!("http://127.0.0.1:{}/ctl/set-delay", server.port))
+        .header("Origin", "https://example.com")
+        .body(hyper::Body::from(r#"{"millis":0,"session":null}"#))?;
+    let response = hyper::Client::new().request(request).await?;
+    assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_unknown_resume_tokens() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &[]).await?;
+
+    let frame = server.resume("unknown").await?.close_frame().await?;
+    assert_eq!(Some(CloseCode::Protocol), frame.map(|f| f.code));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_read_only_sessions_from_changing_the_page() -> anyhow::Result<()> {
+    let dir = tempdir::TempDir::new("gtany-e2e")?;
+    let rules = dir.path().join("rules.json");
+    std::fs::write(
+        &rules,
+        r#"[{ "domain": "gtany-tests.invalid", "read_only": true }]"#,
+    )?;
+
+    let server = Server::start(
+        &fake_editor("set=changed save sleep=500"),
+        &["--rules", rules.to_str().unwrap()],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert!(texts.is_empty(), "sent {texts:?}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn finalizes_inactive_sessions() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("set=done save sleep=60000"),
+        &["--finalize-after", "1"],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("done"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_sessions_with_repeated_updates() -> anyhow::Result<()> {
+    use tokio::time::{timeout, Duration};
+
+    let server = Server::start(
+        &fake_editor("sleep=60000"),
+        &["--finalize-after", "2", "--ping-interval", "1"],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    // the same text, like pages that re-send it periodically
+    for _ in 0..6 {
+        session.send_text("hello").await?;
+        let closed = timeout(Duration::from_millis(500), session.next_text()).await;
+        assert!(closed.is_err(), "closed while updated: {closed:?}");
+    }
+    // answered pings alone aren't activity
+    let texts = timeout(Duration::from_secs(5), session.texts_until_close()).await??;
+    assert_eq!(Some("hello"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn asks_editor_to_close_before_killing_it() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("sleep=60000"),
+        &[
+            "--finalize-after",
+            "1",
+            "--close-grace",
+            "1",
+            "--close-command",
+            "sh -c 'echo saved on close > %f'",
+        ],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("saved on close"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn sets_environment_from_rules() -> anyhow::Result<()> {
+    let dir = tempdir::TempDir::new("gtany-e2e")?;
+    let rules = dir.path().join("rules.json");
+    std::fs::write(
+        &rules,
+        r#"[{ "domain": "gtany-tests.invalid", "env": { "GTANY_TEST": "from rule" } }]"#,
+    )?;
+
+    let server = Server::start(
+        r#"sh -c 'printf %s "$GTANY_TEST" > "$0"' %f"#,
+        &["--rules", rules.to_str().unwrap()],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("from rule"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn gives_editor_a_scratch_directory() -> anyhow::Result<()> {
+    let server = Server::start(
+        r#"sh -c 'echo aux > "$GHOST_TEXT_SCRATCH/aux" && ls "$(dirname "$0")/scratch" > "$0"' %f"#,
+        &[],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("aux"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn reuses_session_directories() -> anyhow::Result<()> {
+    use tokio::time::{sleep, Duration};
+
+    let server = Server::start(
+        r#"sh -c 'dirname "$0" > "$0"; ls "$(dirname "$0")" >> "$0"' %f"#,
+        &["--dir-pool", "1"],
+    )
+    .await?;
+
+    let first = server.edit("hello").await?.texts_until_close().await?;
+    // emptied in the background
+    sleep(Duration::from_millis(500)).await;
+    let second = server.edit("hello").await?.texts_until_close().await?;
+
+    let (first, second) = (first.last().unwrap(), second.last().unwrap());
+    assert_eq!(first.lines().next(), second.lines().next());
+    assert_eq!(first, second, "leftover files in the reused directory");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn formats_text_before_sending() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("set=edited save"),
+        &["--formatter", "txt=tr a-z A-Z"],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("EDITED"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn filters_text_both_ways() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("append=! save"),
+        &["--filter-in", "tr a-z A-Z", "--filter-out", "tr ! ?"],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let texts = session.texts_until_close().await?;
+    // appended after the file's trailing newline
+    assert_eq!(Some("HELLO\n?"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn waits_for_empty_fields_to_fill() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &["--wait-for-text", "5000"]).await?;
+
+    let mut session = server.edit("").await?;
+    session.send_text("hello").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("hello"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn starts_empty_fields_from_template() -> anyhow::Result<()> {
+    let dir = tempdir::TempDir::new("gtany-e2e")?;
+    std::fs::write(dir.path().join("template.md"), "# describe it\nSummary:\n")?;
+    let rules = dir.path().join("rules.json");
+    std::fs::write(
+        &rules,
+        r##"[{ "domain": "gtany-tests.invalid", "template": "template.md", "template_marker": "#" }]"##,
+    )?;
+
+    let server = Server::start(
+        &fake_editor("append=done save"),
+        &["--rules", rules.to_str().unwrap()],
+    )
+    .await?;
+
+    let mut session = server.edit("").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("Summary:\ndone"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stops_syncing_when_page_detaches() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("sleep=2000 set=changed save"), &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    let update = serde_json::json!({
+        "selections": [],
+        "syntax": "",
+        "text": "another field",
+        "title": "gtany tests",
+        "url": "elsewhere.invalid",
+    });
+    session.send(Message::Text(update.to_string())).await?;
+    let frame = session.close_frame().await?.expect("close reason");
+    assert_eq!(CloseCode::Normal, frame.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn saves_undelivered_text_as_draft() -> anyhow::Result<()> {
+    let dir = tempdir::TempDir::new("gtany-e2e")?;
+    let drafts = dir.path().join("drafts");
+    let server = Server::start(
+        &fake_editor("sleep=500 set=draft save"),
+        &["--keep-detached", "--drafts-dir", drafts.to_str().unwrap()],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let update = serde_json::json!({
+        "selections": [],
+        "syntax": "",
+        "text": "another field",
+        "title": "gtany tests",
+        "url": "elsewhere.invalid",
+    });
+    session.send(Message::Text(update.to_string())).await?;
+    session.close_frame().await?;
+    // saved as the session ends, after closing the websocket
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+    let mut saved: Vec<_> = std::fs::read_dir(&drafts)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    saved.sort();
+    assert_eq!(2, saved.len(), "{saved:?}");
+    assert_eq!("draft", std::fs::read_to_string(&saved[0])?.trim_end());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn searches_saved_drafts() -> anyhow::Result<()> {
+    let dir = tempdir::TempDir::new("gtany-e2e")?;
+    let save = |name: &str, text: &str, title: &str| -> std::io::Result<()> {
+        std::fs::write(dir.path().join(name), text)?;
+        let details = serde_json::json!({ "url": "example.com", "title": title, "timestamp": 1 });
+        std::fs::write(dir.path().join(format!("{name}.json")), details.to_string())
+    };
+    save("1-0-example.com.txt", "Fixes Ticket 123\n", "issue")?;
+    save("1-1-example.com.txt", "unrelated", "other")?;
+
+    let history = |args: &[&str]| {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_gtany"))
+            .args(["--drafts-dir", dir.path().to_str().unwrap(), "history"])
+            .args(args)
+            .output()
+    };
+    let output = history(&["search", "ticket 123"]).await?;
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.ends_with("1-0-example.com.txt\tissue\texample.com\n"),
+        "{stdout}"
+    );
+    assert_eq!(1, stdout.lines().count());
+
+    let output = history(&["search", "--print", "OTHER"]).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.ends_with("\tother\texample.com\nunrelated\n"),
+        "{stdout}"
+    );
+
+    let output = history(&["list"]).await?;
+    assert_eq!(2, String::from_utf8_lossy(&output.stdout).lines().count());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ignores_invalid_updates() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("sleep=1000 reload append=again save"), &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    session
+        .send(Message::Text(String::from("not json")))
+        .await?;
+    session.send_text("hello there").await?;
+    let texts = session.texts_until_close().await?;
+    assert_eq!(Some("hello there\nagain"), texts.last().map(String::as_str));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_terminal_editor_without_terminal() -> anyhow::Result<()> {
+    let server = Server::start("vim", &[]).await?;
+
+    let mut session = server.edit("hello").await?;
+    let frame = session.close_frame().await?.expect("close reason");
+    assert_eq!(CloseCode::Error, frame.code);
+    assert!(
+        frame.reason.contains("needs a terminal"),
+        "{}",
+        frame.reason
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_text_over_size_limit() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor("sleep=1000"), &["--max-text-size", "10"]).await?;
+
+    let mut session = server.edit("hello").await?;
+    session.send_text("hello world").await?;
+    let frame = session.close_frame().await?.expect("close reason");
+    assert_eq!(CloseCode::Size, frame.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_edited_text_over_size_limit() -> anyhow::Result<()> {
+    let server = Server::start(
+        &fake_editor("append=world save"),
+        &["--max-text-size", "10"],
+    )
+    .await?;
+
+    let mut session = server.edit("hello").await?;
+    let frame = session.close_frame().await?.expect("close reason");
+    assert_eq!(CloseCode::Size, frame.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_messages_over_size_limit() -> anyhow::Result<()> {
+    let server = Server::start(&fake_editor(""), &["--max-message-size", "1000"]).await?;
+
+    let mut session = server.edit(&"x".repeat(1000)).await?;
+    let frame = session.close_frame().await?.expect("close reason");
+    assert_eq!(CloseCode::Protocol, frame.code);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn serves_profiles_side_by_side() -> anyhow::Result<()> {
+    let profile = format!(
+        "--port 0 --delay 0 --editor '{}'",
+        fake_editor("set=profile save")
+    );
+    let mut server = Server::start(&fake_editor("set=main save"), &["--profile", &profile]).await?;
+    let other = server.next_port().await?;
+
+    // either may start listening first
+    let mut texts = Vec::new();
+    for port in [server.port, other] {
+        server.port = port;
+        let mut session = server.edit("hello").await?;
+        texts.extend(session.texts_until_close().await?.pop());
+    }
+    texts.sort();
+    assert_eq!(vec!["main", "profile"], texts);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bridges_native_messaging() -> anyhow::Result<()> {
+    use std::net::{TcpListener, TcpStream};
+    use std::process::Stdio;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    // no server is running, the host edits texts itself
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let mut host = tokio::process::Command::new(env!("CARGO_BIN_EXE_gtany"))
+        .args(["--port", &port.to_string(), "--max-text-size", "200"])
+        .args(["--editor", &fake_editor("set=edited save")])
+        .arg("native-host")
+        // added by firefox
+        .args(["/path/to/manifest.json", "textern@example.com"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let (mut stdin, mut stdout) = (host.stdin.take().unwrap(), host.stdout.take().unwrap());
+
+    let too_long = format!(
+        r#"{{"type": "new_text", "payload": {{"id": "tab-0", "text": "{}"}}}}"#,
+        "a".repeat(300)
+    );
+    let message = br#"{"type": "new_text", "payload": {"id": "tab-1", "text": "hello", "caret": 5, "url": "gtany-tests.invalid"}}"#;
+    for message in [too_long.as_bytes(), message] {
+        stdin
+            .write_all(&(message.len() as u32).to_ne_bytes())
+            .await?;
+        stdin.write_all(message).await?;
+    }
+    stdin.flush().await?;
+
+    let mut replies = Vec::new();
+    while replies
+        .last()
+        .is_none_or(|reply: &serde_json::Value| reply["type"] != "death_notify")
+    {
+        let mut len = [0; 4];
+        timeout(TIMEOUT, stdout.read_exact(&mut len)).await??;
+        let mut json = vec![0; u32::from_ne_bytes(len) as usize];
+        stdout.read_exact(&mut json).await?;
+        replies.push(serde_json::from_slice(&json)?);
+    }
+
+    assert_eq!("error", replies[0]["type"]);
+    let update = &replies[replies.len() - 2];
+    assert_eq!("text_update", update["type"]);
+    assert_eq!("tab-1", update["payload"]["id"]);
+    assert_eq!("edited", update["payload"]["text"]);
+    // nothing listens on the port
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+
+    drop(stdin);
+    assert!(timeout(TIMEOUT, host.wait()).await??.success());
+
+    Ok(())
+}